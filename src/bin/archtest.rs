@@ -2,18 +2,21 @@ use std::fs::File;
 use std::io::Write;
 use std::ops::Range;
 use std::sync::Arc;
-use std::{env, fs};
+use std::{env, fs, process};
 
 use log::{info, warn};
 use object::{Object, ObjectSection, ObjectSymbol};
 
 use rriscv::device::Device;
 use rriscv::dynbus::DynBus;
-use rriscv::hart::Hart;
+use rriscv::hart::{self, Exit, Hart};
 use rriscv::htif::Htif;
+use rriscv::machine_builder;
 use rriscv::ram::Ram;
 use rriscv::rom::Rom;
 use rriscv::rtc::Rtc;
+use rriscv::sifive_test::{SifiveTest, SIFIVE_TEST_ADDR};
+use rriscv::symbols::Symbols;
 
 fn main() {
     env_logger::init();
@@ -27,6 +30,7 @@ fn main() {
 
     let bin_data = fs::read(elf_file).expect("file");
     let elf = object::File::parse(&*bin_data).expect("parsing");
+    machine_builder::check_elf(&elf).unwrap_or_else(|err| panic!("{}: {}", elf_file, err));
     if let Some(section) = elf.section_by_name(".text.init") {
         let start = section.address() as usize;
         let end = start + section.size() as usize;
@@ -43,39 +47,44 @@ fn main() {
         bus.map(ram, Range { start, end });
     }
 
+    let htif = Arc::new(Htif::new());
     if let Some(section) = elf.section_by_name(".tohost") {
         let start = section.address() as usize;
         let end = start + section.size() as usize;
-        let htif = Htif::new();
-        bus.map(htif, Range { start, end });
+        bus.map(htif.clone(), Range { start, end });
     }
 
     let rtc = Rtc::new();
     bus.map(rtc, 0x4000..0x4020);
 
+    let finisher = Arc::new(SifiveTest::new());
+    bus.map(
+        finisher.clone(),
+        SIFIVE_TEST_ADDR..SIFIVE_TEST_ADDR + 0x1000,
+    );
+
     let bus = Arc::new(bus);
 
     let mut m = Hart::new(0, pc, bus.clone());
-    let mut i = 0;
-    loop {
-        match m.tick() {
-            Ok(_) => {}
-            Err(e) => {
-                info!("exited at: {} ({:?})", i, e);
-                break;
-            }
-        }
-
-        if i >= 1_000_000 {
-            warn!("endless, killing");
-            break;
-        }
-        i += 1;
+    m.set_symbols(Arc::new(Symbols::from_elf(&elf)));
+    let summary = m.run(Some(hart::max_instructions().unwrap_or(1_000_000)));
+    match summary.exit {
+        Exit::Fault(e) => info!("exited at: {} ({:?})", summary.instructions_retired, e),
+        Exit::Budget => warn!("endless, killing"),
     }
 
     if let Some(sig_file) = sig_file {
         write_signature(sig_file, bus.clone(), elf);
     }
+
+    match htif.exit_code().or(finisher.exit_code()) {
+        Some(0) => info!("passed"),
+        Some(code) => {
+            warn!("failed test {}", code);
+            process::exit(code as i32);
+        }
+        None => warn!("did not exit through tohost or the test finisher"),
+    }
 }
 
 fn write_signature(sig_file: &String, bus: Arc<DynBus>, elf: object::File) {
@@ -1,47 +1,38 @@
-use std::sync::Arc;
 use std::{env, fs};
 
 use log::{info, warn};
 
-use rriscv::dynbus::DynBus;
-use rriscv::hart::Hart;
-use rriscv::ram::Ram;
-use rriscv::rtc::Rtc;
+use rriscv::bus;
+use rriscv::hart::{self, Exit};
+use rriscv::machine_builder::MachineBuilder;
 
 fn main() {
     env_logger::init();
 
-    let args: Vec<String> = env::args().collect();
-    let image_file = args.get(1).expect("expect image file");
-
-    let mut bus = DynBus::new();
+    // ballard [--flat] <image>, where images are loaded flat unless they are an ELF
+    let args: Vec<String> = env::args().skip(1).collect();
+    let flat = args.iter().any(|arg| arg == "--flat");
+    let image_file = args
+        .iter()
+        .find(|arg| !arg.starts_with("--"))
+        .expect("expect image file");
 
     let bin_data = fs::read(image_file).expect("file");
 
-    let ram = Ram::new();
-    ram.write(0, bin_data);
-    bus.map(ram, 0x80000000..0xFFFFFFFF);
-
-    let rtc = Rtc::new();
-    bus.map(rtc, 0x4000..0x4020);
-
-    let bus = Arc::new(bus);
-
-    let mut m = Hart::new(0, 0x80000000, bus.clone());
-    let mut i = 0;
-    loop {
-        match m.tick() {
-            Ok(_) => {}
-            Err(e) => {
-                info!("exited at: {} ({:?})", i, e);
-                break;
-            }
-        }
-
-        if i >= 1_000_000 {
-            warn!("endless, killing");
-            break;
-        }
-        i += 1;
+    let builder = MachineBuilder::new().clint();
+    let builder = if flat || !bin_data.starts_with(b"\x7fELF") {
+        builder.load_flat(&bin_data, bus::RAM_ADDR, bus::RAM_ADDR)
+    } else {
+        builder
+            .load_elf(&bin_data)
+            .unwrap_or_else(|err| panic!("cannot load {}: {}", image_file, err))
+    };
+    let (_bus, mut m) = builder
+        .build()
+        .unwrap_or_else(|err| panic!("cannot load {}: {}", image_file, err));
+    let summary = m.run(Some(hart::max_instructions().unwrap_or(1_000_000)));
+    match summary.exit {
+        Exit::Fault(e) => info!("exited at: {} ({:?})", summary.instructions_retired, e),
+        Exit::Budget => warn!("endless, killing"),
     }
 }
@@ -15,10 +15,12 @@ use rriscv::dt;
 use rriscv::dynbus::DynBus;
 use rriscv::gdb::emu::Emulator;
 use rriscv::hart::Hart;
+use rriscv::machine_builder;
 use rriscv::ram::Ram;
 use rriscv::reg::treg;
 use rriscv::rom::Rom;
 use rriscv::rtc::Rtc;
+use rriscv::symbols::Symbols;
 use rriscv::uart8250::Uart8250;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -48,6 +50,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let bin_data = fs::read(image_file).expect("file");
     let elf = object::File::parse(&*bin_data).expect("parsing");
+    machine_builder::check_elf(&elf)?;
 
     let mut bus = DynBus::new();
     let ram = Ram::new();
@@ -111,6 +114,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let bus = Arc::new(bus);
 
     let mut hart = Hart::new(0, pc, bus.clone());
+    hart.set_symbols(Arc::new(Symbols::from_elf(&elf)));
 
     // linux register state
     hart.set_register(treg("a0"), 0);
@@ -123,9 +127,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let debugger = Emulator::new(hart);
     if let Ok((stream, _addr)) = listener.accept() {
         info!("Got connection");
-        gdb_remote_protocol::process_packets_from(stream.try_clone().unwrap(), stream, debugger);
+        gdb_remote_protocol::process_packets_from(
+            stream.try_clone().unwrap(),
+            stream,
+            debugger.clone(),
+        );
     }
     info!("Connection closed");
 
+    if let Err(fault) = debugger.resume() {
+        info!("Stopped: {:?}", fault);
+    }
+
     Ok(())
 }
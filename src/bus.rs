@@ -20,28 +20,42 @@ impl Device for Bus {
     fn write_double(&self, addr: usize, val: u64) -> Result<(), Fault> {
         match addr {
             0x80000000.. => self.ram.write_double(addr - RAM_ADDR, val),
-            _ => Err(Fault::Unmapped(addr)),
+            _ => Err(Fault::StoreFault(addr)),
         }
     }
 
     fn write_word(&self, addr: usize, val: u32) -> Result<(), Fault> {
         match addr {
             0x80000000.. => self.ram.write_word(addr - RAM_ADDR, val),
-            _ => Err(Fault::Unmapped(addr)),
+            _ => Err(Fault::StoreFault(addr)),
         }
     }
 
     fn write_half(&self, addr: usize, val: u16) -> Result<(), Fault> {
         match addr {
             0x80000000.. => self.ram.write_half(addr - RAM_ADDR, val),
-            _ => Err(Fault::Unmapped(addr)),
+            _ => Err(Fault::StoreFault(addr)),
         }
     }
 
     fn write_byte(&self, addr: usize, val: u8) -> Result<(), Fault> {
         match addr {
             0x80000000.. => self.ram.write_byte(addr - RAM_ADDR, val),
-            _ => Err(Fault::Unmapped(addr)),
+            _ => Err(Fault::StoreFault(addr)),
+        }
+    }
+
+    fn amo_word(&self, addr: usize, f: &dyn Fn(u32) -> u32) -> Result<u32, Fault> {
+        match addr {
+            0x80000000.. => self.ram.amo_word(addr - RAM_ADDR, f),
+            _ => Err(Fault::StoreFault(addr)),
+        }
+    }
+
+    fn amo_double(&self, addr: usize, f: &dyn Fn(u64) -> u64) -> Result<u64, Fault> {
+        match addr {
+            0x80000000.. => self.ram.amo_double(addr - RAM_ADDR, f),
+            _ => Err(Fault::StoreFault(addr)),
         }
     }
 
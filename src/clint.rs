@@ -0,0 +1,293 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::csr;
+use crate::device::Device;
+use crate::hart::Hart;
+use crate::plic::Fault;
+use crate::rtc::{self, Rtc};
+
+// Where the CLINT is usually mapped on the bus
+pub const CLINT_ADDR: usize = 0x2000000;
+pub const MSIP_HART0_ADDR: usize = CLINT_ADDR + MSIP_HART0;
+
+// Register offsets, relative to the mapping of the device
+const MSIP_HART0: usize = 0x0;
+const MSIP_END: usize = MSIP_HART0 + 4 * MAX_HARTS;
+const MTIMECMP_HART0: usize = 0x4000;
+const MTIMECMP_HART0_END: usize = MTIMECMP_HART0 + 8;
+const MTIME: usize = 0xbff8;
+const MTIME_END: usize = MTIME + 8;
+
+// Size of the CLINT's register window
+pub const CLINT_SIZE: usize = 0x10000;
+
+// The MSIP region has room for one word per hart
+pub const MAX_HARTS: usize = 4095;
+
+// Machine interrupt codes, as found in `mcause` and as bit positions in `mip`/`mie`
+pub const MSIP: u64 = 3;
+pub const MTIP: u64 = 7;
+pub const MEIP: u64 = 11;
+
+// Supervisor interrupt codes, as delegated to the supervisor by the SEE
+pub const SSIP: u64 = 1;
+pub const STIP: u64 = 5;
+pub const SEIP: u64 = 9;
+
+pub struct Clint {
+    msip: Vec<AtomicBool>,
+    timer: Option<Arc<Rtc>>,
+}
+
+impl Clint {
+    pub fn new() -> Clint {
+        Self {
+            msip: (0..MAX_HARTS).map(|_| AtomicBool::new(false)).collect(),
+            timer: None,
+        }
+    }
+
+    // Also serves mtimecmp of hart 0 and mtime from `rtc`, where drivers of a CLINT expect them
+    pub fn with_timer(rtc: Arc<Rtc>) -> Clint {
+        Self {
+            timer: Some(rtc),
+            ..Clint::new()
+        }
+    }
+
+    // The RTC and its register backing `addr`, if it is part of the timer
+    fn timer(&self, addr: usize) -> Option<(&Rtc, usize)> {
+        let rtc = self.timer.as_deref()?;
+        match addr {
+            MTIMECMP_HART0..MTIMECMP_HART0_END => {
+                Some((rtc, rtc::MTIMECMP + addr - MTIMECMP_HART0))
+            }
+            MTIME..MTIME_END => Some((rtc, rtc::MTIME + addr - MTIME)),
+            _ => None,
+        }
+    }
+
+    fn hartid(addr: usize) -> Result<usize, Fault> {
+        match addr {
+            MSIP_HART0..MSIP_END if addr.is_multiple_of(4) => Ok((addr - MSIP_HART0) / 4),
+            MSIP_HART0..MSIP_END => Err(Fault::Unaligned(addr)),
+            _ => Err(Fault::MemoryFault(addr)),
+        }
+    }
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for Clint {
+    fn write_double(&self, addr: usize, val: u64) -> Result<(), Fault> {
+        match self.timer(addr) {
+            Some((rtc, reg)) => rtc.write_double(reg, val),
+            None => Err(Fault::Unaligned(addr)),
+        }
+    }
+
+    fn write_word(&self, addr: usize, val: u32) -> Result<(), Fault> {
+        if let Some((rtc, reg)) = self.timer(addr) {
+            return rtc.write_word(reg, val);
+        }
+        let hartid = Clint::hartid(addr).map_err(|fault| match fault {
+            Fault::MemoryFault(addr) => Fault::StoreFault(addr),
+            fault => fault,
+        })?;
+        self.msip[hartid].store(val & 0b1 == 1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn write_half(&self, addr: usize, val: u16) -> Result<(), Fault> {
+        match self.timer(addr) {
+            Some((rtc, reg)) => rtc.write_half(reg, val),
+            None => Err(Fault::Unaligned(addr)),
+        }
+    }
+
+    fn write_byte(&self, addr: usize, val: u8) -> Result<(), Fault> {
+        match self.timer(addr) {
+            Some((rtc, reg)) => rtc.write_byte(reg, val),
+            None => Err(Fault::Unaligned(addr)),
+        }
+    }
+
+    fn read_double(&self, addr: usize) -> Result<u64, Fault> {
+        match self.timer(addr) {
+            Some((rtc, reg)) => rtc.read_double(reg),
+            None => Err(Fault::Unaligned(addr)),
+        }
+    }
+
+    fn read_word(&self, addr: usize) -> Result<u32, Fault> {
+        if let Some((rtc, reg)) = self.timer(addr) {
+            return rtc.read_word(reg);
+        }
+        let hartid = Clint::hartid(addr)?;
+        Ok(self.msip[hartid].load(Ordering::SeqCst) as u32)
+    }
+
+    fn read_half(&self, addr: usize) -> Result<u16, Fault> {
+        match self.timer(addr) {
+            Some((rtc, reg)) => rtc.read_half(reg),
+            None => Err(Fault::Unaligned(addr)),
+        }
+    }
+
+    fn read_byte(&self, addr: usize) -> Result<u8, Fault> {
+        match self.timer(addr) {
+            Some((rtc, reg)) => rtc.read_byte(reg),
+            None => Err(Fault::Unaligned(addr)),
+        }
+    }
+}
+
+// The highest priority interrupt which is both pending and enabled
+pub fn interrupt<BT: Device>(hart: &Hart<BT>) -> Option<u64> {
+    let pending = hart.get_csr(csr::MIP) & hart.get_csr(csr::MIE);
+
+    [MEIP, MSIP, MTIP]
+        .into_iter()
+        .find(|irq| pending & (1 << irq) != 0)
+}
+
+// Whether `mtime` has reached mtimecmp, or `None` if there is no timer on the bus
+pub fn timer_pending(bus: &impl Device, mtime: u64) -> Option<bool> {
+    let mtimecmp = bus.read_double(rtc::MTIMECMP_ADDR).ok()?;
+
+    Some(mtime >= mtimecmp)
+}
+
+// Whether a software interrupt was raised for `hartid`, or `None` if there is no CLINT on the bus
+pub fn software_pending(bus: &impl Device, hartid: u64) -> Option<bool> {
+    let msip = bus.read_word(MSIP_HART0_ADDR + 4 * hartid as usize).ok()?;
+
+    Some(msip & 0b1 == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::clint::{Clint, CLINT_ADDR, CLINT_SIZE, MSIP, MSIP_HART0_ADDR, MTIP, STIP};
+    use crate::csr;
+    use crate::device::Device;
+    use crate::dynbus::DynBus;
+    use crate::hart::Hart;
+    use crate::rtc::{self, Rtc, TickCounter};
+
+    #[test]
+    fn timer_interrupt() {
+        let mut bus = DynBus::new();
+        bus.map(Rtc::new(), rtc::RTC_ADDR..rtc::RTC_ADDR + 0x20);
+        let bus = Arc::new(bus);
+        let mut hart = Hart::new(0, 0, bus.clone());
+        hart.set_csr(csr::MIE, 1 << MTIP);
+
+        bus.write_double(rtc::MTIMECMP_ADDR, 1000)
+            .expect("mtimecmp");
+        thread::sleep(Duration::from_millis(1));
+        assert_eq!(hart.pending_interrupt(), Some(MTIP));
+
+        // rewriting mtimecmp clears the interrupt
+        bus.write_double(rtc::MTIMECMP_ADDR, u64::MAX)
+            .expect("mtimecmp");
+        assert_eq!(hart.pending_interrupt(), None);
+        assert_eq!(hart.get_csr(csr::MIP) & (1 << MTIP), 0);
+    }
+
+    #[test]
+    fn supervisor_timer_interrupt() {
+        let clock = Arc::new(TickCounter::new());
+        let mut bus = DynBus::new();
+        bus.map(
+            Rtc::with_clock(clock.clone()),
+            rtc::RTC_ADDR..rtc::RTC_ADDR + 0x20,
+        );
+        let mut hart = Hart::new(0, 0, Arc::new(bus));
+        assert_ne!(hart.get_csr(csr::MENVCFG) & csr::MENVCFG_STCE, 0);
+        hart.set_csr(csr::MIE, 1 << STIP);
+
+        hart.set_csr(csr::STIMECMP, 2);
+        clock.advance();
+        hart.pending_interrupt();
+        assert_eq!(hart.get_csr(csr::MIP) & (1 << STIP), 0);
+
+        clock.advance();
+        hart.pending_interrupt();
+        assert_ne!(hart.get_csr(csr::MIP) & (1 << STIP), 0);
+        // only the supervisor timer fires
+        assert_eq!(hart.get_csr(csr::MIP) & (1 << MTIP), 0);
+
+        // rewriting stimecmp clears the interrupt
+        hart.set_csr(csr::STIMECMP, u64::MAX);
+        hart.pending_interrupt();
+        assert_eq!(hart.get_csr(csr::MIP) & (1 << STIP), 0);
+    }
+
+    #[test]
+    fn timer_registers() {
+        let clock = Arc::new(TickCounter::new());
+        let rtc = Arc::new(Rtc::with_clock(clock.clone()));
+        let mut bus = DynBus::new();
+        bus.map(
+            Clint::with_timer(rtc.clone()),
+            CLINT_ADDR..CLINT_ADDR + CLINT_SIZE,
+        );
+        bus.map(rtc, rtc::RTC_ADDR..rtc::RTC_ADDR + 0x20);
+
+        clock.advance();
+        clock.advance();
+        assert_eq!(bus.read_double(CLINT_ADDR + 0xbff8).expect("mtime"), 2);
+        assert_eq!(bus.read_word(CLINT_ADDR + 0xbff8).expect("mtime"), 2);
+
+        bus.write_double(CLINT_ADDR + 0x4000, 1234)
+            .expect("mtimecmp");
+        assert_eq!(bus.read_double(rtc::MTIMECMP_ADDR).expect("mtimecmp"), 1234);
+        bus.write_word(CLINT_ADDR + 0x4004, 1).expect("mtimecmph");
+        assert_eq!(
+            bus.read_double(CLINT_ADDR + 0x4000).expect("mtimecmp"),
+            1 << 32 | 1234
+        );
+    }
+
+    #[test]
+    fn software_interrupt() {
+        let mut bus = DynBus::new();
+        bus.map(Clint::new(), CLINT_ADDR..CLINT_ADDR + 0x4000);
+        let bus = Arc::new(bus);
+        let mut hart = Hart::new(0, 0, bus.clone());
+
+        bus.write_word(MSIP_HART0_ADDR, 1).expect("msip");
+        // pending, but not enabled
+        assert_eq!(hart.pending_interrupt(), None);
+
+        hart.set_csr(csr::MIE, 1 << MSIP);
+        assert_eq!(hart.pending_interrupt(), Some(MSIP));
+    }
+
+    #[test]
+    fn software_interrupt_per_hart() {
+        let mut bus = DynBus::new();
+        bus.map(Clint::new(), CLINT_ADDR..CLINT_ADDR + 0x4000);
+        let bus = Arc::new(bus);
+        let mut hart0 = Hart::new(0, 0, bus.clone());
+        let mut hart1 = Hart::new(1, 0, bus.clone());
+        hart0.set_csr(csr::MIE, 1 << MSIP);
+        hart1.set_csr(csr::MIE, 1 << MSIP);
+
+        bus.write_word(MSIP_HART0_ADDR + 4, 1).expect("msip");
+        assert_eq!(bus.read_word(MSIP_HART0_ADDR).expect("msip"), 0);
+        assert_eq!(bus.read_word(MSIP_HART0_ADDR + 4).expect("msip"), 1);
+
+        assert_eq!(hart0.pending_interrupt(), None);
+        assert_eq!(hart1.pending_interrupt(), Some(MSIP));
+    }
+}
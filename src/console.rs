@@ -0,0 +1,91 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+pub type InputBuffer = Arc<Mutex<VecDeque<u8>>>;
+
+// Where the guest's console output goes and its input comes from, shared by the UART and the
+// SBI console
+pub trait Console: Send + Sync {
+    fn putchar(&self, byte: u8);
+    // The next byte of input, without blocking
+    fn getchar(&self) -> Option<u8>;
+    // Whether `getchar` would return a byte
+    fn has_input(&self) -> bool;
+}
+
+// The console of the emulator process
+pub struct Stdio;
+
+impl Console for Stdio {
+    fn putchar(&self, byte: u8) {
+        let mut handle = io::stdout().lock();
+        let _ = handle.write_all(&[byte]).and_then(|_| handle.flush());
+    }
+
+    fn getchar(&self) -> Option<u8> {
+        stdin().lock().unwrap().pop_front()
+    }
+
+    fn has_input(&self) -> bool {
+        !stdin().lock().unwrap().is_empty()
+    }
+}
+
+// Buffer of bytes read from stdin.  Reading stdin blocks, so a background thread is started on
+// first use to drain it into the buffer.
+pub fn stdin() -> InputBuffer {
+    static STDIN: OnceLock<InputBuffer> = OnceLock::new();
+
+    STDIN
+        .get_or_init(|| {
+            let buffer = InputBuffer::default();
+            let input = buffer.clone();
+            thread::spawn(move || {
+                let mut byte = [0];
+                while let Ok(1) = io::stdin().read(&mut byte) {
+                    input.lock().unwrap().push_back(byte[0]);
+                }
+            });
+            buffer
+        })
+        .clone()
+}
+
+// In-memory console, capturing output and replaying queued input
+#[derive(Default)]
+pub struct Buffer {
+    input: Mutex<VecDeque<u8>>,
+    output: Mutex<Vec<u8>>,
+}
+
+impl Buffer {
+    pub fn new() -> Buffer {
+        Self::default()
+    }
+
+    pub fn push_input(&self, bytes: &[u8]) {
+        self.input.lock().unwrap().extend(bytes);
+    }
+
+    // Everything written so far
+    pub fn output(&self) -> Vec<u8> {
+        self.output.lock().unwrap().clone()
+    }
+}
+
+impl Console for Buffer {
+    fn putchar(&self, byte: u8) {
+        self.output.lock().unwrap().push(byte);
+    }
+
+    fn getchar(&self) -> Option<u8> {
+        self.input.lock().unwrap().pop_front()
+    }
+
+    fn has_input(&self) -> bool {
+        !self.input.lock().unwrap().is_empty()
+    }
+}
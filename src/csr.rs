@@ -1,15 +1,35 @@
 use log::trace;
 
-const XLEN: u64 = 32;
+use crate::hart::Xlen;
 
 pub const NUM_CSRS: usize = 4096;
 
+// Unprivileged floating point registers
+pub const FFLAGS: usize = 0x001;
+pub const FRM: usize = 0x002;
+pub const FCSR: usize = 0x003;
+
+// Unprivileged counters/timers
+pub const CYCLE: usize = 0xC00;
+pub const TIME: usize = 0xC01;
+pub const INSTRET: usize = 0xC02;
+
+// S-mode registers
+pub const STIMECMP: usize = 0x14D;
+pub const STIMECMPH: usize = 0x15D;
+
 // M-mode registers
 pub const MSTATUS: usize = 0x300;
 pub const MISA: usize = 0x301;
-pub const MEDELEG: usize = 0x301;
+pub const MEDELEG: usize = 0x302;
+pub const MIE: usize = 0x304;
 pub const MTVEC: usize = 0x305;
 pub const MSCRATCH: usize = 0x340;
+pub const MEPC: usize = 0x341;
+pub const MCAUSE: usize = 0x342;
+pub const MTVAL: usize = 0x343;
+pub const MIP: usize = 0x344;
+pub const MENVCFG: usize = 0x30A;
 pub const MVENDORID: usize = 0xF11;
 pub const MARCHID: usize = 0xF12;
 pub const MIMPID: usize = 0xF13;
@@ -17,19 +37,46 @@ pub const MHARTID: usize = 0xF14;
 pub const MCYCLE: usize = 0xB00;
 pub const MINSTRET: usize = 0xB02;
 pub const SATP: usize = 0x180;
+pub const PMPCFG0: usize = 0x3A0;
+pub const PMPADDR0: usize = 0x3B0;
+
+// mstatus fields
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+
+// Sstc: stimecmp is implemented and drives the supervisor timer interrupt
+pub const MENVCFG_STCE: u64 = 1 << 63;
+
+// Compressed instructions, which allow 2-byte aligned instruction addresses
+pub const MISA_C: u64 = 1 << 2;
+
+// Set in mcause when the trap was caused by an interrupt
+pub const MCAUSE_INTERRUPT: u64 = 1 << 63;
+// Environment call from M-mode
+pub const MCAUSE_ECALL_M: u64 = 11;
+
+// Writable mstatus fields: SIE, MIE, SPIE, UBE, MPIE, SPP, VS, MPP, FS, SUM, MXR, TVM, TW and
+// TSR.  Everything else is either WPRI or not implemented and reads as zero.  MPRV stays zero
+// until there are privilege modes below M to take the access privilege from.
+const MSTATUS_MASK: u64 = 0b111_1100_0111_1111_1110_1010;
+
+// Implemented bits of the pmpaddr registers, 53:0 on RV64
+const PMPADDR_MASK: u64 = (1 << 54) - 1;
 
 type CsrFn = for<'a> fn(&'a Csr, usize) -> u64;
 type CsrWrFn = for<'a> fn(&'a mut Csr, usize, u64);
 
-const CSR_MAP: [(usize, &str, CsrFn, CsrWrFn); 99] = [
+const CSR_MAP: [(usize, &str, CsrFn, CsrWrFn); 119] = [
     // Unprivileged Floating Point
-    (0x001, "fflags", handle_nop, handle_nop_wr),
-    (0x002, "frm", handle_nop, handle_nop_wr),
-    (0x003, "fcsr", handle_nop, handle_nop_wr),
+    (FFLAGS, "fflags", Csr::read_fflags, Csr::write_fflags),
+    (FRM, "frm", Csr::read_frm, Csr::write_frm),
+    (FCSR, "fcsr", Csr::read_fcsr, Csr::write_fcsr),
     // Unprivileged Counter/Timers
-    (0xC00, "cycle", handle_nop, handle_nop_wr),
-    (0xC01, "time", handle_nop, handle_nop_wr),
-    (0xC02, "instret", handle_nop, handle_nop_wr),
+    (CYCLE, "cycle", Csr::read_cycle, Csr::write_ro),
+    (TIME, "time", Csr::read_cycle, Csr::write_ro),
+    (INSTRET, "instret", Csr::read_instret, Csr::write_ro),
     (0xC03, "hpmcounter3", handle_nop, handle_nop_wr),
     (0xC04, "hpmcounter4", handle_nop, handle_nop_wr),
     //...
@@ -53,6 +100,14 @@ const CSR_MAP: [(usize, &str, CsrFn, CsrWrFn); 99] = [
     (0x142, "scause", handle_nop, handle_nop_wr),
     (0x143, "stval", handle_nop, handle_nop_wr),
     (0x144, "sip", handle_nop, handle_nop_wr),
+    // Supervisor Timer Compare
+    (STIMECMP, "stimecmp", Csr::read_any, Csr::write_any),
+    (
+        STIMECMPH,
+        "stimecmph",
+        Csr::read_stimecmph,
+        Csr::write_stimecmph,
+    ),
     // Supervisor Protection and Translation
     (SATP, "satp", handle_nop, handle_nop_wr),
     // Supervisor Debug/Trace Registers
@@ -91,37 +146,84 @@ const CSR_MAP: [(usize, &str, CsrFn, CsrWrFn); 99] = [
     (0x244, "vsip", handle_nop, handle_nop_wr),
     (0x280, "vsatp", handle_nop, handle_nop_wr),
     // Machine Information Reigsers
-    (MVENDORID, "mvendorid", Csr::read_any, Csr::write_any),
-    (MARCHID, "marchid", Csr::read_any, Csr::write_any),
-    (MIMPID, "mimpid", Csr::read_any, Csr::write_any),
-    (MHARTID, "mhartid", Csr::read_any, Csr::write_any),
+    (MVENDORID, "mvendorid", Csr::read_any, Csr::write_ro),
+    (MARCHID, "marchid", Csr::read_any, Csr::write_ro),
+    (MIMPID, "mimpid", Csr::read_any, Csr::write_ro),
+    (MHARTID, "mhartid", Csr::read_any, Csr::write_ro),
     (0xF15, "mconfigptr", Csr::read_any, Csr::write_any),
     // Machine Trap Setup
-    (MSTATUS, "mstatus", Csr::read_any, Csr::write_any),
+    (MSTATUS, "mstatus", Csr::read_any, Csr::write_mstatus),
     (MISA, "misa", Csr::read_any, Csr::write_any),
     (MEDELEG, "medeleg", Csr::read_any, Csr::write_any),
     (0x303, "mideleg", Csr::read_any, Csr::write_any),
-    (0x304, "mie", Csr::read_any, Csr::write_any),
+    (MIE, "mie", Csr::read_any, Csr::write_any),
     (MTVEC, "mtvec", Csr::read_mtvec, Csr::write_any),
     (0x306, "mcounteren", Csr::read_any, Csr::write_any),
     (0x310, "mstatush", Csr::read_any, Csr::write_any),
     // Machine Trap Handling
     (MSCRATCH, "mscratch", Csr::read_any, Csr::write_any),
-    (0x341, "mepc", Csr::read_any, Csr::write_any),
-    (0x342, "mcause", Csr::read_any, Csr::write_any),
-    (0x343, "mtval", Csr::read_any, Csr::write_any),
-    (0x344, "mip", Csr::read_any, Csr::write_any),
+    (MEPC, "mepc", Csr::read_mepc, Csr::write_any),
+    (MCAUSE, "mcause", Csr::read_any, Csr::write_any),
+    (MTVAL, "mtval", Csr::read_any, Csr::write_any),
+    (MIP, "mip", Csr::read_any, Csr::write_any),
     (0x34A, "minst", Csr::read_any, Csr::write_any),
     (0x34B, "mtval2", Csr::read_any, Csr::write_any),
     // Machine Configuration
-    (0x30A, "menvcfg", Csr::read_any, Csr::write_any),
+    (MENVCFG, "menvcfg", Csr::read_any, Csr::write_any),
     (0x31A, "menvcfgh", Csr::read_any, Csr::write_any),
     (0x347, "mseccfg", Csr::read_any, Csr::write_any),
     (0x357, "mseccfgh", Csr::read_any, Csr::write_any),
     // Machine Memory Protection
-    (0x3A0, "pmpcfg0", Csr::read_any, Csr::write_any),
-    //...
-    (0x3AF, "pmpaddr0", Csr::read_any, Csr::write_any),
+    (PMPCFG0, "pmpcfg0", Csr::read_any, Csr::write_any),
+    (PMPCFG0 + 1, "pmpcfg1", Csr::read_any, Csr::write_any),
+    (PMPCFG0 + 2, "pmpcfg2", Csr::read_any, Csr::write_any),
+    (PMPCFG0 + 3, "pmpcfg3", Csr::read_any, Csr::write_any),
+    (PMPADDR0, "pmpaddr0", Csr::read_any, Csr::write_pmpaddr),
+    (PMPADDR0 + 1, "pmpaddr1", Csr::read_any, Csr::write_pmpaddr),
+    (PMPADDR0 + 2, "pmpaddr2", Csr::read_any, Csr::write_pmpaddr),
+    (PMPADDR0 + 3, "pmpaddr3", Csr::read_any, Csr::write_pmpaddr),
+    (PMPADDR0 + 4, "pmpaddr4", Csr::read_any, Csr::write_pmpaddr),
+    (PMPADDR0 + 5, "pmpaddr5", Csr::read_any, Csr::write_pmpaddr),
+    (PMPADDR0 + 6, "pmpaddr6", Csr::read_any, Csr::write_pmpaddr),
+    (PMPADDR0 + 7, "pmpaddr7", Csr::read_any, Csr::write_pmpaddr),
+    (PMPADDR0 + 8, "pmpaddr8", Csr::read_any, Csr::write_pmpaddr),
+    (PMPADDR0 + 9, "pmpaddr9", Csr::read_any, Csr::write_pmpaddr),
+    (
+        PMPADDR0 + 10,
+        "pmpaddr10",
+        Csr::read_any,
+        Csr::write_pmpaddr,
+    ),
+    (
+        PMPADDR0 + 11,
+        "pmpaddr11",
+        Csr::read_any,
+        Csr::write_pmpaddr,
+    ),
+    (
+        PMPADDR0 + 12,
+        "pmpaddr12",
+        Csr::read_any,
+        Csr::write_pmpaddr,
+    ),
+    (
+        PMPADDR0 + 13,
+        "pmpaddr13",
+        Csr::read_any,
+        Csr::write_pmpaddr,
+    ),
+    (
+        PMPADDR0 + 14,
+        "pmpaddr14",
+        Csr::read_any,
+        Csr::write_pmpaddr,
+    ),
+    (
+        PMPADDR0 + 15,
+        "pmpaddr15",
+        Csr::read_any,
+        Csr::write_pmpaddr,
+    ),
     (0x3EF, "pmpaddr63", Csr::read_any, Csr::write_any),
     // Machine Counters/Timers
     (MCYCLE, "mcycle", Csr::read_any, Csr::write_any),
@@ -158,6 +260,7 @@ fn handle_nop(_csr: &Csr, _num: usize) -> u64 {
     0
 }
 
+#[derive(Clone)]
 pub struct Csr {
     csrs: [u64; NUM_CSRS],
 }
@@ -168,8 +271,8 @@ impl Csr {
             csrs: [0; NUM_CSRS],
         };
 
-        // RV32 I
-        csr.csrs[MISA] = 0b01 << (XLEN - 2) | 1 << 8;
+        // RV64 IC
+        csr.set_xlen(Xlen::Rv64);
 
         // Non-commercial implementation
         csr.csrs[MVENDORID] = 0;
@@ -191,11 +294,24 @@ impl Csr {
         csr.csrs[MCYCLE] = 0; // actually per core, not hart
         csr.csrs[MINSTRET] = 0;
 
+        // Supervisor timer, never firing until programmed
+        csr.csrs[MENVCFG] = MENVCFG_STCE;
+        csr.csrs[STIMECMP] = u64::MAX;
+
         csr
     }
 }
 
 impl Csr {
+    // Reports the register width in misa.MXL, keeping the extensions
+    pub(crate) fn set_xlen(&mut self, xlen: Xlen) {
+        let extensions = 1 << 8 | MISA_C;
+        self.csrs[MISA] = match xlen {
+            Xlen::Rv32 => 0b01 << 30 | extensions,
+            Xlen::Rv64 => 0b10 << 62 | extensions,
+        };
+    }
+
     pub fn name(csr: usize) -> &'static str {
         for (i, s, ..) in CSR_MAP {
             if i == csr {
@@ -205,6 +321,13 @@ impl Csr {
         "U"
     }
 
+    pub fn number(name: &str) -> Option<usize> {
+        CSR_MAP
+            .iter()
+            .find(|(_, s, ..)| *s == name)
+            .map(|(i, ..)| *i)
+    }
+
     pub(crate) fn read(&self, csr: usize) -> u64 {
         trace!("r csr {}[{:x}]", Csr::name(csr), self.csrs[csr]);
 
@@ -232,6 +355,30 @@ impl Csr {
         }
     }
 
+    // The configuration byte and address register of a PMP entry.  On RV32 each pmpcfg register
+    // holds 4 entries.  On RV64 the odd numbered pmpcfg registers do not exist, pmpcfg0 holds
+    // entries 0-7 and pmpcfg2 entries 8-15.
+    pub(crate) fn pmp(&self, entry: usize) -> (u8, u64) {
+        let cfg = match self.csrs[MISA] >> 62 {
+            0b10 => self.csrs[PMPCFG0 + (entry / 8) * 2] >> (8 * (entry % 8)),
+            _ => self.csrs[PMPCFG0 + entry / 4] >> (8 * (entry % 4)),
+        };
+        (cfg as u8, self.csrs[PMPADDR0 + entry])
+    }
+
+    // Marks interrupt `irq` pending in `mip`, with `irq` being one of the codes in `clint`
+    pub(crate) fn set_mip_bit(&mut self, irq: u64) {
+        self.csrs[MIP] |= 1 << irq;
+    }
+
+    pub(crate) fn clear_mip_bit(&mut self, irq: u64) {
+        self.csrs[MIP] &= !(1 << irq);
+    }
+
+    pub(crate) fn mie_bit(&self, irq: u64) -> bool {
+        self.csrs[MIE] & (1 << irq) != 0
+    }
+
     fn read_any(&self, csr: usize) -> u64 {
         self.csrs[csr]
     }
@@ -240,19 +387,83 @@ impl Csr {
         self.csrs[csr] = val
     }
 
+    fn write_ro(&mut self, _csr: usize, _val: u64) {
+        // ignore
+    }
+
+    // The unprivileged counters are read-only shadows of the machine counters.  Without a
+    // memory mapped mtime, `time` is approximated by the cycle count.
+    fn read_cycle(&self, _csr: usize) -> u64 {
+        self.csrs[MCYCLE]
+    }
+
+    fn read_instret(&self, _csr: usize) -> u64 {
+        self.csrs[MINSTRET]
+    }
+
+    // Upper half of stimecmp on RV32
+    fn read_stimecmph(&self, _csr: usize) -> u64 {
+        self.csrs[STIMECMP] >> 32
+    }
+
+    fn write_stimecmph(&mut self, _csr: usize, val: u64) {
+        self.csrs[STIMECMP] = (val << 32) | (self.csrs[STIMECMP] & 0xFFFF_FFFF);
+    }
+
+    // WARL: pmpaddr holds bits 55:2 of a 56-bit physical address
+    fn write_pmpaddr(&mut self, csr: usize, val: u64) {
+        self.csrs[csr] = val & PMPADDR_MASK
+    }
+
+    fn write_mstatus(&mut self, csr: usize, val: u64) {
+        self.csrs[csr] = val & MSTATUS_MASK
+    }
+
+    // fflags and frm are views into fcsr, which holds both
+    fn read_fflags(&self, _csr: usize) -> u64 {
+        self.csrs[FCSR] & 0b11111
+    }
+
+    fn write_fflags(&mut self, _csr: usize, val: u64) {
+        self.csrs[FCSR] = (self.csrs[FCSR] & !0b11111) | (val & 0b11111)
+    }
+
+    fn read_frm(&self, _csr: usize) -> u64 {
+        (self.csrs[FCSR] >> 5) & 0b111
+    }
+
+    fn write_frm(&mut self, _csr: usize, val: u64) {
+        self.csrs[FCSR] = (self.csrs[FCSR] & !(0b111 << 5)) | ((val & 0b111) << 5)
+    }
+
+    fn read_fcsr(&self, _csr: usize) -> u64 {
+        self.csrs[FCSR] & 0xFF
+    }
+
+    fn write_fcsr(&mut self, _csr: usize, val: u64) {
+        self.csrs[FCSR] = val & 0xFF
+    }
+
+    // WARL: instruction addresses are 2-byte aligned with compressed instructions, 4-byte
+    // aligned otherwise
+    fn read_mepc(&self, csr: usize) -> u64 {
+        match self.csrs[MISA] & MISA_C {
+            0 => self.csrs[csr] & !0b11,
+            _ => self.csrs[csr] & !0b1,
+        }
+    }
+
     // WARL
     fn read_mtvec(&self, csr: usize) -> u64 {
         let val = &self.csrs[csr];
-        let base = val >> 2;
+        // legality: base must be aligned to 4 byte boundary
+        let base = val & !0b11;
         let mode = val & 0b11;
 
         // legality: mode >= 2 is reserved
         let mode = mode & 0b01;
 
-        // legality: base must be aligned to 4 byte boundary
-        let base = (base >> 2) << 2;
-
-        let legal_val = (base << 2) | mode;
+        let legal_val = base | mode;
 
         trace!(
             "r csr {}[{:x}]->[{:x}]",
@@ -264,3 +475,68 @@ impl Csr {
         legal_val
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::csr::{Csr, MEPC, MHARTID, MISA, MISA_C, MSTATUS, MSTATUS_MPRV, MTVEC, MVENDORID};
+
+    #[test]
+    fn write_mhartid() {
+        let mut csr = Csr::new(3);
+        csr.write(MHARTID, 7);
+        csr.write(MVENDORID, 0xdead);
+
+        assert_eq!(csr.read(MHARTID), 3);
+        assert_eq!(csr.read(MVENDORID), 0);
+    }
+
+    #[test]
+    fn write_mstatus_reserved() {
+        let mut csr = Csr::new(0);
+        csr.write(MSTATUS, u64::MAX);
+
+        let mstatus = csr.read(MSTATUS);
+        // WPRI bits 0, 2, 4, 23..=31 and 38..=62
+        assert_eq!(mstatus & 0b10101, 0);
+        assert_eq!(mstatus & 0xFF80_0000, 0);
+        assert_eq!(mstatus & 0x7FFF_FFC0_0000_0000, 0);
+        // MIE and MPP survive
+        assert_eq!(mstatus & (1 << 3), 1 << 3);
+        assert_eq!(mstatus & (0b11 << 11), 0b11 << 11);
+        // no lower privilege modes for MPRV to apply
+        assert_eq!(mstatus & MSTATUS_MPRV, 0);
+    }
+
+    #[test]
+    fn mepc_alignment() {
+        let mut csr = Csr::new(0);
+        csr.write(MEPC, 0x80000003);
+        assert_eq!(csr.read(MEPC), 0x80000002);
+
+        // without compressed instructions
+        csr.write(MISA, csr.read(MISA) & !MISA_C);
+        assert_eq!(csr.read(MEPC), 0x80000000);
+    }
+
+    #[test]
+    fn mtvec_round_trip() {
+        let mut csr = Csr::new(0);
+        csr.write(MTVEC, 0x80000104);
+        assert_eq!(csr.read(MTVEC), 0x80000104);
+        csr.write(MTVEC, 0x80000101);
+        assert_eq!(csr.read(MTVEC), 0x80000101);
+    }
+
+    #[test]
+    fn mtvec_reserved_modes() {
+        let mut csr = Csr::new(0);
+        csr.write(MTVEC, 0x80000004);
+        assert_eq!(csr.read(MTVEC), 0x80000004);
+
+        // modes 2 and 3 are reserved, and read as direct and vectored
+        csr.write(MTVEC, 0x80000002);
+        assert_eq!(csr.read(MTVEC), 0x80000000);
+        csr.write(MTVEC, 0x80000003);
+        assert_eq!(csr.read(MTVEC), 0x80000001);
+    }
+}
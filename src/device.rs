@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use crate::plic::Fault;
 
 pub trait Device {
@@ -9,4 +11,95 @@ pub trait Device {
     fn read_word(&self, addr: usize) -> Result<u32, Fault>;
     fn read_half(&self, addr: usize) -> Result<u16, Fault>;
     fn read_byte(&self, addr: usize) -> Result<u8, Fault>;
+
+    // Atomic read-modify-write returning the previous value.  Devices which can be shared
+    // between harts should override these to apply `f` while holding their lock.
+    fn amo_word(&self, addr: usize, f: &dyn Fn(u32) -> u32) -> Result<u32, Fault> {
+        let val = self.read_word(addr)?;
+        self.write_word(addr, f(val))?;
+        Ok(val)
+    }
+    fn amo_double(&self, addr: usize, f: &dyn Fn(u64) -> u64) -> Result<u64, Fault> {
+        let val = self.read_double(addr)?;
+        self.write_double(addr, f(val))?;
+        Ok(val)
+    }
+
+    // Bulk access, byte by byte.  Memory backed devices should override these to copy the
+    // whole range under a single lock.
+    fn read_bytes(&self, addr: usize, buf: &mut [u8]) -> Result<(), Fault> {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = self.read_byte(addr + i)?;
+        }
+        Ok(())
+    }
+    fn write_bytes(&self, addr: usize, data: &[u8]) -> Result<(), Fault> {
+        for (i, byte) in data.iter().enumerate() {
+            self.write_byte(addr + i, *byte)?;
+        }
+        Ok(())
+    }
+
+    // Periodic work of the device, called once per iteration of the emulation loop
+    fn tick(&self) {}
+
+    // Whether the guest cannot store to the device, as with ROM
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    // Describes the mappings around `addr` for diagnosing faults, only buses know of any
+    fn describe(&self, _addr: usize) -> Option<String> {
+        None
+    }
+}
+
+// Devices which are also referenced outside the bus, e.g. an interrupt controller other devices
+// signal, are mapped behind an `Arc`.
+impl<T: Device> Device for Arc<T> {
+    fn write_double(&self, addr: usize, val: u64) -> Result<(), Fault> {
+        (**self).write_double(addr, val)
+    }
+    fn write_word(&self, addr: usize, val: u32) -> Result<(), Fault> {
+        (**self).write_word(addr, val)
+    }
+    fn write_half(&self, addr: usize, val: u16) -> Result<(), Fault> {
+        (**self).write_half(addr, val)
+    }
+    fn write_byte(&self, addr: usize, val: u8) -> Result<(), Fault> {
+        (**self).write_byte(addr, val)
+    }
+    fn read_double(&self, addr: usize) -> Result<u64, Fault> {
+        (**self).read_double(addr)
+    }
+    fn read_word(&self, addr: usize) -> Result<u32, Fault> {
+        (**self).read_word(addr)
+    }
+    fn read_half(&self, addr: usize) -> Result<u16, Fault> {
+        (**self).read_half(addr)
+    }
+    fn read_byte(&self, addr: usize) -> Result<u8, Fault> {
+        (**self).read_byte(addr)
+    }
+    fn amo_word(&self, addr: usize, f: &dyn Fn(u32) -> u32) -> Result<u32, Fault> {
+        (**self).amo_word(addr, f)
+    }
+    fn amo_double(&self, addr: usize, f: &dyn Fn(u64) -> u64) -> Result<u64, Fault> {
+        (**self).amo_double(addr, f)
+    }
+    fn read_bytes(&self, addr: usize, buf: &mut [u8]) -> Result<(), Fault> {
+        (**self).read_bytes(addr, buf)
+    }
+    fn write_bytes(&self, addr: usize, data: &[u8]) -> Result<(), Fault> {
+        (**self).write_bytes(addr, data)
+    }
+    fn tick(&self) {
+        (**self).tick()
+    }
+    fn read_only(&self) -> bool {
+        (**self).read_only()
+    }
+    fn describe(&self, addr: usize) -> Option<String> {
+        (**self).describe(addr)
+    }
 }
@@ -0,0 +1,445 @@
+use crate::csr::Csr;
+use crate::ins::Instruction;
+use crate::ins::InstructionFormat::{self, B, I, J, R, S, U};
+use crate::reg::{fpreg, reg};
+
+// Decodes every instruction in `bytes`, which is located at `base`, without executing it.
+// Decoding stops at the first incomplete instruction.
+pub fn disassemble(bytes: &[u8], base: usize) -> Vec<(usize, Instruction, String)> {
+    let mut listing = vec![];
+    let mut offset = 0;
+
+    while let Some(ins) = fetch(&bytes[offset..]) {
+        let addr = base + offset;
+        let asm = match ins.decode() {
+            Ok((ins, decoded)) => format(ins, &decoded, addr),
+            Err(_) => unknown(ins),
+        };
+        listing.push((addr, ins, asm));
+        offset += ins.size();
+    }
+
+    listing
+}
+
+fn fetch(bytes: &[u8]) -> Option<Instruction> {
+    match bytes {
+        [lo, ..] if lo & 0b11 == 0b11 => {
+            let word = <[u8; 4]>::try_from(bytes.get(..4)?).ok()?;
+            Some(Instruction::IRV32(u32::from_le_bytes(word)))
+        }
+        [lo, hi, ..] => Some(Instruction::CRV32(u16::from_le_bytes([*lo, *hi]))),
+        _ => None,
+    }
+}
+
+// Like objdump without aliases, e.g. `addi\ta0,zero,5`.  Compressed instructions are shown
+// as their expansion, branch and jump targets are resolved relative to `pc`.
+pub fn format(ins: Instruction, decoded: &InstructionFormat, pc: usize) -> String {
+    let target = |imm: i64| format!("{:x}", (pc as u64).wrapping_add_signed(imm));
+
+    match *decoded {
+        R {
+            opcode: opcode @ (0b0110011 | 0b0111011),
+            rd,
+            funct3,
+            rs1,
+            rs2,
+            funct7,
+        } => {
+            let name = match (funct7, funct3) {
+                (0x00, 0x0) => "add",
+                (0x20, 0x0) => "sub",
+                (0x00, 0x1) => "sll",
+                (0x00, 0x2) => "slt",
+                (0x00, 0x3) => "sltu",
+                (0x00, 0x4) => "xor",
+                (0x00, 0x5) => "srl",
+                (0x20, 0x5) => "sra",
+                (0x00, 0x6) => "or",
+                (0x00, 0x7) => "and",
+                (0x01, 0x0) => "mul",
+                (0x01, 0x1) => "mulh",
+                (0x01, 0x2) => "mulhsu",
+                (0x01, 0x3) => "mulhu",
+                (0x01, 0x4) => "div",
+                (0x01, 0x5) => "divu",
+                (0x01, 0x6) => "rem",
+                (0x01, 0x7) => "remu",
+                _ => return unknown(ins),
+            };
+            // RV64 only has word forms of add, sub, the shifts, mul and the divisions
+            let word = opcode == 0b0111011;
+            if word && matches!((funct7, funct3), (0x00, 0x2..=0x4 | 0x6..=0x7) | (0x01, 0x1..=0x3))
+            {
+                return unknown(ins);
+            }
+            let suffix = if word { "w" } else { "" };
+            format!("{name}{suffix}\t{},{},{}", reg(rd), reg(rs1), reg(rs2))
+        }
+        I {
+            opcode: opcode @ (0b0010011 | 0b0011011),
+            rd,
+            funct3,
+            rs1,
+            imm,
+        } => {
+            let word = opcode == 0b0011011;
+            let suffix = if word { "w" } else { "" };
+            let shamt = imm & 0b11_1111;
+            match (funct3, (imm as u16) >> 6) {
+                // word shifts take a 5-bit shamt and there is only addiw besides them
+                (0x1 | 0x5, _) if word && shamt > 0b1_1111 => unknown(ins),
+                (0x2..=0x4 | 0x6..=0x7, _) if word => unknown(ins),
+                (0x1, 0x00) => format!("slli{suffix}\t{},{},{shamt:#x}", reg(rd), reg(rs1)),
+                (0x5, 0x00) => format!("srli{suffix}\t{},{},{shamt:#x}", reg(rd), reg(rs1)),
+                (0x5, 0x10) => format!("srai{suffix}\t{},{},{shamt:#x}", reg(rd), reg(rs1)),
+                (0x1 | 0x5, _) => unknown(ins),
+                _ => {
+                    let name = match funct3 {
+                        0x0 => "addi",
+                        0x2 => "slti",
+                        0x3 => "sltiu",
+                        0x4 => "xori",
+                        0x6 => "ori",
+                        _ => "andi",
+                    };
+                    format!("{name}{suffix}\t{},{},{imm}", reg(rd), reg(rs1))
+                }
+            }
+        }
+        I {
+            opcode: 0b0000011,
+            rd,
+            funct3,
+            rs1,
+            imm,
+        } => {
+            let name = match funct3 {
+                0x0 => "lb",
+                0x1 => "lh",
+                0x2 => "lw",
+                0x3 => "ld",
+                0x4 => "lbu",
+                0x5 => "lhu",
+                0x6 => "lwu",
+                _ => return unknown(ins),
+            };
+            format!("{name}\t{},{imm}({})", reg(rd), reg(rs1))
+        }
+        S {
+            opcode: 0b0100011,
+            funct3,
+            rs1,
+            rs2,
+            imm,
+        } => {
+            let name = match funct3 {
+                0x0 => "sb",
+                0x1 => "sh",
+                0x2 => "sw",
+                0x3 => "sd",
+                _ => return unknown(ins),
+            };
+            format!("{name}\t{},{imm}({})", reg(rs2), reg(rs1))
+        }
+        B {
+            opcode: 0b1100011,
+            funct3,
+            rs1,
+            rs2,
+            imm,
+        } => {
+            let name = match funct3 {
+                0x0 => "beq",
+                0x1 => "bne",
+                0x4 => "blt",
+                0x5 => "bge",
+                0x6 => "bltu",
+                0x7 => "bgeu",
+                _ => return unknown(ins),
+            };
+            format!("{name}\t{},{},{}", reg(rs1), reg(rs2), target(imm as i64))
+        }
+        J {
+            opcode: 0b1101111,
+            rd,
+            imm,
+        } => format!("jal\t{},{}", reg(rd), target(imm as i64)),
+        I {
+            opcode: 0b1100111,
+            rd,
+            funct3: 0x0,
+            rs1,
+            imm,
+        } => format!("jalr\t{},{imm}({})", reg(rd), reg(rs1)),
+        U { opcode, rd, imm } => {
+            let name = match opcode {
+                0b0110111 => "lui",
+                _ => "auipc",
+            };
+            format!("{name}\t{},{:#x}", reg(rd), imm as u32 & 0xF_FFFF)
+        }
+        I {
+            opcode: 0b0001111,
+            funct3: 0x0,
+            imm,
+            ..
+        } => format!("fence\t{},{}", fence_set(imm >> 4), fence_set(imm)),
+        I {
+            opcode: 0b0001111,
+            funct3: 0x1,
+            ..
+        } => "fence.i".to_string(),
+        I {
+            opcode: 0b1110011,
+            funct3: 0x0,
+            imm,
+            ..
+        } => match imm {
+            0x0 => "ecall".to_string(),
+            0x1 => "ebreak".to_string(),
+            _ => "mret".to_string(),
+        },
+        I {
+            opcode: 0b1110011,
+            rd,
+            funct3,
+            rs1,
+            imm,
+        } => {
+            let csr = match Csr::name((imm as u16 & 0xFFF) as usize) {
+                "U" => format!("{:#x}", imm as u16 & 0xFFF),
+                name => name.to_string(),
+            };
+            match funct3 {
+                0x1 => format!("csrrw\t{},{csr},{}", reg(rd), reg(rs1)),
+                0x2 => format!("csrrs\t{},{csr},{}", reg(rd), reg(rs1)),
+                0x3 => format!("csrrc\t{},{csr},{}", reg(rd), reg(rs1)),
+                0x5 => format!("csrrwi\t{},{csr},{rs1}", reg(rd)),
+                0x6 => format!("csrrsi\t{},{csr},{rs1}", reg(rd)),
+                _ => format!("csrrci\t{},{csr},{rs1}", reg(rd)),
+            }
+        }
+        R {
+            opcode: 0b1110011,
+            rs1,
+            rs2,
+            funct7,
+            ..
+        } => match (funct7, rs2) {
+            (0x08, 0x5) => "wfi".to_string(),
+            (0x09, _) => format!("sfence.vma\t{},{}", reg(rs1), reg(rs2)),
+            _ => unknown(ins),
+        },
+        R {
+            opcode: 0b0101111,
+            rd,
+            funct3: funct3 @ (0x2 | 0x3),
+            rs1,
+            rs2,
+            funct7,
+        } => {
+            let width = if funct3 == 0x2 { "w" } else { "d" };
+            let ordering = match funct7 & 0b11 {
+                0b00 => "",
+                0b01 => ".rl",
+                0b10 => ".aq",
+                _ => ".aqrl",
+            };
+            let name = match funct7 >> 2 {
+                0x02 => {
+                    return format!("lr.{width}{ordering}\t{},({})", reg(rd), reg(rs1));
+                }
+                0x03 => "sc",
+                0x01 => "amoswap",
+                0x00 => "amoadd",
+                0x0C => "amoand",
+                0x08 => "amoor",
+                0x04 => "amoxor",
+                0x14 => "amomax",
+                0x10 => "amomin",
+                0x1C => "amomaxu",
+                0x18 => "amominu",
+                _ => return unknown(ins),
+            };
+            format!(
+                "{name}.{width}{ordering}\t{},{},({})",
+                reg(rd),
+                reg(rs2),
+                reg(rs1)
+            )
+        }
+        I {
+            opcode: 0b0000111,
+            rd,
+            funct3: 0x3,
+            rs1,
+            imm,
+        } => format!("fld\t{},{imm}({})", fpreg(rd), reg(rs1)),
+        S {
+            opcode: 0b0100111,
+            funct3: 0x3,
+            rs1,
+            rs2,
+            imm,
+        } => format!("fsd\t{},{imm}({})", fpreg(rs2), reg(rs1)),
+        R {
+            opcode: 0b1010011,
+            rd,
+            funct3: rm,
+            rs1,
+            rs2,
+            funct7,
+        } => match (funct7, rs2) {
+            (0x01 | 0x05 | 0x09 | 0x0D, _) => {
+                let name = match funct7 {
+                    0x01 => "fadd.d",
+                    0x05 => "fsub.d",
+                    0x09 => "fmul.d",
+                    _ => "fdiv.d",
+                };
+                let (rd, rs1, rs2) = (fpreg(rd), fpreg(rs1), fpreg(rs2));
+                format!("{name}\t{rd},{rs1},{rs2}{}", rounding(rm))
+            }
+            (0x61, 0x0..=0x3) => {
+                let name = ["fcvt.w.d", "fcvt.wu.d", "fcvt.l.d", "fcvt.lu.d"][rs2 as usize];
+                format!("{name}\t{},{}{}", reg(rd), fpreg(rs1), rounding(rm))
+            }
+            (0x69, 0x0..=0x3) => {
+                let name = ["fcvt.d.w", "fcvt.d.wu", "fcvt.d.l", "fcvt.d.lu"][rs2 as usize];
+                format!("{name}\t{},{}{}", fpreg(rd), reg(rs1), rounding(rm))
+            }
+            (0x71, 0x0) if rm == 0 => format!("fmv.x.d\t{},{}", reg(rd), fpreg(rs1)),
+            (0x79, 0x0) if rm == 0 => format!("fmv.d.x\t{},{}", fpreg(rd), reg(rs1)),
+            _ => unknown(ins),
+        },
+        _ => unknown(ins),
+    }
+}
+
+// The mnemonic on its own, with branch and jump targets as offsets like objdump shows them for
+// an unlinked object
+pub fn format_instruction(ins: Instruction, decoded: &InstructionFormat) -> String {
+    format(ins, decoded, 0)
+}
+
+// Instructions which do not decode are shown as data
+fn unknown(ins: Instruction) -> String {
+    match ins {
+        Instruction::IRV32(ins) => format!(".4byte\t{ins:#x}"),
+        Instruction::CRV32(ins) => format!(".2byte\t{ins:#x}"),
+    }
+}
+
+// The static rounding mode as an extra operand, the dynamic one is implied
+fn rounding(rm: u8) -> &'static str {
+    match rm {
+        0b000 => ",rne",
+        0b001 => ",rtz",
+        0b010 => ",rdn",
+        0b011 => ",rup",
+        0b100 => ",rmm",
+        _ => "",
+    }
+}
+
+// The accesses ordered by a fence, e.g. `iorw`
+fn fence_set(bits: i16) -> String {
+    let set: String = [(8, 'i'), (4, 'o'), (2, 'r'), (1, 'w')]
+        .iter()
+        .filter(|(bit, _)| bits & bit != 0)
+        .map(|(_, c)| c)
+        .collect();
+    match set.as_str() {
+        "" => "0".to_string(),
+        _ => set,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disasm::{disassemble, format_instruction};
+    use crate::ins::Instruction;
+
+    fn asm(word: u32) -> String {
+        let (ins, decoded) = Instruction::IRV32(word).decode().expect("decode");
+        format_instruction(ins, &decoded)
+    }
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!(asm(0x4083d333), "sra\tt1,t2,s0");
+        assert_eq!(asm(0xff077793), "andi\ta5,a4,-16");
+        assert_eq!(asm(0x43f55513), "srai\ta0,a0,0x3f");
+        assert_eq!(asm(0x00912623), "sw\ts1,12(sp)");
+        assert_eq!(asm(0x02b56063), "bltu\ta0,a1,20");
+        assert_eq!(asm(0xfffff197), "auipc\tgp,0xfffff");
+        assert_eq!(asm(0x0400006f), "jal\tzero,40");
+        assert_eq!(asm(0x00008067), "jalr\tzero,0(ra)");
+        assert_eq!(asm(0x3052d073), "csrrwi\tzero,mtvec,5");
+    }
+
+    #[test]
+    fn word_mnemonics() {
+        assert_eq!(asm(0xffd5851b), "addiw\ta0,a1,-3");
+        assert_eq!(asm(0x01f5151b), "slliw\ta0,a0,0x1f");
+        assert_eq!(asm(0x4035551b), "sraiw\ta0,a0,0x3");
+        assert_eq!(asm(0x00c5853b), "addw\ta0,a1,a2");
+        assert_eq!(asm(0x02c5853b), "mulw\ta0,a1,a2");
+        assert_eq!(asm(0x02c5f53b), "remuw\ta0,a1,a2");
+
+        // slliw with a 6-bit shamt, andiw, sltw and mulhw do not exist
+        for word in [0x0205151b, 0x0005751b, 0x00b5253b, 0x02b5153b] {
+            let listing = disassemble(&u32::to_le_bytes(word), 0);
+            assert_eq!(listing[0].2, format!(".4byte\t{word:#x}"));
+        }
+    }
+
+    #[test]
+    fn listing() {
+        #[rustfmt::skip]
+        let blob = [
+            0x13, 0x05, 0x50, 0x00, // addi	a0,zero,5
+            0x93, 0x15, 0x35, 0x00, // slli	a1,a0,0x3
+            0x3b, 0x86, 0xa5, 0x40, // subw	a2,a1,a0
+            0x03, 0x36, 0x81, 0x00, // ld	a2,8(sp)
+            0x23, 0x3c, 0xc1, 0xfe, // sd	a2,-8(sp)
+            0x63, 0x04, 0xb5, 0x00, // beq	a0,a1,8000001c
+            0x15, 0x05,             // c.addi	a0,5
+            0xb7, 0x52, 0x34, 0x12, // lui	t0,0x12345
+            0x73, 0x25, 0x00, 0x30, // csrrs	a0,mstatus,zero
+            0x2f, 0xb5, 0xc5, 0x04, // amoadd.d.aq	a0,a2,(a1)
+            0xd3, 0x15, 0x25, 0xc2, // fcvt.l.d	a1,fa0,rtz
+            0x0f, 0x00, 0x30, 0x03, // fence	rw,rw
+            0xef, 0xf0, 0x3f, 0xfd, // jal	ra,80000000
+            0x73, 0x00, 0x10, 0x00, // ebreak
+            0x13, 0x00,             // incomplete
+        ];
+
+        let listing = disassemble(&blob, 0x80000000);
+        let asm: Vec<(usize, &str)> = listing
+            .iter()
+            .map(|(addr, _, asm)| (*addr, asm.as_str()))
+            .collect();
+        assert_eq!(
+            asm,
+            vec![
+                (0x80000000, "addi\ta0,zero,5"),
+                (0x80000004, "slli\ta1,a0,0x3"),
+                (0x80000008, "subw\ta2,a1,a0"),
+                (0x8000000c, "ld\ta2,8(sp)"),
+                (0x80000010, "sd\ta2,-8(sp)"),
+                (0x80000014, "beq\ta0,a1,8000001c"),
+                (0x80000018, "addi\ta0,a0,5"),
+                (0x8000001a, "lui\tt0,0x12345"),
+                (0x8000001e, "csrrs\ta0,mstatus,zero"),
+                (0x80000022, "amoadd.d.aq\ta0,a2,(a1)"),
+                (0x80000026, "fcvt.l.d\ta1,fa0,rtz"),
+                (0x8000002a, "fence\trw,rw"),
+                (0x8000002e, "jal\tra,80000000"),
+                (0x80000032, "ebreak"),
+            ]
+        );
+    }
+}
@@ -1,5 +1,344 @@
+use std::collections::HashMap;
 use std::fs;
+use std::ops::Range;
+
+use crate::clint::{MEIP, MSIP, MTIP, SEIP};
+use crate::machine_builder::UART_IRQ;
 
 pub fn load(x: &str) -> Vec<u8> {
     fs::read(format!("data/{x}.dtb")).expect("no device tree data")
 }
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+// An empty memory reservation block, consisting only of its terminating entry
+const FDT_RSVMAP_SIZE: usize = 16;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+// mtime counts nanoseconds, see `Rtc`
+const TIMEBASE_FREQUENCY: u32 = 1_000_000_000;
+
+// Builds a flattened device tree blob, as described by chapter 5 of the devicetree specification
+pub struct Fdt {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+    depth: usize,
+}
+
+impl Fdt {
+    pub fn new() -> Fdt {
+        Self {
+            structure: vec![],
+            strings: vec![],
+            string_offsets: HashMap::new(),
+            depth: 0,
+        }
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "no node to end");
+        self.token(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        assert!(self.depth > 0, "property {name} outside of a node");
+        let nameoff = self.string(name);
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(nameoff);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, val: u32) {
+        self.property_cells(name, &[val]);
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, val: &str) {
+        self.property_strings(name, &[val]);
+    }
+
+    pub fn property_strings(&mut self, name: &str, vals: &[&str]) {
+        let mut value = vec![];
+        for val in vals {
+            value.extend_from_slice(val.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    // Assembles the header, memory reservation, structure and strings blocks
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "unterminated node");
+        self.token(FDT_END);
+
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + FDT_RSVMAP_SIZE;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let totalsize = off_dt_strings + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            totalsize as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0, // boot_cpuid_phys
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob = Vec::with_capacity(totalsize);
+        for field in header {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; FDT_RSVMAP_SIZE]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+
+    fn token(&mut self, val: u32) {
+        self.structure.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    fn string(&mut self, name: &str) -> u32 {
+        if let Some(&off) = self.string_offsets.get(name) {
+            return off;
+        }
+        let off = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), off);
+        off
+    }
+}
+
+impl Default for Fdt {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The devices mapped on the bus of a machine, to be described to the guest
+pub struct Layout {
+    pub harts: usize,
+    pub ram: Range<usize>,
+    pub clint: Option<Range<usize>>,
+    pub plic: Option<Range<usize>>,
+    pub uart: Option<Range<usize>>,
+}
+
+// Generates the device tree of a machine, with every node derived from its `Layout`
+pub fn generate(layout: &Layout) -> Vec<u8> {
+    // phandles of the per hart interrupt controllers, followed by the PLIC
+    let intc_phandle = |hart: usize| hart as u32 + 1;
+    let plic_phandle = layout.harts as u32 + 1;
+
+    let mut fdt = Fdt::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 1);
+    fdt.property_string("compatible", "BuJo,rriscv");
+    fdt.property_string("model", "BuJo,rriscv");
+
+    fdt.begin_node("chosen");
+    if let Some(uart) = &layout.uart {
+        fdt.property_string("stdout-path", &format!("/soc/uart@{:x}", uart.start));
+    }
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY);
+    for hart in 0..layout.harts {
+        fdt.begin_node(&format!("cpu@{hart}"));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", hart as u32);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", "rv64imac");
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", intc_phandle(hart));
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", layout.ram.start));
+    fdt.property_string("device_type", "memory");
+    fdt.property_cells("reg", &reg(&layout.ram));
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 1);
+    fdt.property_strings("compatible", &["BuJo,rriscv-soc", "simple-bus"]);
+    fdt.property_null("ranges");
+
+    if let Some(clint) = &layout.clint {
+        let interrupts: Vec<u32> = (0..layout.harts)
+            .flat_map(|hart| {
+                [
+                    intc_phandle(hart),
+                    MSIP as u32,
+                    intc_phandle(hart),
+                    MTIP as u32,
+                ]
+            })
+            .collect();
+        fdt.begin_node(&format!("clint@{:x}", clint.start));
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.property_cells("reg", &reg(clint));
+        fdt.property_cells("interrupts-extended", &interrupts);
+        fdt.end_node();
+    }
+
+    if let Some(plic) = &layout.plic {
+        let interrupts: Vec<u32> = (0..layout.harts)
+            .flat_map(|hart| {
+                [
+                    intc_phandle(hart),
+                    MEIP as u32,
+                    intc_phandle(hart),
+                    SEIP as u32,
+                ]
+            })
+            .collect();
+        fdt.begin_node(&format!("plic@{:x}", plic.start));
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_cells("reg", &reg(plic));
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        fdt.property_u32("riscv,ndev", 31);
+        fdt.property_cells("interrupts-extended", &interrupts);
+        fdt.property_u32("phandle", plic_phandle);
+        fdt.end_node();
+    }
+
+    if let Some(uart) = &layout.uart {
+        fdt.begin_node(&format!("uart@{:x}", uart.start));
+        fdt.property_string("compatible", "ns16550a");
+        fdt.property_cells("reg", &reg(uart));
+        fdt.property_u32("clock-frequency", 3686400);
+        if layout.plic.is_some() {
+            fdt.property_u32("interrupt-parent", plic_phandle);
+            fdt.property_u32("interrupts", UART_IRQ);
+        }
+        fdt.end_node();
+    }
+
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+// A `reg` property for one address and one size cell
+fn reg(range: &Range<usize>) -> [u32; 2] {
+    [range.start as u32, range.len() as u32]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dt::{generate, Layout};
+
+    fn be32(blob: &[u8], off: usize) -> u32 {
+        u32::from_be_bytes(blob[off..off + 4].try_into().unwrap())
+    }
+
+    // Walks the structure block, returning the value of property `name` of the node at `path`
+    fn find_property(blob: &[u8], path: &str, name: &str) -> Option<Vec<u8>> {
+        let off_struct = be32(blob, 8) as usize;
+        let off_strings = be32(blob, 12) as usize;
+        let cstr = |off: usize| {
+            let len = blob[off..].iter().position(|&b| b == 0).unwrap();
+            String::from_utf8(blob[off..off + len].to_vec()).unwrap()
+        };
+
+        let mut nodes: Vec<String> = vec![];
+        let mut off = off_struct;
+        loop {
+            let token = be32(blob, off);
+            off += 4;
+            match token {
+                1 => {
+                    let node = cstr(off);
+                    off = (off + node.len() + 1).next_multiple_of(4);
+                    nodes.push(node);
+                }
+                2 => {
+                    nodes.pop();
+                }
+                3 => {
+                    let len = be32(blob, off) as usize;
+                    let prop = cstr(off_strings + be32(blob, off + 4) as usize);
+                    off += 8;
+                    if nodes.join("/") == path && prop == name {
+                        return Some(blob[off..off + len].to_vec());
+                    }
+                    off = (off + len).next_multiple_of(4);
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    #[test]
+    fn generated_blob() {
+        let blob = generate(&Layout {
+            harts: 1,
+            ram: 0x80000000..0x80800000,
+            clint: Some(0x2000000..0x2004000),
+            plic: Some(0xc000000..0xc400000),
+            uart: Some(0x10000000..0x10000010),
+        });
+
+        assert_eq!(be32(&blob, 0), 0xd00dfeed);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+
+        let reg = find_property(&blob, "/memory@80000000", "reg").expect("memory node");
+        assert_eq!(reg, [0x80, 0, 0, 0, 0, 0x80, 0, 0]);
+        let device_type = find_property(&blob, "/memory@80000000", "device_type");
+        assert_eq!(device_type.as_deref(), Some(&b"memory\0"[..]));
+
+        assert!(find_property(&blob, "/soc/uart@10000000", "interrupts").is_some());
+    }
+}
@@ -4,7 +4,8 @@ use std::sync::RwLock;
 use crate::device::Device;
 use crate::plic::Fault;
 
-type DeviceList = Vec<(Range<usize>, Box<dyn Device>)>;
+// Mapped devices along with their label, e.g. "uart"
+type DeviceList = Vec<(Range<usize>, String, Box<dyn Device>)>;
 
 pub struct DynBus {
     devices: RwLock<DeviceList>,
@@ -25,9 +26,58 @@ impl DynBus {
     }
 
     pub fn map(&mut self, device: impl Device + 'static, range: Range<usize>) {
+        self.map_named("device", device, range);
+    }
+
+    // Panics if `range` overlaps a range already mapped, as one device would shadow the other
+    pub fn map_named(&mut self, name: &str, device: impl Device + 'static, range: Range<usize>) {
         let mut devices = self.devices.write().unwrap();
 
-        devices.push((range, Box::new(device)));
+        if let Some((mapped, ..)) = devices
+            .iter()
+            .find(|(mapped, ..)| range.start < mapped.end && mapped.start < range.end)
+        {
+            panic!(
+                "mapping {:#x?} overlaps already mapped {:#x?}",
+                range, mapped
+            );
+        }
+
+        let idx = devices.partition_point(|(mapped, ..)| mapped.start <= range.start);
+        devices.insert(idx, (range, name.to_string(), Box::new(device)));
+    }
+
+    // Lets every mapped device do its periodic work, see `Device::tick`
+    pub fn tick_devices(&self) {
+        let devices = self.devices.read().unwrap();
+        for (_, _, device) in devices.iter() {
+            device.tick();
+        }
+    }
+
+    // The labels and ranges of all mapped devices, ordered by address
+    pub fn mappings(&self) -> Vec<(String, Range<usize>)> {
+        let devices = self.devices.read().unwrap();
+        devices
+            .iter()
+            .map(|(range, name, _)| (name.clone(), range.clone()))
+            .collect()
+    }
+
+    // Whether the device mapped at `addr` is read-only, see `Device::read_only`
+    pub fn is_read_only(&self, addr: usize) -> bool {
+        let devices = self.devices.read().unwrap();
+        DynBus::find(&devices, addr).is_some_and(|(_, _, device)| device.read_only())
+    }
+
+    // Devices are kept sorted by the start of their range and never overlap, so the only
+    // candidate for `addr` is the last device starting at or below it.
+    fn find(devices: &DeviceList, addr: usize) -> Option<&(Range<usize>, String, Box<dyn Device>)> {
+        let idx = devices.partition_point(|(range, ..)| range.start <= addr);
+
+        idx.checked_sub(1)
+            .map(|idx| &devices[idx])
+            .filter(|(range, ..)| range.contains(&addr))
     }
 }
 
@@ -41,96 +91,163 @@ impl Device for DynBus {
     fn write_double(&self, addr: usize, val: u64) -> Result<(), Fault> {
         let devices = self.devices.read().unwrap();
 
-        for (range, device) in devices.iter() {
-            if range.contains(&addr) {
-                return device.write_double(addr - range.start, val);
-            }
+        match DynBus::find(&devices, addr) {
+            Some((range, _, device)) => device.write_double(addr - range.start, val),
+            None => Err(Fault::StoreFault(addr)),
         }
-        Err(Fault::Unmapped(addr))
     }
     fn write_word(&self, addr: usize, val: u32) -> Result<(), Fault> {
         let devices = self.devices.read().unwrap();
 
-        for (range, device) in devices.iter() {
-            if range.contains(&addr) {
-                return device.write_word(addr - range.start, val);
-            }
+        match DynBus::find(&devices, addr) {
+            Some((range, _, device)) => device.write_word(addr - range.start, val),
+            None => Err(Fault::StoreFault(addr)),
         }
-        Err(Fault::Unmapped(addr))
     }
 
     fn write_half(&self, addr: usize, val: u16) -> Result<(), Fault> {
         let devices = self.devices.read().unwrap();
 
-        for (range, device) in devices.iter() {
-            if range.contains(&addr) {
-                return device.write_half(addr - range.start, val);
-            }
+        match DynBus::find(&devices, addr) {
+            Some((range, _, device)) => device.write_half(addr - range.start, val),
+            None => Err(Fault::StoreFault(addr)),
         }
-        Err(Fault::Unmapped(addr))
     }
 
     fn write_byte(&self, addr: usize, val: u8) -> Result<(), Fault> {
         let devices = self.devices.read().unwrap();
 
-        for (range, device) in devices.iter() {
-            if range.contains(&addr) {
-                return device.write_byte(addr - range.start, val);
-            }
+        match DynBus::find(&devices, addr) {
+            Some((range, _, device)) => device.write_byte(addr - range.start, val),
+            None => Err(Fault::StoreFault(addr)),
         }
-        Err(Fault::Unmapped(addr))
     }
 
     fn read_double(&self, addr: usize) -> Result<u64, Fault> {
         let devices = self.devices.read().unwrap();
 
-        for (range, device) in devices.iter() {
-            if range.contains(&addr) {
-                return device.read_double(addr - range.start);
-            }
+        match DynBus::find(&devices, addr) {
+            Some((range, _, device)) => device.read_double(addr - range.start),
+            None => Err(Fault::Unmapped(addr)),
         }
-        Err(Fault::Unmapped(addr))
     }
     fn read_word(&self, addr: usize) -> Result<u32, Fault> {
         let devices = self.devices.read().unwrap();
 
-        for (range, device) in devices.iter() {
-            if range.contains(&addr) {
-                return device.read_word(addr - range.start);
-            }
+        match DynBus::find(&devices, addr) {
+            Some((range, _, device)) => device.read_word(addr - range.start),
+            None => Err(Fault::Unmapped(addr)),
         }
-        Err(Fault::Unmapped(addr))
     }
 
     fn read_half(&self, addr: usize) -> Result<u16, Fault> {
         let devices = self.devices.read().unwrap();
 
-        for (range, device) in devices.iter() {
-            if range.contains(&addr) {
-                return device.read_half(addr - range.start);
-            }
+        match DynBus::find(&devices, addr) {
+            Some((range, _, device)) => device.read_half(addr - range.start),
+            None => Err(Fault::Unmapped(addr)),
         }
-        Err(Fault::Unmapped(addr))
     }
 
     fn read_byte(&self, addr: usize) -> Result<u8, Fault> {
         let devices = self.devices.read().unwrap();
 
-        for (range, device) in devices.iter() {
-            if range.contains(&addr) {
-                return device.read_byte(addr - range.start);
-            }
+        match DynBus::find(&devices, addr) {
+            Some((range, _, device)) => device.read_byte(addr - range.start),
+            None => Err(Fault::Unmapped(addr)),
+        }
+    }
+
+    fn amo_word(&self, addr: usize, f: &dyn Fn(u32) -> u32) -> Result<u32, Fault> {
+        let devices = self.devices.read().unwrap();
+
+        match DynBus::find(&devices, addr) {
+            Some((range, _, device)) => device.amo_word(addr - range.start, f),
+            None => Err(Fault::StoreFault(addr)),
+        }
+    }
+
+    fn amo_double(&self, addr: usize, f: &dyn Fn(u64) -> u64) -> Result<u64, Fault> {
+        let devices = self.devices.read().unwrap();
+
+        match DynBus::find(&devices, addr) {
+            Some((range, _, device)) => device.amo_double(addr - range.start, f),
+            None => Err(Fault::StoreFault(addr)),
+        }
+    }
+
+    fn tick(&self) {
+        self.tick_devices();
+    }
+
+    // Locates `addr` relative to the mappings around it, e.g.
+    // `0xffffff0 is below uart@10000000 [0x10000000..0x10000010]`
+    fn describe(&self, addr: usize) -> Option<String> {
+        let devices = self.devices.read().unwrap();
+        let mapping = |(range, name, _): &(Range<usize>, String, Box<dyn Device>)| {
+            format!(
+                "{name}@{:x} [{:#x}..{:#x}]",
+                range.start, range.start, range.end
+            )
+        };
+
+        if let Some(device) = DynBus::find(&devices, addr) {
+            return Some(format!("{addr:#x} is in {}", mapping(device)));
+        }
+        let idx = devices.partition_point(|(range, ..)| range.start <= addr);
+        let below = idx.checked_sub(1).map(|idx| mapping(&devices[idx]));
+        let above = devices.get(idx).map(mapping);
+        match (below, above) {
+            (Some(below), Some(above)) => Some(format!("{addr:#x} is between {below} and {above}")),
+            (Some(below), None) => Some(format!("{addr:#x} is above {below}")),
+            (None, Some(above)) => Some(format!("{addr:#x} is below {above}")),
+            (None, None) => Some(format!("{addr:#x} is unmapped, the bus is empty")),
         }
-        Err(Fault::Unmapped(addr))
+    }
+
+    // Bulk access is split at device boundaries, each device sees a single access
+    fn read_bytes(&self, addr: usize, buf: &mut [u8]) -> Result<(), Fault> {
+        let devices = self.devices.read().unwrap();
+
+        let mut done = 0;
+        while done < buf.len() {
+            let addr = addr + done;
+            let (range, _, device) = DynBus::find(&devices, addr).ok_or(Fault::Unmapped(addr))?;
+            let len = (range.end - addr).min(buf.len() - done);
+            device.read_bytes(addr - range.start, &mut buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&self, addr: usize, data: &[u8]) -> Result<(), Fault> {
+        let devices = self.devices.read().unwrap();
+
+        let mut done = 0;
+        while done < data.len() {
+            let addr = addr + done;
+            let (range, _, device) = DynBus::find(&devices, addr).ok_or(Fault::StoreFault(addr))?;
+            let len = (range.end - addr).min(data.len() - done);
+            device.write_bytes(addr - range.start, &data[done..done + len])?;
+            done += len;
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
     use crate::device::Device;
     use crate::dynbus::DynBus;
+    use crate::hart::Hart;
     use crate::htif::Htif;
+    use crate::plic::Fault;
     use crate::ram::Ram;
+    use crate::rom::Rom;
 
     #[test]
     fn basic() {
@@ -158,4 +275,214 @@ mod test {
         let err = bus.write_word(0x0, 0x0);
         assert_eq!(err.is_ok(), false, "should shut down");
     }
+
+    #[test]
+    #[should_panic(expected = "mapping 0x1000..0x3000 overlaps already mapped 0x0..0x2000")]
+    fn overlapping() {
+        let mut bus = DynBus::new();
+        bus.map(Ram::new(), 0..0x2000);
+        bus.map(Htif::new(), 0x1000..0x3000);
+    }
+
+    #[test]
+    fn adjacent() {
+        let mut bus = DynBus::new();
+        bus.map(Ram::new(), 0..0x2000);
+        bus.map(Htif::new(), 0x2000..0x2050);
+
+        assert!(bus.write_word(0x1ffc, 0x0).is_ok(), "ram should write");
+        assert!(bus.write_word(0x2000, 0x0).is_err(), "should shut down");
+    }
+
+    #[test]
+    fn bulk_across_devices() {
+        let mut bus = DynBus::new();
+        bus.map(Ram::sized(0x100), 0x1000..0x1100);
+        bus.map(Ram::sized(0x100), 0x1100..0x1200);
+
+        let data: Vec<u8> = (0..0x80).collect();
+        bus.write_bytes(0x10c0, &data).expect("write");
+
+        let mut bulk = [0; 0x80];
+        bus.read_bytes(0x10c0, &mut bulk).expect("read");
+        assert_eq!(bulk.to_vec(), data);
+        assert_eq!(bus.read_byte(0x1100).expect("read"), 0x40);
+
+        // running off the end of the last device
+        assert!(matches!(
+            bus.read_bytes(0x11c0, &mut bulk),
+            Err(Fault::Unmapped(0x1200))
+        ));
+    }
+
+    #[test]
+    fn load_and_store_faults() {
+        let mut bus = DynBus::new();
+        bus.map(Rom::new(vec![0; 0x10]), 0x1000..0x1010);
+
+        let load = bus.read_word(0x2000).expect_err("unmapped");
+        assert!(matches!(load, Fault::Unmapped(0x2000)));
+        assert_eq!(load.cause_code(), Some(5));
+
+        let store = bus.write_word(0x2000, 0).expect_err("unmapped");
+        assert!(matches!(store, Fault::StoreFault(0x2000)));
+        assert_eq!(store.cause_code(), Some(7));
+
+        let amo = bus.amo_word(0x2000, &|val| val).expect_err("unmapped");
+        assert_eq!(amo.cause_code(), Some(7));
+
+        let store = bus.write_word(0x1000, 0).expect_err("read-only");
+        assert_eq!(store.cause_code(), Some(7));
+    }
+
+    // Deterministic addresses spread over the low 64KiB, plus some out of range
+    fn addresses() -> impl Iterator<Item = usize> {
+        let mut state: u64 = 0x2545F4914F6CDD1D;
+        (0..10_000).map(move |_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((state >> 33) % 0x11000) as usize
+        })
+    }
+
+    #[test]
+    fn resolution_matches_linear() {
+        // mapped out of order, with holes
+        let mut ranges: Vec<_> = (0..0x100).map(|i| i * 0x100..i * 0x100 + 0xc0).collect();
+        ranges.reverse();
+        ranges.swap(3, 200);
+
+        let mut bus = DynBus::new();
+        for range in &ranges {
+            let ram = Ram::sized(range.len());
+            ram.write(0, vec![(range.start >> 8) as u8; range.len()]);
+            bus.map(ram, range.clone());
+        }
+
+        for addr in addresses() {
+            let linear = ranges.iter().find(|range| range.contains(&addr));
+            match (linear, bus.read_byte(addr)) {
+                (Some(range), Ok(val)) => assert_eq!(val, (range.start >> 8) as u8),
+                (None, Err(Fault::Unmapped(a))) => assert_eq!(a, addr),
+                (linear, res) => panic!("{:#x}: {:?} vs {:?}", addr, linear, res),
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "timing based, run with --ignored"]
+    fn resolution_is_sublinear() {
+        fn lookup_time(devices: usize) -> Duration {
+            let mut bus = DynBus::new();
+            for i in 0..devices {
+                bus.map(Ram::sized(0x10), i * 0x10..(i + 1) * 0x10);
+            }
+            let last = (devices - 1) * 0x10;
+
+            (0..5)
+                .map(|_| {
+                    let start = Instant::now();
+                    for _ in 0..10_000 {
+                        bus.read_byte(last).expect("read");
+                    }
+                    start.elapsed()
+                })
+                .min()
+                .unwrap()
+        }
+
+        // A linear scan would be 256 times slower for the last device
+        let few = lookup_time(16);
+        let many = lookup_time(16 * 256);
+        assert!(many < few * 16, "{:?} vs {:?}", few, many);
+    }
+
+    #[test]
+    fn mappings() {
+        let mut bus = DynBus::new();
+        bus.map_named("uart", Ram::sized(0x10), 0x10000000..0x10000010);
+        bus.map_named("memory", Ram::sized(0x1000), 0x80000000..0x80001000);
+        bus.map(Rom::new(vec![0; 0x10]), 0x1000..0x1010);
+
+        assert_eq!(
+            bus.mappings(),
+            vec![
+                ("device".to_string(), 0x1000..0x1010),
+                ("uart".to_string(), 0x10000000..0x10000010),
+                ("memory".to_string(), 0x80000000..0x80001000),
+            ]
+        );
+        assert!(bus.is_read_only(0x1000));
+        assert!(!bus.is_read_only(0x80000000));
+        assert!(!bus.is_read_only(0x2000), "unmapped");
+    }
+
+    #[test]
+    fn describe() {
+        let mut bus = DynBus::new();
+        bus.map_named("uart", Ram::sized(0x10), 0x10000000..0x10000010);
+        bus.map_named("memory", Ram::sized(0x1000), 0x80000000..0x80001000);
+
+        assert_eq!(
+            bus.describe(0xffffff0).expect("description"),
+            "0xffffff0 is below uart@10000000 [0x10000000..0x10000010]"
+        );
+        let between = bus.describe(0x10000010).expect("description");
+        assert!(between.contains("between uart@10000000"), "{}", between);
+        assert!(between.contains("and memory@80000000"), "{}", between);
+        assert!(bus
+            .describe(0x80000004)
+            .expect("description")
+            .contains("is in memory"));
+    }
+
+    #[test]
+    fn tick_devices() {
+        struct Counter(Arc<AtomicU32>);
+
+        impl Device for Counter {
+            fn write_double(&self, addr: usize, _val: u64) -> Result<(), Fault> {
+                Err(Fault::StoreFault(addr))
+            }
+            fn write_word(&self, addr: usize, _val: u32) -> Result<(), Fault> {
+                Err(Fault::StoreFault(addr))
+            }
+            fn write_half(&self, addr: usize, _val: u16) -> Result<(), Fault> {
+                Err(Fault::StoreFault(addr))
+            }
+            fn write_byte(&self, addr: usize, _val: u8) -> Result<(), Fault> {
+                Err(Fault::StoreFault(addr))
+            }
+            fn read_double(&self, addr: usize) -> Result<u64, Fault> {
+                Err(Fault::MemoryFault(addr))
+            }
+            fn read_word(&self, _addr: usize) -> Result<u32, Fault> {
+                Ok(self.0.load(Ordering::SeqCst))
+            }
+            fn read_half(&self, addr: usize) -> Result<u16, Fault> {
+                Err(Fault::MemoryFault(addr))
+            }
+            fn read_byte(&self, addr: usize) -> Result<u8, Fault> {
+                Err(Fault::MemoryFault(addr))
+            }
+            fn tick(&self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let ticks = Arc::new(AtomicU32::new(0));
+        let mut bus = DynBus::new();
+        bus.map(Ram::sized(0x1000), 0x80000000..0x80001000);
+        bus.map(Counter(ticks.clone()), 0x1000..0x1004);
+        let bus = Arc::new(bus);
+        let mut hart = Hart::new(0, 0x80000000, bus.clone());
+
+        for _ in 0..10 {
+            hart.tick().expect("tick");
+            bus.tick_devices();
+        }
+        assert_eq!(ticks.load(Ordering::SeqCst), 10);
+        assert_eq!(bus.read_word(0x1000).expect("counter"), 10);
+    }
 }
@@ -1,34 +1,197 @@
 use std::borrow::Cow;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::ops::Range;
+use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use gdb_remote_protocol::Signal::{SIGSTOP, SIGTRAP};
 use gdb_remote_protocol::{
     Breakpoint, Error, Handler, MemoryRegion, ProcessType, StopReason, ThreadId, VCont,
-    VContFeature,
+    VContFeature, Watchpoint,
 };
 use log::debug;
 
 use crate::device::Device;
 use crate::dynbus::DynBus;
-use crate::hart::Hart;
+use crate::hart::{AccessKind, Hart};
 use crate::plic::Fault;
 
+// The accesses a watchpoint stops on
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Watch {
+    Read,
+    Write,
+    Access,
+}
+
+impl Watch {
+    fn matches(self, kind: AccessKind) -> bool {
+        match self {
+            Watch::Read => kind != AccessKind::Write,
+            Watch::Write => kind != AccessKind::Read,
+            Watch::Access => true,
+        }
+    }
+}
+
+// Register numbers as used by gdb for RISC-V, CSRs follow the floating point registers
+const PC_REGNUM: u64 = 32;
+const FIRST_FPR_REGNUM: u64 = 33;
+const FIRST_CSR_REGNUM: u64 = 65;
+const LAST_CSR_REGNUM: u64 = FIRST_CSR_REGNUM + 4095;
+
+// How the hart is left when gdb connects
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExecutionMode {
+    // Waits for gdb to continue or step
+    Paused,
+    // Runs until a breakpoint, or until interrupted, before reporting the first stop to gdb
+    Running,
+}
+
+type Watchpoints = Vec<(Range<usize>, Watch)>;
+
+// What the hart does once gdb is gone, see `Emulator::resume`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DetachPolicy {
+    // Runs on without breakpoints
+    Resume,
+    // Stays where it was stopped
+    Halt,
+}
+
+// Clones share the hart and the debugger state, so a handle can be kept while another is
+// handed to the gdb session
+#[derive(Clone)]
 pub struct Emulator {
-    hart: RefCell<Hart<DynBus>>,
-    breakpoints: RefCell<Vec<usize>>,
+    hart: Rc<RefCell<Hart<DynBus>>>,
+    mode: Rc<Cell<ExecutionMode>>,
+    on_detach: DetachPolicy,
+    breakpoints: Rc<RefCell<Vec<usize>>>,
+    watchpoints: Rc<RefCell<Watchpoints>>,
     trap: Arc<AtomicBool>,
 }
 
 impl Emulator {
     pub fn new(hart: Hart<DynBus>) -> Emulator {
+        Emulator::with_mode(hart, ExecutionMode::Paused)
+    }
+
+    pub fn with_mode(hart: Hart<DynBus>, mode: ExecutionMode) -> Emulator {
         Emulator {
-            hart: hart.into(),
-            breakpoints: RefCell::new(vec![]),
+            hart: Rc::new(hart.into()),
+            mode: Rc::new(Cell::new(mode)),
+            on_detach: DetachPolicy::Resume,
+            breakpoints: Rc::new(RefCell::new(vec![])),
+            watchpoints: Rc::new(RefCell::new(vec![])),
             trap: Arc::new(AtomicBool::new(false)),
         }
     }
+
+    pub fn set_detach_policy(&mut self, policy: DetachPolicy) {
+        self.on_detach = policy;
+    }
+
+    // Continues without gdb, after it detached or the connection dropped.  Breakpoints are
+    // dropped, as there is nobody left to report them to.  Returns the fault the hart stopped
+    // on, e.g. `Fault::Halt` on shutdown.
+    pub fn resume(&self) -> Result<(), Fault> {
+        self.breakpoints.borrow_mut().clear();
+        self.watchpoints.borrow_mut().clear();
+        if self.on_detach == DetachPolicy::Halt {
+            return Ok(());
+        }
+
+        let mut hart = self.hart.borrow_mut();
+        loop {
+            hart.tick()?;
+            hart.bus.tick_devices();
+        }
+    }
+
+    // The memory map as served for `qXfer:memory-map:read`, listing read-only devices as ROM and
+    // everything else mapped on the bus as RAM
+    pub fn memory_map(&self) -> String {
+        let mut xml = String::from(concat!(
+            "<?xml version=\"1.0\"?>\n",
+            "<!DOCTYPE memory-map PUBLIC \"+//IDN gnu.org//DTD GDB Memory Map V1.0//EN\" ",
+            "\"http://sourceware.org/gdb/gdb-memory-map.dtd\">\n",
+            "<memory-map>\n",
+        ));
+        let bus = &self.hart.borrow().bus;
+        for (_, range) in bus.mappings() {
+            let kind = match bus.is_read_only(range.start) {
+                true => "rom",
+                false => "ram",
+            };
+            xml += &format!(
+                "  <memory type=\"{}\" start=\"{:#x}\" length=\"{:#x}\"/>\n",
+                kind,
+                range.start,
+                range.len()
+            );
+        }
+        xml += "</memory-map>\n";
+        xml
+    }
+
+    // Executes one instruction, returning whether it touched a watched address or hit an ebreak
+    fn step(&self, hart: &mut Hart<DynBus>) -> Result<bool, Error> {
+        let watched = hart.next_access().is_some_and(|access| {
+            let accessed = access.addr..access.addr + access.width;
+            self.watchpoints.borrow().iter().any(|(range, watch)| {
+                watch.matches(access.kind)
+                    && range.start < accessed.end
+                    && accessed.start < range.end
+            })
+        });
+        let res = hart.tick();
+        hart.bus.tick_devices();
+        match res {
+            Ok(()) => Ok(watched),
+            Err(Fault::Breakpoint) => Ok(true),
+            Err(fault) => Err(fault.into()),
+        }
+    }
+
+    // Continues until a breakpoint, a watchpoint or SIGTRAP
+    fn run(&self) -> Result<StopReason, Error> {
+        let mut cpu_ref = self.hart.borrow_mut();
+        if self.step(&mut cpu_ref)? {
+            return Ok(StopReason::Signal(SIGTRAP as u8));
+        }
+        while !self.breakpoints.borrow().contains(&cpu_ref.get_pc()) {
+            if self.trap.load(Ordering::Relaxed) {
+                self.trap.store(false, Ordering::Relaxed);
+                return Ok(StopReason::Signal(SIGTRAP as u8));
+            }
+
+            if self.step(&mut cpu_ref)? {
+                return Ok(StopReason::Signal(SIGTRAP as u8));
+            }
+        }
+        Ok(StopReason::Signal(SIGTRAP as u8))
+    }
+
+    fn insert_watchpoint(&self, watchpoint: Watchpoint, watch: Watch) -> Result<(), Error> {
+        let start = watchpoint.addr as usize;
+        let range = start..start + watchpoint.n_bytes as usize;
+        let mut watchpoints = self.watchpoints.borrow_mut();
+        if !watchpoints.contains(&(range.clone(), watch)) {
+            watchpoints.push((range, watch));
+        }
+        Ok(())
+    }
+
+    fn remove_watchpoint(&self, watchpoint: Watchpoint, watch: Watch) -> Result<(), Error> {
+        let start = watchpoint.addr as usize;
+        let range = start..start + watchpoint.n_bytes as usize;
+        self.watchpoints
+            .borrow_mut()
+            .retain(|w| *w != (range.clone(), watch));
+        Ok(())
+    }
 }
 
 impl Handler for Emulator {
@@ -42,35 +205,95 @@ impl Handler for Emulator {
 
     fn detach(&self, _pid: Option<u64>) -> Result<(), Error> {
         debug!("process detached");
+        self.breakpoints.borrow_mut().clear();
+        self.watchpoints.borrow_mut().clear();
         Ok(())
     }
 
     fn read_memory(&self, region: MemoryRegion) -> Result<Vec<u8>, Error> {
-        let mut result: Vec<u8> = vec![];
-        for i in 0..region.length {
-            result.push(
-                self.hart
-                    .borrow()
-                    .bus
-                    .read_byte((region.address + i) as usize)?,
-            );
-        }
+        let mut result = vec![0; region.length as usize];
+        self.hart
+            .borrow()
+            .bus
+            .read_bytes(region.address as usize, &mut result)?;
         Ok(result)
     }
 
+    fn write_memory(&self, address: u64, bytes: &[u8]) -> Result<(), Error> {
+        self.hart
+            .borrow()
+            .bus
+            .write_bytes(address as usize, bytes)?;
+        Ok(())
+    }
+
     fn read_general_registers(&self) -> Result<Vec<u8>, Error> {
         debug!("reading registers");
+        let hart = self.hart.borrow();
         let mut result = Vec::new();
-        for i in 0..32 {
-            let reg = self.hart.borrow().get_register(i);
+        for reg in hart.get_registers() {
             result.extend_from_slice(&reg.to_le_bytes());
         }
-        let reg = self.hart.borrow().get_pc();
-        result.extend_from_slice(&reg.to_le_bytes());
+        result.extend_from_slice(&hart.get_pc().to_le_bytes());
         Ok(result)
     }
 
+    // x0 stays hardwired to zero, whatever gdb writes to it
+    fn write_general_registers(&self, contents: &[u8]) -> Result<(), Error> {
+        debug!("writing registers");
+        if contents.len() != 33 * 8 {
+            return Err(Error::Error(6));
+        }
+        let mut values = contents
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()));
+        let registers: [u64; 32] = std::array::from_fn(|_| values.next().unwrap());
+        let pc = values.next().unwrap();
+
+        let mut hart = self.hart.borrow_mut();
+        hart.set_registers(registers);
+        hart.set_pc(pc as usize);
+        Ok(())
+    }
+
+    fn read_register(&self, register: u64) -> Result<Vec<u8>, Error> {
+        let hart = self.hart.borrow();
+        let val = match register {
+            0..PC_REGNUM => hart.get_register(register as u8),
+            PC_REGNUM => hart.get_pc() as u64,
+            FIRST_FPR_REGNUM..FIRST_CSR_REGNUM => {
+                hart.get_fregister((register - FIRST_FPR_REGNUM) as u8)
+            }
+            FIRST_CSR_REGNUM..=LAST_CSR_REGNUM => {
+                hart.get_csr((register - FIRST_CSR_REGNUM) as usize)
+            }
+            _ => return Err(Error::Unimplemented),
+        };
+        Ok(val.to_le_bytes().to_vec())
+    }
+
+    fn write_register(&self, register: u64, contents: &[u8]) -> Result<(), Error> {
+        let val = u64::from_le_bytes(contents.try_into().map_err(|_| Error::Error(6))?);
+        let mut hart = self.hart.borrow_mut();
+        match register {
+            0..PC_REGNUM => hart.set_register(register as u8, val),
+            PC_REGNUM => hart.set_pc(val as usize),
+            FIRST_FPR_REGNUM..FIRST_CSR_REGNUM => {
+                hart.set_fregister((register - FIRST_FPR_REGNUM) as u8, val)
+            }
+            FIRST_CSR_REGNUM..=LAST_CSR_REGNUM => {
+                hart.set_csr((register - FIRST_CSR_REGNUM) as usize, val)
+            }
+            _ => return Err(Error::Unimplemented),
+        }
+        Ok(())
+    }
+
     fn halt_reason(&self) -> Result<StopReason, Error> {
+        if self.mode.replace(ExecutionMode::Paused) == ExecutionMode::Running {
+            debug!("running until stopped");
+            return self.run();
+        }
         debug!("halted");
         Ok(StopReason::Signal(SIGTRAP as u8))
     }
@@ -91,6 +314,18 @@ impl Handler for Emulator {
         self.insert_software_breakpoint(breakpoint)
     }
 
+    fn insert_write_watchpoint(&self, watchpoint: Watchpoint) -> Result<(), Error> {
+        self.insert_watchpoint(watchpoint, Watch::Write)
+    }
+
+    fn insert_read_watchpoint(&self, watchpoint: Watchpoint) -> Result<(), Error> {
+        self.insert_watchpoint(watchpoint, Watch::Read)
+    }
+
+    fn insert_access_watchpoint(&self, watchpoint: Watchpoint) -> Result<(), Error> {
+        self.insert_watchpoint(watchpoint, Watch::Access)
+    }
+
     fn remove_software_breakpoint(&self, breakpoint: Breakpoint) -> Result<(), Error> {
         self.breakpoints
             .borrow_mut()
@@ -102,6 +337,18 @@ impl Handler for Emulator {
         self.remove_software_breakpoint(breakpoint)
     }
 
+    fn remove_write_watchpoint(&self, watchpoint: Watchpoint) -> Result<(), Error> {
+        self.remove_watchpoint(watchpoint, Watch::Write)
+    }
+
+    fn remove_read_watchpoint(&self, watchpoint: Watchpoint) -> Result<(), Error> {
+        self.remove_watchpoint(watchpoint, Watch::Read)
+    }
+
+    fn remove_access_watchpoint(&self, watchpoint: Watchpoint) -> Result<(), Error> {
+        self.remove_watchpoint(watchpoint, Watch::Access)
+    }
+
     fn query_supported_vcont(&self) -> Result<Cow<'static, [VContFeature]>, Error> {
         Ok(Cow::from(
             &[
@@ -119,35 +366,29 @@ impl Handler for Emulator {
         debug!("continuing");
         let req = request.first().unwrap();
         match &req.0 {
-            VCont::Continue => {
+            VCont::Continue => self.run(),
+            VCont::ContinueWithSignal(sig) => {
                 let mut cpu_ref = self.hart.borrow_mut();
-                cpu_ref.tick()?;
+                if self.step(&mut cpu_ref)? {
+                    return Ok(StopReason::Signal(SIGTRAP as u8));
+                }
                 while !self.breakpoints.borrow().contains(&cpu_ref.get_pc()) {
                     if self.trap.load(Ordering::Relaxed) {
                         self.trap.store(false, Ordering::Relaxed);
                         return Ok(StopReason::Signal(SIGTRAP as u8));
                     }
 
-                    cpu_ref.tick()?;
-                }
-                Ok(StopReason::Signal(SIGTRAP as u8))
-            }
-            VCont::ContinueWithSignal(sig) => {
-                let mut cpu_ref = self.hart.borrow_mut();
-                cpu_ref.tick()?;
-                while !self.breakpoints.borrow().contains(&cpu_ref.get_pc()) {
-                    if self.trap.load(Ordering::Relaxed) {
-                        self.trap.store(false, Ordering::Relaxed);
+                    if self.step(&mut cpu_ref)? {
                         return Ok(StopReason::Signal(SIGTRAP as u8));
                     }
-
-                    cpu_ref.tick()?;
                 }
                 Ok(StopReason::Signal(*sig))
             }
             VCont::RangeStep(range) => {
                 let mut cpu_ref = self.hart.borrow_mut();
-                cpu_ref.tick()?;
+                if self.step(&mut cpu_ref)? {
+                    return Ok(StopReason::Signal(SIGTRAP as u8));
+                }
                 while !self.breakpoints.borrow().contains(&cpu_ref.get_pc())
                     && range.contains(&(cpu_ref.get_pc() as u64))
                 {
@@ -156,16 +397,18 @@ impl Handler for Emulator {
                         return Ok(StopReason::Signal(SIGTRAP as u8));
                     }
 
-                    cpu_ref.tick()?;
+                    if self.step(&mut cpu_ref)? {
+                        return Ok(StopReason::Signal(SIGTRAP as u8));
+                    }
                 }
                 Ok(StopReason::Signal(SIGTRAP as u8))
             }
             VCont::Step => {
-                self.hart.borrow_mut().tick()?;
+                self.step(&mut self.hart.borrow_mut())?;
                 Ok(StopReason::Signal(SIGTRAP as u8))
             }
             VCont::StepWithSignal(sig) => {
-                self.hart.borrow_mut().tick()?;
+                self.step(&mut self.hart.borrow_mut())?;
                 Ok(StopReason::Signal(*sig))
             }
             VCont::Stop => Ok(StopReason::Signal(SIGSTOP as u8)),
@@ -176,13 +419,277 @@ impl Handler for Emulator {
 impl From<Fault> for gdb_remote_protocol::Error {
     fn from(value: Fault) -> Self {
         match value {
-            Fault::MemoryFault(_) => Error::Error(0),
+            Fault::FetchFault(_) | Fault::MemoryFault(_) | Fault::StoreFault(_) => Error::Error(0),
             Fault::Unmapped(_) => Error::Error(1),
-            Fault::Unaligned(_) => Error::Error(2),
-            Fault::Halt => Error::Error(3),
+            Fault::Unaligned(_) | Fault::MisalignedLoad(_) | Fault::MisalignedStore(_) => {
+                Error::Error(2)
+            }
+            Fault::Halt | Fault::Breakpoint => Error::Error(3),
             Fault::Unimplemented => Error::Unimplemented,
             Fault::InstructionDecodingError => Error::Error(4),
             Fault::IllegalOpcode(_) => Error::Error(5),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use gdb_remote_protocol::Signal::SIGTRAP;
+    use gdb_remote_protocol::{Breakpoint, Handler, MemoryRegion, StopReason, VCont, Watchpoint};
+
+    use crate::csr;
+    use crate::device::Device;
+    use crate::dynbus::DynBus;
+    use crate::gdb::emu::{DetachPolicy, Emulator, ExecutionMode, FIRST_CSR_REGNUM};
+    use crate::hart::Hart;
+    use crate::machine_builder::MachineBuilder;
+    use crate::plic::Fault;
+    use crate::ram::Ram;
+
+    #[test]
+    fn memory_round_trip() {
+        let (bus, hart) = MachineBuilder::new().ram(0x1000).build().expect("build");
+        let emulator = Emulator::new(hart);
+
+        emulator
+            .write_memory(0x80000ffe, &[1, 2])
+            .expect("write memory");
+        assert_eq!(bus.read_half(0x80000ffe).expect("ram"), 0x0201);
+
+        let region = MemoryRegion {
+            address: 0x80000ffd,
+            length: 3,
+        };
+        let data = emulator.read_memory(region).expect("read memory");
+        assert_eq!(data, [0, 1, 2]);
+
+        assert!(emulator.write_memory(0x80001000, &[3]).is_err());
+    }
+
+    #[test]
+    fn csr_registers() {
+        let (_bus, mut hart) = MachineBuilder::new().ram(0x1000).build().expect("build");
+        hart.set_csr(csr::MSTATUS, csr::MSTATUS_MIE);
+        let emulator = Emulator::new(hart);
+
+        let mstatus = FIRST_CSR_REGNUM + csr::MSTATUS as u64;
+        let val = emulator.read_register(mstatus).expect("mstatus");
+        assert_eq!(val, csr::MSTATUS_MIE.to_le_bytes());
+
+        let mepc = FIRST_CSR_REGNUM + csr::MEPC as u64;
+        emulator
+            .write_register(mepc, &0x80000100u64.to_le_bytes())
+            .expect("mepc");
+        assert_eq!(emulator.hart.borrow().get_csr(csr::MEPC), 0x80000100);
+    }
+
+    #[test]
+    fn single_step() {
+        let (_bus, hart) = MachineBuilder::new()
+            .ram(0x1000)
+            .load_bin(&[
+                0x05, 0x45, // c.li	a0,1
+                0x93, 0x05, 0xa0, 0x02, // li	a1,42
+            ])
+            .build()
+            .expect("build");
+        let emulator = Emulator::new(hart);
+
+        let stop = emulator.vcont(vec![(VCont::Step, None)]);
+        assert!(matches!(stop, Ok(StopReason::Signal(sig)) if sig == SIGTRAP as u8));
+        assert_eq!(emulator.hart.borrow().get_pc(), 0x80000002);
+        assert_eq!(emulator.hart.borrow().get_register(10), 1);
+        assert_eq!(emulator.hart.borrow().get_register(11), 0);
+
+        let stop = emulator.vcont(vec![(VCont::Step, None)]);
+        assert!(matches!(stop, Ok(StopReason::Signal(sig)) if sig == SIGTRAP as u8));
+        assert_eq!(emulator.hart.borrow().get_pc(), 0x80000006);
+        assert_eq!(emulator.hart.borrow().get_register(11), 42);
+    }
+
+    #[test]
+    fn write_watchpoint() {
+        let (bus, hart) = MachineBuilder::new()
+            .ram(0x1000)
+            .load_bin(&[
+                0x17, 0x05, 0x00, 0x00, // auipc	a0,0x0
+                0x93, 0x05, 0xa0, 0x02, // li	a1,42
+                0x23, 0x20, 0xb5, 0x10, // sw	a1,256(a0)
+                0x6f, 0x00, 0x00, 0x00, // j	.
+            ])
+            .build()
+            .expect("build");
+        let emulator = Emulator::new(hart);
+
+        emulator
+            .insert_read_watchpoint(Watchpoint::new(0x80000100, 4))
+            .expect("watch");
+        emulator
+            .insert_write_watchpoint(Watchpoint::new(0x80000102, 1))
+            .expect("watch");
+
+        let stop = emulator.vcont(vec![(VCont::Continue, None)]);
+        assert!(matches!(stop, Ok(StopReason::Signal(sig)) if sig == SIGTRAP as u8));
+        // stopped right after the store
+        assert_eq!(emulator.hart.borrow().get_pc(), 0x8000000c);
+        assert_eq!(bus.read_word(0x80000100).expect("ram"), 42);
+    }
+
+    #[test]
+    fn ebreak_stops() {
+        let (_bus, hart) = MachineBuilder::new()
+            .ram(0x1000)
+            .load_bin(&[
+                0x73, 0x00, 0x10, 0x00, // ebreak
+                0x13, 0x05, 0x10, 0x00, // li	a0,1
+                0x6f, 0x00, 0x00, 0x00, // j	.
+            ])
+            .build()
+            .expect("build");
+        let emulator = Emulator::new(hart);
+
+        let stop = emulator.vcont(vec![(VCont::Continue, None)]);
+        assert!(matches!(stop, Ok(StopReason::Signal(sig)) if sig == SIGTRAP as u8));
+        assert_eq!(emulator.hart.borrow().get_pc(), 0x80000004);
+
+        let stop = emulator.vcont(vec![(VCont::Step, None)]);
+        assert!(matches!(stop, Ok(StopReason::Signal(sig)) if sig == SIGTRAP as u8));
+        assert_eq!(emulator.hart.borrow().get_register(10), 1);
+    }
+
+    #[test]
+    fn free_running() {
+        let program = [
+            0x13, 0x05, 0x10, 0x00, // li	a0,1
+            0x93, 0x05, 0xa0, 0x02, // li	a1,42
+            0x6f, 0x00, 0x00, 0x00, // j	.
+        ];
+        let (_bus, hart) = MachineBuilder::new()
+            .ram(0x1000)
+            .load_bin(&program)
+            .build()
+            .expect("build");
+        let paused = Emulator::new(hart);
+        assert!(paused.halt_reason().is_ok());
+        assert_eq!(paused.hart.borrow().get_pc(), 0x80000000);
+
+        let (_bus, hart) = MachineBuilder::new()
+            .ram(0x1000)
+            .load_bin(&program)
+            .build()
+            .expect("build");
+        let emulator = Emulator::with_mode(hart, ExecutionMode::Running);
+        emulator
+            .insert_software_breakpoint(Breakpoint::new(0x80000008, 4, None, None))
+            .expect("breakpoint");
+
+        // runs to the breakpoint without being continued
+        let stop = emulator.halt_reason();
+        assert!(matches!(stop, Ok(StopReason::Signal(sig)) if sig == SIGTRAP as u8));
+        assert_eq!(emulator.hart.borrow().get_pc(), 0x80000008);
+        assert_eq!(emulator.hart.borrow().get_register(11), 42);
+
+        // only once, afterwards gdb is in control
+        assert!(emulator.halt_reason().is_ok());
+        assert_eq!(emulator.hart.borrow().get_pc(), 0x80000008);
+    }
+
+    #[test]
+    fn resume_after_detach() {
+        let program = [
+            0x13, 0x05, 0x10, 0x00, // li	a0,1
+            0x93, 0x05, 0xa0, 0x02, // li	a1,42
+            0x93, 0x08, 0x80, 0x00, // li	a7,8
+            0x73, 0x00, 0x00, 0x00, // ecall (legacy shutdown)
+        ];
+        let (_bus, hart) = MachineBuilder::new()
+            .ram(0x1000)
+            .load_bin(&program)
+            .build()
+            .expect("build");
+        let emulator = Emulator::new(hart);
+
+        let session = emulator.clone();
+        session
+            .insert_software_breakpoint(Breakpoint::new(0x80000004, 4, None, None))
+            .expect("breakpoint");
+        session.vcont(vec![(VCont::Continue, None)]).expect("stop");
+        assert_eq!(emulator.hart.borrow().get_pc(), 0x80000004);
+        // the connection drops
+        drop(session);
+
+        assert!(matches!(emulator.resume(), Err(Fault::Halt)));
+        assert_eq!(emulator.hart.borrow().get_register(11), 42);
+
+        let (_bus, hart) = MachineBuilder::new()
+            .ram(0x1000)
+            .load_bin(&program)
+            .build()
+            .expect("build");
+        let mut emulator = Emulator::new(hart);
+        emulator.set_detach_policy(DetachPolicy::Halt);
+        emulator.detach(None).expect("detach");
+        assert!(emulator.resume().is_ok());
+        assert_eq!(emulator.hart.borrow().get_pc(), 0x80000000);
+    }
+
+    #[test]
+    fn memory_map() {
+        let (_bus, hart) = MachineBuilder::new()
+            .ram(0x1000)
+            .uart(0x10000000)
+            .load_flat(&[0x6f, 0x00, 0x00, 0x00], 0x1000, 0x1000)
+            .build()
+            .expect("build");
+        let emulator = Emulator::new(hart);
+
+        let xml = emulator.memory_map();
+        assert!(xml.starts_with("<?xml version=\"1.0\"?>\n<!DOCTYPE memory-map"));
+        let regions: Vec<&str> = xml.lines().filter(|l| l.contains("<memory ")).collect();
+        assert_eq!(
+            regions,
+            [
+                r#"  <memory type="rom" start="0x1000" length="0x4"/>"#,
+                r#"  <memory type="ram" start="0x10000000" length="0x10"/>"#,
+                r#"  <memory type="ram" start="0x80000000" length="0x1000"/>"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn memory_map_read_only() {
+        let ram = Ram::sized(0x1000);
+        ram.set_readonly(true);
+        let mut bus = DynBus::new();
+        bus.map_named("memory", ram, 0x80000000..0x80001000);
+        let emulator = Emulator::new(Hart::new(0, 0x80000000, Arc::new(bus)));
+
+        assert!(emulator
+            .memory_map()
+            .contains(r#"<memory type="rom" start="0x80000000" length="0x1000"/>"#));
+    }
+
+    #[test]
+    fn general_registers_round_trip() {
+        let (_bus, hart) = MachineBuilder::new().ram(0x1000).build().expect("build");
+        let emulator = Emulator::new(hart);
+
+        let mut contents = vec![];
+        for i in 0..32u64 {
+            contents.extend_from_slice(&(0x100 + i).to_le_bytes());
+        }
+        contents.extend_from_slice(&0x80000010u64.to_le_bytes());
+        emulator
+            .write_general_registers(&contents)
+            .expect("write registers");
+
+        let read = emulator.read_general_registers().expect("read registers");
+        assert_eq!(read[..8], [0; 8], "x0 is hardwired");
+        assert_eq!(read[8..], contents[8..]);
+        assert_eq!(emulator.hart.borrow().get_pc(), 0x80000010);
+
+        assert!(emulator.write_general_registers(&contents[..8]).is_err());
+    }
+}
@@ -1,37 +1,201 @@
 use std::cmp;
+use std::env;
 use std::sync::Arc;
 
 use log::{debug, trace};
 
+use crate::clint;
+use crate::console::{Console, Stdio};
 use crate::csr;
 use crate::csr::Csr;
 use crate::device::Device;
+use crate::disasm;
+use crate::hsm::{Hsm, Poll};
 use crate::ins::InstructionFormat::{B, I, J, R, S, U};
 use crate::ins::{Instruction, InstructionFormat};
-use crate::plic::Fault;
 use crate::plic::Fault::{Halt, IllegalOpcode};
-use crate::reg::reg;
+use crate::plic::{Fault, Plic};
+use crate::pmp;
+use crate::reg::treg;
+use crate::rtc::{self, TickCounter};
 use crate::see;
+use crate::symbols::Symbols;
+
+// The kind of data access an instruction performs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    // Atomic read-modify-write
+    ReadWrite,
+}
+
+// A data access an instruction performs on the bus
+#[derive(Debug, PartialEq)]
+pub struct MemoryAccess {
+    pub addr: usize,
+    pub width: usize,
+    pub kind: AccessKind,
+}
+
+// Why `Hart::run` stopped
+#[derive(Debug)]
+pub enum Exit {
+    // The hart faulted, or halted e.g. through an SBI shutdown
+    Fault(Fault),
+    // The instruction budget was used up
+    Budget,
+}
+
+#[derive(Debug)]
+pub struct RunSummary {
+    // As counted by `minstret`
+    pub instructions_retired: u64,
+    pub exit: Exit,
+}
+
+// Instruction budget for the runners, from the environment variable `MAX_INSTRUCTIONS`
+pub fn max_instructions() -> Option<usize> {
+    env::var("MAX_INSTRUCTIONS").ok()?.parse().ok()
+}
+
+// Width of the integer registers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Xlen {
+    Rv32,
+    Rv64,
+}
+
+impl Xlen {
+    // Truncates `val` to the register width, as used for addresses and CSR values
+    fn truncate(self, val: u64) -> u64 {
+        match self {
+            Xlen::Rv32 => val & 0xFFFF_FFFF,
+            Xlen::Rv64 => val,
+        }
+    }
+}
+
+// Cycles added to `mcycle` per executed instruction, by class of instruction
+#[derive(Debug, Clone, Copy)]
+pub struct CycleCosts {
+    pub alu: u64,
+    pub branch: u64,
+    pub load: u64,
+    pub store: u64,
+    // Atomic memory operations, including lr/sc
+    pub atomic: u64,
+    pub mul: u64,
+    pub div: u64,
+}
+
+impl CycleCosts {
+    fn cost(&self, instruction: &InstructionFormat) -> u64 {
+        let (opcode, funct3, funct7) = match *instruction {
+            R {
+                opcode,
+                funct3,
+                funct7,
+                ..
+            } => (opcode, funct3, funct7),
+            I { opcode, .. } | S { opcode, .. } | B { opcode, .. } => (opcode, 0, 0),
+            U { opcode, .. } | J { opcode, .. } => (opcode, 0, 0),
+        };
+        match (opcode, funct7) {
+            (0b0000011 | 0b0000111, _) => self.load,
+            (0b0100011 | 0b0100111, _) => self.store,
+            (0b0101111, _) => self.atomic,
+            (0b0110011 | 0b0111011, 0b0000001) if funct3 < 0x4 => self.mul,
+            (0b0110011 | 0b0111011, 0b0000001) => self.div,
+            (0b1100011 | 0b1101111 | 0b1100111, _) => self.branch,
+            _ => self.alu,
+        }
+    }
+}
+
+// Rough relative latencies, based on three cycles for simple instructions
+impl Default for CycleCosts {
+    fn default() -> Self {
+        Self {
+            alu: 3,
+            branch: 3,
+            load: 6,
+            store: 6,
+            atomic: 9,
+            mul: 9,
+            div: 30,
+        }
+    }
+}
+
+// The architectural state of a hart, see `Hart::snapshot`
+#[derive(Clone)]
+pub struct HartState {
+    registers: [u64; 32],
+    f: [u64; 32],
+    pc: usize,
+    csr: Csr,
+    reservation: Option<(usize, u64)>,
+}
+
+// Number of decoded instructions cached per hart
+const ICACHE_SIZE: usize = 4096;
 
 pub struct Hart<BT: Device> {
     start_pc: usize,
 
     pub(crate) bus: Arc<BT>,
+    xlen: Xlen,
     registers: [u64; 32],
+    f: [u64; 32],
+    strict_alignment: bool,
+    // Whether ecall is serviced by the emulator's SBI, instead of trapping to the guest
+    emulated_sbi: bool,
     pc: usize,
     csr: Csr,
+    // Address and value loaded by the last LR, which a matching SC consumes
+    reservation: Option<(usize, u64)>,
+    pub(crate) hsm: Option<Arc<Hsm>>,
+    // The interrupt controller driving the external interrupt pin
+    plic: Option<Arc<Plic>>,
+    // The console of the emulated SBI
+    pub(crate) console: Arc<dyn Console>,
+    clock: Option<Arc<TickCounter>>,
+    symbols: Option<Arc<Symbols>>,
+    costs: CycleCosts,
+    // Decoded instructions, direct-mapped by address and tagged with their raw bits
+    icache: Vec<Option<(usize, Instruction, InstructionFormat)>>,
+    icache_misses: u64,
 
     stop: bool,
 }
 
 impl<BT: Device> Hart<BT> {
     pub fn new(id: u64, pc: usize, bus: Arc<BT>) -> Self {
+        Hart::with_xlen(id, pc, bus, Xlen::Rv64)
+    }
+
+    // A hart with 32-bit registers executes RV32, the word instructions of RV64 are illegal
+    pub fn with_xlen(id: u64, pc: usize, bus: Arc<BT>, xlen: Xlen) -> Self {
         let mut m = Hart {
             start_pc: pc,
             bus,
+            xlen,
             registers: [0; 32],
+            f: [0; 32],
+            strict_alignment: false,
+            emulated_sbi: true,
             pc,
             csr: Csr::new(id),
+            reservation: None,
+            hsm: None,
+            plic: None,
+            console: Arc::new(Stdio),
+            clock: None,
+            symbols: None,
+            costs: CycleCosts::default(),
+            icache: vec![None; ICACHE_SIZE],
+            icache_misses: 0,
             stop: false,
         };
 
@@ -43,36 +207,189 @@ impl<BT: Device> Hart<BT> {
     pub fn reset(&mut self) {
         self.pc = self.start_pc;
         self.registers = [0; 32];
+        self.f = [0; 32];
+        self.csr = Csr::new(self.csr.read(csr::MHARTID));
+        self.csr.set_xlen(self.xlen);
+        self.reservation = None;
+        self.flush_icache();
+    }
+
+    pub fn flush_icache(&mut self) {
+        self.icache.fill(None);
+    }
+
+    // How often an instruction had to be decoded, as it was not cached
+    pub fn icache_misses(&self) -> u64 {
+        self.icache_misses
     }
 
     pub fn stop(&mut self) {
         self.stop = true;
     }
 
+    // Captures the state to resume from with `restore`, memory is saved separately
+    pub fn snapshot(&self) -> HartState {
+        HartState {
+            registers: self.registers,
+            f: self.f,
+            pc: self.pc,
+            csr: self.csr.clone(),
+            reservation: self.reservation,
+        }
+    }
+
+    pub fn restore(&mut self, state: HartState) {
+        self.registers = state.registers;
+        self.f = state.f;
+        self.pc = state.pc;
+        self.csr = state.csr;
+        self.reservation = state.reservation;
+    }
+
+    // Lets the harts of a machine start and stop each other through SBI HSM calls
+    pub fn set_hsm(&mut self, hsm: Arc<Hsm>) {
+        self.hsm = Some(hsm);
+    }
+
+    // Latches the external interrupt of the hart's M-mode context on `plic` into mip.MEIP
+    pub fn set_plic(&mut self, plic: Arc<Plic>) {
+        self.plic = Some(plic);
+    }
+
+    // Services the SBI console calls with `console` instead of stdin and stdout
+    pub fn set_console(&mut self, console: Arc<dyn Console>) {
+        self.console = console;
+    }
+
+    // Drives a deterministic clock with the instructions retired by this hart
+    pub fn set_clock(&mut self, clock: Arc<TickCounter>) {
+        self.clock = Some(clock);
+    }
+
+    pub fn set_cycle_costs(&mut self, costs: CycleCosts) {
+        self.costs = costs;
+    }
+
+    // Annotates traced instructions with the symbol they are part of
+    pub fn set_symbols(&mut self, symbols: Arc<Symbols>) {
+        self.symbols = Some(symbols);
+    }
+
     pub fn tick(&mut self) -> Result<(), Fault> {
         if self.stop {
             return Err(Halt);
         }
 
-        let res = self
-            .fetch_instruction()
-            .and_then(|instruction| instruction.decode())
-            .and_then(|(ins, decoded)| self.execute_instruction(decoded, ins));
+        if let Some(hsm) = &self.hsm {
+            let hartid = self.csr.read(csr::MHARTID);
+            match hsm.poll(hartid as usize) {
+                Poll::Run => {}
+                Poll::Idle => return Ok(()),
+                Poll::Start(addr, opaque) => {
+                    self.pc = addr;
+                    self.set_register(treg("a0"), hartid);
+                    self.set_register(treg("a1"), opaque);
+                    return Ok(());
+                }
+            }
+        }
+
+        if let Some(irq) = self.pending_interrupt() {
+            if self.csr.read(csr::MSTATUS) & csr::MSTATUS_MIE != 0 {
+                self.trap(csr::MCAUSE_INTERRUPT | irq, 0);
+                return Ok(());
+            }
+        }
+
+        let pc = self.pc;
+        let (res, cost) = match self.fetch_decoded() {
+            Ok((ins, decoded)) => {
+                let pc = self.pc - ins.size();
+                self.dbgins(ins, || disasm::format(ins, &decoded, pc));
+                (
+                    self.execute_instruction(decoded, ins),
+                    self.costs.cost(&decoded),
+                )
+            }
+            Err(Fault::MemoryFault(addr) | Fault::Unmapped(addr)) => {
+                (Err(Fault::FetchFault(addr)), self.costs.load)
+            }
+            Err(err) => (Err(err), self.costs.load),
+        };
 
         // simulate passing of time
-        self.csr.write(csr::MCYCLE, self.csr.read(csr::MCYCLE) + 3);
+        self.csr
+            .write(csr::MCYCLE, self.csr.read(csr::MCYCLE) + cost);
 
         match res {
             Ok(_) => Ok(()),
-            Err(Fault::MemoryFault(0)) => Ok(()), // Ignore zero-reads/writes
+            Err(Fault::MemoryFault(0)) => Ok(()), // Ignore zero-reads
             Err(err) => {
                 debug!("hart fault: {:?}", err);
-                Err(err)
+                if let Fault::Unmapped(addr) | Fault::StoreFault(addr) = err {
+                    if let Some(description) = self.bus.describe(addr) {
+                        debug!("bus fault at {}", description);
+                    }
+                }
+                // Exceptions are taken by the guest at the faulting instruction, ebreak is left
+                // to the debugger
+                match err.cause_code() {
+                    Some(cause) if !matches!(err, Fault::Breakpoint) => {
+                        self.pc = pc;
+                        self.trap(cause, err.trap_value());
+                        Ok(())
+                    }
+                    _ => Err(err),
+                }
             }
         }
     }
 
+    // Ticks the hart and the devices on its bus until it faults or halts, or `budget` ticks were
+    // executed
+    pub fn run(&mut self, budget: Option<usize>) -> RunSummary {
+        let minstret = self.csr.read(csr::MINSTRET);
+        let mut ticks = 0;
+        let exit = loop {
+            if budget.is_some_and(|budget| ticks >= budget) {
+                break Exit::Budget;
+            }
+            let res = self.tick();
+            self.bus.tick();
+            if let Err(fault) = res {
+                break Exit::Fault(fault);
+            }
+            ticks += 1;
+        };
+
+        RunSummary {
+            instructions_retired: self.csr.read(csr::MINSTRET).wrapping_sub(minstret),
+            exit,
+        }
+    }
+
+    // Ticks until `pred` holds, returning the number of instructions executed.  Stops after
+    // `max_steps`, so check the predicate again if that many were executed.
+    pub fn run_until<F: FnMut(&Hart<BT>) -> bool>(
+        &mut self,
+        max_steps: usize,
+        mut pred: F,
+    ) -> Result<usize, Fault> {
+        for step in 0..max_steps {
+            if pred(self) {
+                return Ok(step);
+            }
+            self.tick()?;
+        }
+        Ok(max_steps)
+    }
+
+    // Under RV32 the value is kept sign extended, like the results of word instructions
     pub fn set_register(&mut self, reg: u8, val: u64) {
+        let val = match self.xlen {
+            Xlen::Rv32 => (val as u32).sext(),
+            Xlen::Rv64 => val,
+        };
         match reg {
             0 => {}
             1..=31 => self.registers[reg as usize] = val,
@@ -87,21 +404,281 @@ impl<BT: Device> Hart<BT> {
         }
     }
 
+    // All integer registers, indexed by register number
+    pub fn get_registers(&self) -> [u64; 32] {
+        self.registers
+    }
+
+    // Sets all integer registers, the write to x0 is ignored like it is for instructions
+    pub fn set_registers(&mut self, registers: [u64; 32]) {
+        self.registers = registers;
+        self.registers[0] = 0;
+    }
+
+    // Fault on loads and stores which are not naturally aligned, like hardware without
+    // misaligned access support
+    pub fn set_strict_alignment(&mut self, strict: bool) {
+        self.strict_alignment = strict;
+    }
+
+    // Guests bringing their own M-mode firmware service environment calls themselves
+    pub fn set_emulated_sbi(&mut self, emulated: bool) {
+        self.emulated_sbi = emulated;
+    }
+
+    // The target of a pc-relative branch or jump, relative to the executing instruction
+    fn relative(&self, ins: Instruction, imm: i64) -> usize {
+        let pc = (self.pc - ins.size()) as u64;
+        self.address(pc, imm)
+    }
+
+    // The effective address of `base` offset by `offset`, wrapping at the register width
+    fn address(&self, base: u64, offset: i64) -> usize {
+        self.xlen.truncate(base.wrapping_add_signed(offset)) as usize
+    }
+
+    fn check_alignment(
+        &self,
+        addr: usize,
+        width: usize,
+        fault: fn(usize) -> Fault,
+    ) -> Result<(), Fault> {
+        if self.strict_alignment && !addr.is_multiple_of(width) {
+            return Err(fault(addr));
+        }
+        Ok(())
+    }
+
+    // Denied loads raise a load access fault, denied stores and AMOs a store access fault.
+    // The hart only runs in M-mode, so accesses are always checked with its privilege.
+    fn check_pmp(&self, addr: usize, width: usize, kind: AccessKind) -> Result<(), Fault> {
+        if pmp::allows(&self.csr, addr, width, kind, true) {
+            return Ok(());
+        }
+        match kind {
+            AccessKind::Read => Err(Fault::MemoryFault(addr)),
+            AccessKind::Write | AccessKind::ReadWrite => Err(Fault::StoreFault(addr)),
+        }
+    }
+
+    pub fn set_fregister(&mut self, reg: u8, val: u64) {
+        match reg {
+            0..=31 => self.f[reg as usize] = val,
+            _ => panic!(),
+        }
+    }
+
+    pub fn get_fregister(&self, reg: u8) -> u64 {
+        match reg {
+            0..=31 => self.f[reg as usize],
+            _ => panic!(),
+        }
+    }
+
     pub fn set_csr(&mut self, csr: usize, val: u64) {
         self.csr.write(csr, val);
     }
 
+    // Enters the machine mode trap handler, `cause` as it will appear in `mcause`
+    fn trap(&mut self, cause: u64, tval: u64) {
+        let mstatus = self.csr.read(csr::MSTATUS);
+        let mpie = match mstatus & csr::MSTATUS_MIE {
+            0 => 0,
+            _ => csr::MSTATUS_MPIE,
+        };
+        let mstatus = (mstatus & !(csr::MSTATUS_MIE | csr::MSTATUS_MPIE)) | mpie | csr::MSTATUS_MPP;
+        self.csr.write(csr::MSTATUS, mstatus);
+
+        self.csr.write(csr::MEPC, self.pc as u64);
+        self.csr.write(csr::MCAUSE, cause);
+        self.csr.write(csr::MTVAL, tval);
+        debug!(
+            "trap M -> M: cause {:#x}, epc {:#x}, tval {:#x}",
+            cause, self.pc, tval
+        );
+
+        // Vectored mode only applies to interrupts
+        let mtvec = self.csr.read(csr::MTVEC);
+        let base = (mtvec & !0b11) as usize;
+        self.pc = match (mtvec & 0b11, cause & csr::MCAUSE_INTERRUPT) {
+            (1, csr::MCAUSE_INTERRUPT) => base + 4 * (cause & !csr::MCAUSE_INTERRUPT) as usize,
+            _ => base,
+        };
+    }
+
+    // The `time` CSR shadows the memory mapped mtime, when there is one on the bus
+    fn read_csr(&mut self, csr: usize) -> u64 {
+        match csr {
+            csr::TIME => self
+                .bus
+                .read_double(rtc::MTIME_ADDR)
+                .unwrap_or_else(|_| self.csr.read(csr)),
+            // Up to date even while no interrupt is enabled and the sources are not polled
+            csr::MIP => {
+                self.latch_interrupts();
+                self.csr.read(csr)
+            }
+            _ => self.csr.read(csr),
+        }
+    }
+
+    pub fn get_csr(&self, csr: usize) -> u64 {
+        self.csr.read(csr)
+    }
+
+    pub fn get_csr_by_name(&self, name: &str) -> Option<u64> {
+        Csr::number(name).map(|csr| self.csr.read(csr))
+    }
+
+    // Returns the highest priority interrupt which is pending and enabled.  This runs before
+    // every instruction, so the devices are only polled when any interrupt is enabled.
+    pub fn pending_interrupt(&mut self) -> Option<u64> {
+        if self.csr.read(csr::MIE) == 0 {
+            return None;
+        }
+        self.latch_interrupts();
+
+        clint::interrupt(self)
+    }
+
+    // Latches the state of the interrupt sources on the bus into `mip`
+    fn latch_interrupts(&mut self) {
+        let hartid = self.csr.read(csr::MHARTID);
+        let mtime = self.bus.read_double(rtc::MTIME_ADDR).ok();
+        let stimer = match self.csr.read(csr::MENVCFG) & csr::MENVCFG_STCE {
+            0 => None,
+            _ => mtime.map(|mtime| mtime >= self.csr.read(csr::STIMECMP)),
+        };
+        let sources = [
+            (
+                clint::MTIP,
+                mtime.and_then(|mtime| clint::timer_pending(&*self.bus, mtime)),
+            ),
+            (clint::STIP, stimer),
+            (clint::MSIP, clint::software_pending(&*self.bus, hartid)),
+            (
+                clint::MEIP,
+                self.plic
+                    .as_ref()
+                    .map(|plic| plic.interrupt_pending(2 * hartid as usize)),
+            ),
+        ];
+        let mut mip = self.csr.read(csr::MIP);
+        for (irq, pending) in sources {
+            match pending {
+                Some(true) => mip |= 1 << irq,
+                Some(false) => mip &= !(1 << irq),
+                None => {}
+            }
+        }
+        self.csr.write(csr::MIP, mip);
+    }
+
+    // Raises interrupt `irq` as pending, as a device without a memory mapped source would.  The
+    // timer and software interrupts are overridden by the CLINT and RTC when they are mapped.
+    pub fn inject_interrupt(&mut self, irq: u64) {
+        self.csr.set_mip_bit(irq);
+    }
+
+    pub fn clear_interrupt(&mut self, irq: u64) {
+        self.csr.clear_mip_bit(irq);
+    }
+
+    pub fn interrupt_enabled(&self, irq: u64) -> bool {
+        self.csr.mie_bit(irq)
+    }
+
+    // The address of the next instruction to execute
     pub fn get_pc(&self) -> usize {
         self.pc
     }
 
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    // The data access the instruction at pc is about to perform, without executing it
+    pub fn next_access(&self) -> Option<MemoryAccess> {
+        let ins = self.bus.read_word(self.pc).ok()?;
+        let ins = match ins & 0b11 {
+            0b11 => Instruction::IRV32(ins),
+            _ => Instruction::CRV32(self.bus.read_half(self.pc).ok()?),
+        };
+        let (_, decoded) = self.decode(ins).ok()?;
+
+        let offset = |rs1: u8, imm: i16| self.address(self.get_register(rs1), imm as i64);
+        let (addr, width, kind) = match decoded {
+            // integer and floating point loads
+            I {
+                opcode: 0b0000011 | 0b0000111,
+                funct3,
+                rs1,
+                imm,
+                ..
+            } => (offset(rs1, imm), 1 << (funct3 & 0b11), AccessKind::Read),
+            // integer and floating point stores
+            S {
+                opcode: 0b0100011 | 0b0100111,
+                funct3,
+                rs1,
+                imm,
+                ..
+            } => (offset(rs1, imm), 1 << (funct3 & 0b11), AccessKind::Write),
+            R {
+                opcode: 0b0101111,
+                funct3,
+                rs1,
+                funct7,
+                ..
+            } => {
+                let kind = match funct7 >> 2 {
+                    0x02 => AccessKind::Read,
+                    0x03 => AccessKind::Write,
+                    _ => AccessKind::ReadWrite,
+                };
+                (offset(rs1, 0), 1 << funct3, kind)
+            }
+            _ => return None,
+        };
+
+        Some(MemoryAccess { addr, width, kind })
+    }
+
+    // As the raw instruction is always fetched, the cache is coherent with memory and only saves
+    // decoding
+    fn fetch_decoded(&mut self) -> Result<(Instruction, InstructionFormat), Fault> {
+        let pc = self.pc;
+        let ins = self.fetch_instruction()?;
+
+        let slot = (pc >> 1) % ICACHE_SIZE;
+        if let Some((tag, cached, decoded)) = self.icache[slot] {
+            if tag == pc && cached == ins {
+                return Ok((ins, decoded));
+            }
+        }
+
+        self.icache_misses += 1;
+        let (ins, decoded) = self.decode(ins)?;
+        self.icache[slot] = Some((pc, ins, decoded));
+        Ok((ins, decoded))
+    }
+
+    fn decode(&self, ins: Instruction) -> Result<(Instruction, InstructionFormat), Fault> {
+        match self.xlen {
+            Xlen::Rv32 => ins.decode_rv32(),
+            Xlen::Rv64 => ins.decode(),
+        }
+    }
+
     fn fetch_instruction(&mut self) -> Result<Instruction, Fault> {
         // Assuming little-endian, the first byte contains the opcode
         let ins = self.bus.read_word(self.pc)?;
-        match ins & 0b11 {
+        match ins & 0b11111 {
+            // 48-bit, 64-bit and longer instructions, none of which are defined
+            0b11111 => Err(IllegalOpcode(Instruction::IRV32(ins))),
             // 32-bit instruction
-            0b11 => {
-                debug!(
+            _ if ins & 0b11 == 0b11 => {
+                trace!(
                     "[{}] [{:#x}] {:07b} Opcode for ins {:08x} {:032b}",
                     self.csr.read(csr::MHARTID),
                     self.pc,
@@ -115,7 +692,7 @@ impl<BT: Device> Hart<BT> {
             // 16-bit compressed instruction
             _ => {
                 let ins = self.bus.read_half(self.pc)?;
-                debug!(
+                trace!(
                     "[{}] [{:#x}] {:02b} Opcode for ins {:04x} {:016b}",
                     self.csr.read(csr::MHARTID),
                     self.pc,
@@ -177,6 +754,11 @@ impl<BT: Device> Hart<BT> {
         instruction: InstructionFormat,
         ins: Instruction,
     ) -> Result<(), Fault> {
+        let instruction = match self.xlen {
+            Xlen::Rv32 => Hart::<BT>::narrow(instruction).ok_or(IllegalOpcode(ins))?,
+            Xlen::Rv64 => instruction,
+        };
+
         match instruction {
             // RV32I
 
@@ -191,8 +773,6 @@ impl<BT: Device> Hart<BT> {
             } => {
                 let val = self.get_register(rs1).wrapping_add(self.get_register(rs2));
                 self.set_register(rd, val);
-
-                self.dbgins(ins, format!("add\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // addw ADD
             R {
@@ -206,8 +786,6 @@ impl<BT: Device> Hart<BT> {
                 let val = ((self.get_register(rs1) & 0xFFFFFFFF) as u32)
                     .wrapping_add((self.get_register(rs2) & 0xFFFFFFFF) as u32);
                 self.set_register(rd, val.sext());
-
-                self.dbgins(ins, format!("addw\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // sub SUB
             R {
@@ -220,8 +798,6 @@ impl<BT: Device> Hart<BT> {
             } => {
                 let val = self.get_register(rs1).wrapping_sub(self.get_register(rs2));
                 self.set_register(rd, val);
-
-                self.dbgins(ins, format!("sub\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // subw SUB
             R {
@@ -235,8 +811,6 @@ impl<BT: Device> Hart<BT> {
                 let val = ((self.get_register(rs1) & 0xFFFFFFFF) as u32)
                     .wrapping_sub((self.get_register(rs2) & 0xFFFFFFFF) as u32);
                 self.set_register(rd, val.sext());
-
-                self.dbgins(ins, format!("subw\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // XOR
             R {
@@ -249,8 +823,6 @@ impl<BT: Device> Hart<BT> {
             } => {
                 let val = self.get_register(rs1) ^ self.get_register(rs2);
                 self.set_register(rd, val);
-
-                self.dbgins(ins, format!("xor\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // OR
             R {
@@ -263,8 +835,6 @@ impl<BT: Device> Hart<BT> {
             } => {
                 let val = self.get_register(rs1) | self.get_register(rs2);
                 self.set_register(rd, val);
-
-                self.dbgins(ins, format!("or\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // AND
             R {
@@ -277,8 +847,6 @@ impl<BT: Device> Hart<BT> {
             } => {
                 let val = self.get_register(rs1) & self.get_register(rs2);
                 self.set_register(rd, val);
-
-                self.dbgins(ins, format!("and\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // sll Shift Left Logical
             R {
@@ -293,8 +861,6 @@ impl<BT: Device> Hart<BT> {
                     .get_register(rs1)
                     .overflowing_shl((self.get_register(rs2) & 0b111111) as u32);
                 self.set_register(rd, val);
-
-                self.dbgins(ins, format!("sll\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // sllw Shift Left Logical
             R {
@@ -308,8 +874,6 @@ impl<BT: Device> Hart<BT> {
                 let (val, _) = ((self.get_register(rs1) & 0xFFFFFFFF) as u32)
                     .overflowing_shl((self.get_register(rs2) & 0b11111) as u32);
                 self.set_register(rd, val.sext());
-
-                self.dbgins(ins, format!("sll\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // srl Shift Left Logical
             R {
@@ -324,8 +888,6 @@ impl<BT: Device> Hart<BT> {
                     .get_register(rs1)
                     .overflowing_shr((self.get_register(rs2) & 0b111111) as u32);
                 self.set_register(rd, val);
-
-                self.dbgins(ins, format!("srl\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // srlw Shift Left Logical
             R {
@@ -339,8 +901,6 @@ impl<BT: Device> Hart<BT> {
                 let (val, _) = ((self.get_register(rs1) & 0xFFFFFFFF) as u32)
                     .overflowing_shr((self.get_register(rs2) & 0b11111) as u32);
                 self.set_register(rd, val.sext());
-
-                self.dbgins(ins, format!("srl\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // sra Shift Right Arith
             R {
@@ -354,8 +914,6 @@ impl<BT: Device> Hart<BT> {
                 let (val, _) = (self.get_register(rs1) as i64)
                     .overflowing_shr((self.get_register(rs2) & 0b111111) as u32);
                 self.set_register(rd, val as u64);
-
-                self.dbgins(ins, format!("sra\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // sraw Shift Right Arith
             R {
@@ -369,8 +927,6 @@ impl<BT: Device> Hart<BT> {
                 let (val, _) = ((self.get_register(rs1) & 0xFFFFFFFF) as i32)
                     .overflowing_shr((self.get_register(rs2) & 0b11111) as u32);
                 self.set_register(rd, val as u64);
-
-                self.dbgins(ins, format!("sra\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // slt Set Less Than
             R {
@@ -387,8 +943,6 @@ impl<BT: Device> Hart<BT> {
                     0
                 };
                 self.set_register(rd, val.sext());
-
-                self.dbgins(ins, format!("slt\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // sltu Set Less Than (U, zero extends)
             R {
@@ -405,8 +959,6 @@ impl<BT: Device> Hart<BT> {
                     0
                 };
                 self.set_register(rd, val as u64);
-
-                self.dbgins(ins, format!("sltu\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
 
             // RV64M
@@ -423,7 +975,19 @@ impl<BT: Device> Hart<BT> {
                     .get_register(rs1)
                     .overflowing_mul(self.get_register(rs2));
                 self.set_register(rd, val);
-                self.dbgins(ins, format!("mul\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
+            }
+            // mulh MUL high signed
+            R {
+                opcode: 0b0110011,
+                rd,
+                funct3: 0b001,
+                rs1,
+                rs2,
+                funct7: 0b1,
+            } => {
+                let a = self.get_register(rs1) as i64 as i128;
+                let b = self.get_register(rs2) as i64 as i128;
+                self.set_register(rd, ((a * b) >> self.bits()) as u64);
             }
             // mulhu MUL high unsigned
             R {
@@ -434,10 +998,9 @@ impl<BT: Device> Hart<BT> {
                 rs2,
                 funct7: 0b1,
             } => {
-                let (val, _) = (self.get_register(rs1) as u128)
-                    .overflowing_mul(self.get_register(rs2) as u128);
-                self.set_register(rd, (val >> 64) as u64);
-                self.dbgins(ins, format!("mul\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
+                let a = self.xlen.truncate(self.get_register(rs1)) as u128;
+                let b = self.xlen.truncate(self.get_register(rs2)) as u128;
+                self.set_register(rd, ((a * b) >> self.bits()) as u64);
             }
             // mulhsu MUL high signed with unsigned
             R {
@@ -448,10 +1011,9 @@ impl<BT: Device> Hart<BT> {
                 rs2,
                 funct7: 0b1,
             } => {
-                let (val, _) = (self.get_register(rs1) as i64 as i128)
-                    .overflowing_mul(self.get_register(rs2) as u128 as i128);
-                self.set_register(rd, (val >> 64) as u64);
-                self.dbgins(ins, format!("mul\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
+                let a = self.get_register(rs1) as i64 as i128;
+                let b = self.xlen.truncate(self.get_register(rs2)) as i128;
+                self.set_register(rd, (a.wrapping_mul(b) >> self.bits()) as u64);
             }
             // mulw MUL word
             R {
@@ -465,7 +1027,6 @@ impl<BT: Device> Hart<BT> {
                 let (val, _) = ((self.get_register(rs1) & 0xFFFFFFFF) as u32)
                     .overflowing_mul((self.get_register(rs2) & 0xFFFFFFFF) as u32);
                 self.set_register(rd, val.sext());
-                self.dbgins(ins, format!("mulw\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // divw DIV word
             R {
@@ -476,15 +1037,15 @@ impl<BT: Device> Hart<BT> {
                 rs2,
                 funct7: 0b1,
             } => {
-                let dividend = ((self.get_register(rs1) & 0xFFFFFFFF) as u32).sext();
-                let divisor = ((self.get_register(rs2) & 0xFFFFFFFF) as u32).sext();
+                let dividend = self.get_register(rs1) as i32;
+                let divisor = self.get_register(rs2) as i32;
+                // Division by zero gives all ones, overflow the dividend
                 let val = if divisor == 0 {
-                    0xFFFFFFFFFFFFFFFF
+                    -1
                 } else {
-                    dividend / divisor
+                    dividend.wrapping_div(divisor)
                 };
-                self.set_register(rd, val);
-                self.dbgins(ins, format!("divw\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
+                self.set_register(rd, (val as u32).sext());
             }
             // div DIV
             R {
@@ -500,10 +1061,9 @@ impl<BT: Device> Hart<BT> {
                 let val = if divisor == 0 {
                     0xFFFFFFFFFFFFFFFFu64 as i64
                 } else {
-                    dividend / divisor
+                    dividend.wrapping_div(divisor)
                 };
                 self.set_register(rd, val as u64);
-                self.dbgins(ins, format!("div\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // divu DIV
             R {
@@ -522,7 +1082,6 @@ impl<BT: Device> Hart<BT> {
                     dividend / divisor
                 };
                 self.set_register(rd, val);
-                self.dbgins(ins, format!("divu\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // divuw DIV word
             R {
@@ -541,7 +1100,6 @@ impl<BT: Device> Hart<BT> {
                     dividend / divisor
                 };
                 self.set_register(rd, val.sext());
-                self.dbgins(ins, format!("divuw\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // rem REM
             R {
@@ -557,10 +1115,9 @@ impl<BT: Device> Hart<BT> {
                 let val = if divisor == 0 {
                     dividend
                 } else {
-                    dividend % divisor
+                    dividend.wrapping_rem(divisor)
                 };
                 self.set_register(rd, val as u64);
-                self.dbgins(ins, format!("rem\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // remu REM unsigned
             R {
@@ -579,7 +1136,6 @@ impl<BT: Device> Hart<BT> {
                     dividend % divisor
                 };
                 self.set_register(rd, val);
-                self.dbgins(ins, format!("remu\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
             // remw REM word
             R {
@@ -590,15 +1146,15 @@ impl<BT: Device> Hart<BT> {
                 rs2,
                 funct7: 0b1,
             } => {
-                let dividend = (self.get_register(rs1) & 0xFFFFFFFF) as u32;
-                let divisor = (self.get_register(rs2) & 0xFFFFFFFF) as u32;
+                let dividend = self.get_register(rs1) as i32;
+                let divisor = self.get_register(rs2) as i32;
+                // Division by zero gives the dividend, overflow zero
                 let val = if divisor == 0 {
                     dividend
                 } else {
-                    dividend % divisor
+                    dividend.wrapping_rem(divisor)
                 };
-                self.set_register(rd, val.sext());
-                self.dbgins(ins, format!("remw\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
+                self.set_register(rd, (val as u32).sext());
             }
             // remuw REM unsigned word
             R {
@@ -609,15 +1165,14 @@ impl<BT: Device> Hart<BT> {
                 rs2,
                 funct7: 0b1,
             } => {
-                let dividend = (self.get_register(rs1) & 0xFFFFFFFF) as u32 as i32;
-                let divisor = (self.get_register(rs2) & 0xFFFFFFFF) as u32 as i32;
+                let dividend = self.get_register(rs1) as u32;
+                let divisor = self.get_register(rs2) as u32;
                 let val = if divisor == 0 {
                     dividend
                 } else {
                     dividend % divisor
                 };
                 self.set_register(rd, val.sext());
-                self.dbgins(ins, format!("remuw\t{},{},{}", reg(rd), reg(rs1), reg(rs2)))
             }
 
             // addi ADD immediate
@@ -629,17 +1184,7 @@ impl<BT: Device> Hart<BT> {
                 imm,
             } => {
                 let val = self.get_register(rs1).wrapping_add(imm.sext());
-
-                if rd == 0 {
-                    self.dbgins(ins, "nop".to_string())
-                } else {
-                    self.set_register(rd, val);
-
-                    self.dbgins(
-                        ins,
-                        format!("add\t{},{},{} # {:x}", reg(rd), reg(rs1), imm, val),
-                    )
-                }
+                self.set_register(rd, val);
             }
             // addiw ADD immediate word
             I {
@@ -652,17 +1197,10 @@ impl<BT: Device> Hart<BT> {
                 if imm == 0 {
                     let extended = (self.get_register(rs1) & 0xFFFFFFFF) as i32;
                     self.set_register(rd, extended.sext());
-
-                    self.dbgins(ins, format!("sext.w\t{},{}", reg(rd), reg(rs1)))
                 } else {
                     let val = ((self.get_register(rs1) & 0xFFFFFFFF) as u32)
                         .wrapping_add(imm as i32 as u32);
                     self.set_register(rd, val.sext());
-
-                    self.dbgins(
-                        ins,
-                        format!("addw\t{},{},{} # {:x}", reg(rd), reg(rs1), imm, val),
-                    )
                 }
             }
             // xori XOR immediate
@@ -675,11 +1213,6 @@ impl<BT: Device> Hart<BT> {
             } => {
                 let val = self.get_register(rs1) ^ imm.sext();
                 self.set_register(rd, val);
-
-                self.dbgins(
-                    ins,
-                    format!("xor\t{},{},{} # {:x}", reg(rd), reg(rs1), imm, val),
-                )
             }
             // ori OR immediate
             I {
@@ -691,11 +1224,6 @@ impl<BT: Device> Hart<BT> {
             } => {
                 let val = self.get_register(rs1) | imm as u64;
                 self.set_register(rd, val);
-
-                self.dbgins(
-                    ins,
-                    format!("or\t{},{},{} # {:x}", reg(rd), reg(rs1), imm, val),
-                )
             }
             // andi AND immediate
             I {
@@ -707,11 +1235,6 @@ impl<BT: Device> Hart<BT> {
             } => {
                 let val = self.get_register(rs1) & imm as u64;
                 self.set_register(rd, val);
-
-                self.dbgins(
-                    ins,
-                    format!("and\t{},{},{} # {:x}", reg(rd), reg(rs1), imm, val),
-                )
             }
             // slli Shift Left Logical Imm
             I {
@@ -725,8 +1248,6 @@ impl<BT: Device> Hart<BT> {
                 let shift = (imm & 0b111111) as u32;
                 let (val, _) = rs1val.overflowing_shl(shift);
                 self.set_register(rd, val);
-
-                self.dbgins(ins, format!("sll\t{},{},{:#x}", reg(rd), reg(rs1), imm))
             }
             // slliw Shift Left Logical Imm
             I {
@@ -739,8 +1260,6 @@ impl<BT: Device> Hart<BT> {
                 let (val, _) = ((self.get_register(rs1) & 0xFFFFFFFF) as u32)
                     .overflowing_shl((imm & 0b11111) as u32);
                 self.set_register(rd, val.sext());
-
-                self.dbgins(ins, format!("sll\t{},{},{:#x}", reg(rd), reg(rs1), imm))
             }
             // srli Shift Right Logical Imm
             I {
@@ -754,11 +1273,6 @@ impl<BT: Device> Hart<BT> {
                     .get_register(rs1)
                     .overflowing_shr((imm & 0b111111) as u32);
                 self.set_register(rd, val);
-
-                self.dbgins(
-                    ins,
-                    format!("srl\t{},{},{:#x} # {:x}", reg(rd), reg(rs1), imm, val),
-                )
             }
             // srliw Shift Right Logical Imm
             I {
@@ -771,11 +1285,6 @@ impl<BT: Device> Hart<BT> {
                 let (val, _) = ((self.get_register(rs1) & 0xFFFFFFFF) as u32)
                     .overflowing_shr((imm & 0b11111) as u32);
                 self.set_register(rd, val.sext());
-
-                self.dbgins(
-                    ins,
-                    format!("srlw\t{},{},{:#x} # {:x}", reg(rd), reg(rs1), imm, val),
-                )
             }
             // srai Shift Right Arith Imm
             I {
@@ -788,11 +1297,6 @@ impl<BT: Device> Hart<BT> {
                 let shamt = (imm & 0b111111) as u32;
                 let (val, _) = (self.get_register(rs1) as i64).overflowing_shr(shamt);
                 self.set_register(rd, val.sext());
-
-                self.dbgins(
-                    ins,
-                    format!("sra\t{},{},{:#x} # {:x}", reg(rd), reg(rs1), shamt, val),
-                )
             }
             // sraiw Shift Right Arith Imm
             I {
@@ -805,17 +1309,6 @@ impl<BT: Device> Hart<BT> {
                 let (val, _) = ((self.get_register(rs1) & 0xFFFFFFFF) as i32)
                     .overflowing_shr((imm & 0b11111) as u32);
                 self.set_register(rd, val.sext());
-
-                self.dbgins(
-                    ins,
-                    format!(
-                        "sraw\t{},{},{:#x} # {:x}",
-                        reg(rd),
-                        reg(rs1),
-                        (imm & 0b11111),
-                        val
-                    ),
-                )
             }
             // slti Set Less Than Imm
             I {
@@ -830,12 +1323,7 @@ impl<BT: Device> Hart<BT> {
                 } else {
                     0
                 };
-                self.set_register(rd, val);
-
-                self.dbgins(
-                    ins,
-                    format!("slti\t{},{},{} # {:x}", reg(rd), reg(rs1), imm, val),
-                )
+                self.set_register(rd, val.sext());
             }
             // sltiu Set Less Than Imm (U, zero extends)
             I {
@@ -845,17 +1333,13 @@ impl<BT: Device> Hart<BT> {
                 rs1,
                 imm,
             } => {
-                let val = if self.get_register(rs1) < (imm as u64) {
+                // The immediate is sign extended, then compared as unsigned
+                let val = if self.get_register(rs1) < imm.sext() {
                     1
                 } else {
                     0
                 };
-                self.set_register(rd, val);
-
-                self.dbgins(
-                    ins,
-                    format!("sltiu\t{},{},{} # {:x}", reg(rd), reg(rs1), imm, val),
-                )
+                self.set_register(rd, val.sext());
             }
 
             // lb Load Byte
@@ -866,11 +1350,10 @@ impl<BT: Device> Hart<BT> {
                 rs1,
                 imm,
             } => {
-                let addr = (self.get_register(rs1).wrapping_add(imm.sext())) as usize;
+                let addr = self.address(self.get_register(rs1), imm as i64);
+                self.check_pmp(addr, 1, AccessKind::Read)?;
                 let val = self.bus.read_byte(addr)? as i8;
                 self.set_register(rd, val.sext());
-
-                self.dbgins(ins, format!("lb\t{},{}({})", reg(rd), imm, reg(rs1)))
             }
             // lh Load Half
             I {
@@ -880,11 +1363,11 @@ impl<BT: Device> Hart<BT> {
                 rs1,
                 imm,
             } => {
-                let addr = (self.get_register(rs1).wrapping_add(imm.sext())) as usize;
+                let addr = self.address(self.get_register(rs1), imm as i64);
+                self.check_alignment(addr, 2, Fault::MisalignedLoad)?;
+                self.check_pmp(addr, 2, AccessKind::Read)?;
                 let val = self.bus.read_half(addr)?;
                 self.set_register(rd, val.sext());
-
-                self.dbgins(ins, format!("lh\t{},{}({})", reg(rd), imm, reg(rs1)))
             }
             // lw Load Word
             I {
@@ -894,10 +1377,10 @@ impl<BT: Device> Hart<BT> {
                 rs1,
                 imm,
             } => {
-                let addr = (self.get_register(rs1).wrapping_add(imm.sext())) as usize;
-
-                self.dbgins(ins, format!("lw\t{},{}({})", reg(rd), imm, reg(rs1)));
+                let addr = self.address(self.get_register(rs1), imm as i64);
+                self.check_alignment(addr, 4, Fault::MisalignedLoad)?;
 
+                self.check_pmp(addr, 4, AccessKind::Read)?;
                 let val = self.bus.read_word(addr)?;
                 self.set_register(rd, val.sext());
             }
@@ -909,10 +1392,10 @@ impl<BT: Device> Hart<BT> {
                 rs1,
                 imm,
             } => {
-                let addr = (self.get_register(rs1).wrapping_add(imm.sext())) as usize;
-
-                self.dbgins(ins, format!("ld\t{},{}({})", reg(rd), imm, reg(rs1)));
+                let addr = self.address(self.get_register(rs1), imm as i64);
+                self.check_alignment(addr, 8, Fault::MisalignedLoad)?;
 
+                self.check_pmp(addr, 8, AccessKind::Read)?;
                 let val = self.bus.read_double(addr)?;
                 self.set_register(rd, val);
             }
@@ -924,11 +1407,10 @@ impl<BT: Device> Hart<BT> {
                 rs1,
                 imm,
             } => {
-                let addr = (self.get_register(rs1).wrapping_add(imm.sext())) as usize;
+                let addr = self.address(self.get_register(rs1), imm as i64);
+                self.check_pmp(addr, 1, AccessKind::Read)?;
                 let val = self.bus.read_byte(addr)?;
                 self.set_register(rd, val as u64);
-
-                self.dbgins(ins, format!("lbu\t{},{},{:#x}", reg(rd), reg(rs1), imm))
             }
             // lhu Load Half (U, zero extends)
             I {
@@ -938,11 +1420,11 @@ impl<BT: Device> Hart<BT> {
                 rs1,
                 imm,
             } => {
-                let addr = (self.get_register(rs1).wrapping_add(imm as u64)) as usize;
+                let addr = self.address(self.get_register(rs1), imm as i64);
+                self.check_alignment(addr, 2, Fault::MisalignedLoad)?;
+                self.check_pmp(addr, 2, AccessKind::Read)?;
                 let val = self.bus.read_half(addr)?;
                 self.set_register(rd, val as u64);
-
-                self.dbgins(ins, format!("lhu\t{},{},{:#x}", reg(rd), reg(rs1), imm))
             }
             // lwu Load Word (U, zero extends)
             I {
@@ -952,11 +1434,11 @@ impl<BT: Device> Hart<BT> {
                 rs1,
                 imm,
             } => {
-                let addr = (self.get_register(rs1).wrapping_add(imm as u64)) as usize;
+                let addr = self.address(self.get_register(rs1), imm as i64);
+                self.check_alignment(addr, 4, Fault::MisalignedLoad)?;
+                self.check_pmp(addr, 4, AccessKind::Read)?;
                 let val = self.bus.read_word(addr)?;
                 self.set_register(rd, val as u64);
-
-                self.dbgins(ins, format!("lwu\t{},{},{:#x}", reg(rd), reg(rs1), imm))
             }
 
             // sb Store Byte
@@ -967,10 +1449,10 @@ impl<BT: Device> Hart<BT> {
                 rs2,
                 imm,
             } => {
-                let addr = (self.get_register(rs1).wrapping_add(imm.sext())) as usize;
+                let addr = self.address(self.get_register(rs1), imm as i64);
                 let val = (self.get_register(rs2) & 0xFF) as u8;
 
-                self.dbgins(ins, format!("sb\t{},{}({})", reg(rs2), imm, reg(rs1)));
+                self.check_pmp(addr, 1, AccessKind::Write)?;
                 return self.bus.write_byte(addr, val);
             }
             // sh Store Half
@@ -981,10 +1463,11 @@ impl<BT: Device> Hart<BT> {
                 rs2,
                 imm,
             } => {
-                let addr = (self.get_register(rs1).wrapping_add(imm.sext())) as usize;
+                let addr = self.address(self.get_register(rs1), imm as i64);
+                self.check_alignment(addr, 2, Fault::MisalignedStore)?;
                 let val = (self.get_register(rs2) & 0xFFFF) as u16;
 
-                self.dbgins(ins, format!("sh\t{},{}({})", reg(rs2), imm, reg(rs1)));
+                self.check_pmp(addr, 2, AccessKind::Write)?;
                 return self.bus.write_half(addr, val);
             }
             // sw Store Word
@@ -995,10 +1478,11 @@ impl<BT: Device> Hart<BT> {
                 rs2,
                 imm,
             } => {
-                let addr = (self.get_register(rs1).wrapping_add(imm.sext())) as usize;
+                let addr = self.address(self.get_register(rs1), imm as i64);
+                self.check_alignment(addr, 4, Fault::MisalignedStore)?;
                 let val = (self.get_register(rs2) & 0xFFFFFFFF) as u32;
 
-                self.dbgins(ins, format!("sw\t{},{}({})", reg(rs2), imm, reg(rs1)));
+                self.check_pmp(addr, 4, AccessKind::Write)?;
                 return self.bus.write_word(addr, val);
             }
             // sd Store Double
@@ -1009,10 +1493,11 @@ impl<BT: Device> Hart<BT> {
                 rs2,
                 imm,
             } => {
-                let addr = (self.get_register(rs1).wrapping_add(imm.sext())) as usize;
+                let addr = self.address(self.get_register(rs1), imm as i64);
+                self.check_alignment(addr, 8, Fault::MisalignedStore)?;
                 let val = self.get_register(rs2);
 
-                self.dbgins(ins, format!("sd\t{},{}({})", reg(rs2), imm, reg(rs1)));
+                self.check_pmp(addr, 8, AccessKind::Write)?;
                 return self.bus.write_double(addr, val);
             }
             // beq Branch ==
@@ -1023,9 +1508,7 @@ impl<BT: Device> Hart<BT> {
                 rs2,
                 imm,
             } => {
-                let isize = ins.size();
-                let target = self.pc.wrapping_add(imm as usize).wrapping_sub(isize);
-                self.dbgins(ins, format!("beq\t{},{},{:x}", reg(rs1), reg(rs2), target));
+                let target = self.relative(ins, imm as i64);
 
                 if self.get_register(rs1) == self.get_register(rs2) {
                     self.pc = target;
@@ -1039,9 +1522,7 @@ impl<BT: Device> Hart<BT> {
                 rs2,
                 imm,
             } => {
-                let isize = ins.size();
-                let target = self.pc.wrapping_add(imm as usize).wrapping_sub(isize);
-                self.dbgins(ins, format!("bne\t{},{},{:x}", reg(rs1), reg(rs2), target));
+                let target = self.relative(ins, imm as i64);
 
                 if self.get_register(rs1) != self.get_register(rs2) {
                     self.pc = target;
@@ -1055,9 +1536,7 @@ impl<BT: Device> Hart<BT> {
                 rs2,
                 imm,
             } => {
-                let isize = ins.size();
-                let target = self.pc.wrapping_add(imm as usize).wrapping_sub(isize);
-                self.dbgins(ins, format!("blt\t{},{},{:x}", reg(rs1), reg(rs2), target));
+                let target = self.relative(ins, imm as i64);
 
                 if (self.get_register(rs1) as i64) < (self.get_register(rs2) as i64) {
                     self.pc = target;
@@ -1071,9 +1550,7 @@ impl<BT: Device> Hart<BT> {
                 rs2,
                 imm,
             } => {
-                let isize = ins.size();
-                let target = self.pc.wrapping_add(imm as usize).wrapping_sub(isize);
-                self.dbgins(ins, format!("bge\t{},{},{:x}", reg(rs1), reg(rs2), target));
+                let target = self.relative(ins, imm as i64);
 
                 if (self.get_register(rs1) as i64) >= (self.get_register(rs2) as i64) {
                     self.pc = target;
@@ -1087,12 +1564,7 @@ impl<BT: Device> Hart<BT> {
                 rs2,
                 imm,
             } => {
-                let isize = ins.size();
-                let target = self.pc.wrapping_add(imm as usize).wrapping_sub(isize);
-                self.dbgins(
-                    ins,
-                    format!("bgltu\t{},{},{:x}", reg(rs1), reg(rs2), target),
-                );
+                let target = self.relative(ins, imm as i64);
 
                 if self.get_register(rs1) < self.get_register(rs2) {
                     self.pc = target;
@@ -1106,9 +1578,7 @@ impl<BT: Device> Hart<BT> {
                 rs2,
                 imm,
             } => {
-                let isize = ins.size();
-                let target = self.pc.wrapping_add(imm as usize).wrapping_sub(isize);
-                self.dbgins(ins, format!("bgeu\t{},{},{:x}", reg(rs1), reg(rs2), target));
+                let target = self.relative(ins, imm as i64);
 
                 if self.get_register(rs1) >= self.get_register(rs2) {
                     self.pc = target;
@@ -1121,9 +1591,7 @@ impl<BT: Device> Hart<BT> {
                 rd,
                 imm,
             } => {
-                let isize = ins.size();
-                let target = self.pc.wrapping_add(imm as usize).wrapping_sub(isize);
-                self.dbgins(ins, format!("jal\t{},{:x}", reg(rd), target));
+                let target = self.relative(ins, imm as i64);
 
                 self.set_register(rd, self.pc as u64);
                 self.pc = target;
@@ -1136,14 +1604,12 @@ impl<BT: Device> Hart<BT> {
                 rs1,
                 imm,
             } => {
-                let target = self.get_register(rs1).wrapping_add(imm as u64);
+                let target = self.address(self.get_register(rs1), imm as i64);
                 // Clear last bit: Spec (V 2.1, p. 5), align to 16 bit parcels
-                let target = target & 0xFFFF_FFFE;
-
-                self.dbgins(ins, format!("jalr\t{},{}({})", reg(rd), imm, reg(rs1)));
+                let target = target & !1;
 
                 self.set_register(rd, self.pc as u64);
-                self.pc = target as usize;
+                self.pc = target;
             }
 
             // lui Load Upper Imm
@@ -1154,8 +1620,6 @@ impl<BT: Device> Hart<BT> {
             } => {
                 let val = (imm << 12) as i64 as u64;
                 self.set_register(rd, val);
-
-                self.dbgins(ins, format!("lui\t{},{:#x}", reg(rd), imm))
             }
             // auipc Add Upper Imm to PC
             U {
@@ -1166,8 +1630,6 @@ impl<BT: Device> Hart<BT> {
                 let val = (imm << 12) as i64 as u64;
                 let val = (self.pc as u64 - 4).wrapping_add(val);
                 self.set_register(rd, val);
-
-                self.dbgins(ins, format!("auipc\t{},{:#x}", reg(rd), imm))
             }
 
             // RV32 Zifencei
@@ -1177,12 +1639,8 @@ impl<BT: Device> Hart<BT> {
                 funct3: 0x0,
                 rd: 0x0,
                 rs1: 0x0,
-                imm,
-            } => {
-                let pred = (imm >> 4) & 0b1111;
-                let succ = imm & 0b1111;
-                self.dbgins(ins, format!("fence\t{},{}", pred, succ))
-            }
+                ..
+            } => {}
             // Fence.I
             I {
                 opcode: 0b0001111,
@@ -1191,8 +1649,7 @@ impl<BT: Device> Hart<BT> {
                 rs1: 0x0,
                 imm: 0,
             } => {
-                // For now, all accesses to addresses go through locking, ignore fence
-                self.dbgins(ins, "fence unknown,unknown".to_string())
+                self.flush_icache();
             }
 
             // ecall Environment Call
@@ -1204,10 +1661,14 @@ impl<BT: Device> Hart<BT> {
             } => {
                 // We're unprivileged machine mode, no need to check SEDELEG
 
-                self.dbgins(ins, "ecall".to_string());
+                if !self.emulated_sbi {
+                    // mepc points at the ecall itself
+                    self.pc -= 4;
+                    self.trap(csr::MCAUSE_ECALL_M, 0);
+                    return Ok(());
+                }
 
-                // For now, ignore SEE errors
-                let _ = see::call(self);
+                see::call(self)?;
 
                 // ecall causes synchronous exception
                 return Ok(());
@@ -1219,12 +1680,28 @@ impl<BT: Device> Hart<BT> {
                 imm: 0x1,
                 ..
             } => {
-                see::ebreak();
+                // ebreak causes synchronous exception, the pc is left past the ebreak so
+                // execution can be resumed
+                return Err(Fault::Breakpoint);
+            }
 
-                self.dbgins(ins, "ebreak".to_string());
+            // mret Machine Trap Return
+            I {
+                opcode: 0b1110011,
+                funct3: 0x0,
+                imm: 0x302,
+                ..
+            } => {
+                let mstatus = self.csr.read(csr::MSTATUS);
+                let mie = match mstatus & csr::MSTATUS_MPIE {
+                    0 => 0,
+                    _ => csr::MSTATUS_MIE,
+                };
+                let mstatus = (mstatus & !csr::MSTATUS_MIE) | mie | csr::MSTATUS_MPIE;
+                self.csr.write(csr::MSTATUS, mstatus);
 
-                // ebreak causes synchronous exception
-                return Ok(());
+                self.pc = self.csr.read(csr::MEPC) as usize;
+                debug!("mret M -> M: epc {:#x}", self.pc);
             }
 
             // RV32/RV64 Zicsr
@@ -1237,16 +1714,16 @@ impl<BT: Device> Hart<BT> {
                 imm,
             } => {
                 let csr = (imm as u16 & 0xFFF) as usize;
+                Hart::<BT>::check_csr_write(csr, ins)?;
 
+                // rs1 is read before rd is written, as they may be the same register
+                let src = self.xlen.truncate(self.get_register(rs1));
+                // Without a destination the CSR is not read at all, avoiding read side effects
                 if rd != 0 {
-                    self.set_register(rd, self.csr.read(csr));
+                    let val = self.read_csr(csr);
+                    self.set_register(rd, val);
                 }
-                self.csr.write(csr, self.get_register(rs1));
-
-                self.dbgins(
-                    ins,
-                    format!("csrrw\t{},{},{}", reg(rd), Csr::name(csr), reg(rs1)),
-                )
+                self.csr.write(csr, src);
             }
             // csrrs Atomic Read and Set Bits in CSR
             I {
@@ -1257,18 +1734,17 @@ impl<BT: Device> Hart<BT> {
                 imm,
             } => {
                 let csr = (imm as u16 & 0xFFF) as usize;
+                if rs1 != 0 {
+                    Hart::<BT>::check_csr_write(csr, ins)?;
+                }
 
-                self.set_register(rd, self.csr.read(csr));
+                let mask = self.xlen.truncate(self.get_register(rs1));
+                let val = self.read_csr(csr);
+                self.set_register(rd, val);
 
                 if rs1 != 0 {
-                    self.csr
-                        .write(csr, self.csr.read(csr) | self.get_register(rs1));
+                    self.csr.write(csr, val | mask);
                 }
-
-                self.dbgins(
-                    ins,
-                    format!("csrrs\t{},{},{}", reg(rd), Csr::name(csr), reg(rs1)),
-                )
             }
             // csrrc Atomic Read and Clear Bits in CSR
             I {
@@ -1279,19 +1755,17 @@ impl<BT: Device> Hart<BT> {
                 imm,
             } => {
                 let csr = (imm as u16 & 0xFFF) as usize;
-                if rd != 0 {
-                    self.set_register(rd, self.csr.read(csr));
+                if rs1 != 0 {
+                    Hart::<BT>::check_csr_write(csr, ins)?;
                 }
 
+                let mask = self.xlen.truncate(self.get_register(rs1));
+                let val = self.read_csr(csr);
+                self.set_register(rd, val);
+
                 if rs1 != 0 {
-                    self.csr
-                        .write(csr, self.csr.read(csr) & !self.get_register(rs1));
+                    self.csr.write(csr, val & !mask);
                 }
-
-                self.dbgins(
-                    ins,
-                    format!("csrrc\t{},{},{}", reg(rd), Csr::name(csr), reg(rs1)),
-                )
             }
             // csrrwi
             I {
@@ -1303,14 +1777,11 @@ impl<BT: Device> Hart<BT> {
             } => {
                 let csr = (imm as u16 & 0xFFF) as usize;
                 let imm = rs1 as u64;
-
-                self.dbgins(
-                    ins,
-                    format!("csrrwi\t{},{},{}", reg(rd), Csr::name(csr), imm),
-                );
+                Hart::<BT>::check_csr_write(csr, ins)?;
 
                 if rd != 0 {
-                    self.set_register(rd, self.csr.read(csr));
+                    let val = self.read_csr(csr);
+                    self.set_register(rd, val);
                 }
                 self.csr.write(csr, imm);
             }
@@ -1324,16 +1795,15 @@ impl<BT: Device> Hart<BT> {
             } => {
                 let csr = (imm as u16 & 0xFFF) as usize;
                 let imm = rs1 as u64;
+                if imm != 0 {
+                    Hart::<BT>::check_csr_write(csr, ins)?;
+                }
 
-                self.dbgins(
-                    ins,
-                    format!("csrrsi\t{},{},{}", reg(rd), Csr::name(csr), imm),
-                );
-
-                self.set_register(rd, self.csr.read(csr));
+                let val = self.read_csr(csr);
+                self.set_register(rd, val);
 
-                if rs1 != 0 {
-                    self.csr.write(csr, self.csr.read(csr) | imm);
+                if imm != 0 {
+                    self.csr.write(csr, val | imm);
                 }
             }
             // csrrci
@@ -1346,18 +1816,15 @@ impl<BT: Device> Hart<BT> {
             } => {
                 let csr = (imm as u16 & 0xFFF) as usize;
                 let imm = rs1 as u64;
-
-                self.dbgins(
-                    ins,
-                    format!("csrrci\t{},{},{}", reg(rd), Csr::name(csr), imm),
-                );
-
-                if rd != 0 {
-                    self.set_register(rd, self.csr.read(csr));
+                if imm != 0 {
+                    Hart::<BT>::check_csr_write(csr, ins)?;
                 }
 
-                if rs1 != 0 {
-                    self.csr.write(csr, self.csr.read(csr) & !imm);
+                let val = self.read_csr(csr);
+                self.set_register(rd, val);
+
+                if imm != 0 {
+                    self.csr.write(csr, val & !imm);
                 }
             }
 
@@ -1365,23 +1832,12 @@ impl<BT: Device> Hart<BT> {
             // sfence.vma Atomic Read and Clear Bits in CSR
             R {
                 opcode: 0b1110011,
-                rd,
                 funct3: 0x0,
-                rs1,
-                rs2,
                 ..
-            } => self.dbgins(
-                ins,
-                format!(
-                    "system\t{},{},{} # {:08x}",
-                    reg(rd),
-                    reg(rs1),
-                    reg(rs2),
-                    ins
-                ),
-            ),
+            } => {}
 
-            // Atomics
+            // Atomics.  Every AMO runs as a single read-modify-write under the device lock, which
+            // already orders it at least as strongly as the aq/rl bits ask for.
             R {
                 opcode: 0b0101111,
                 rd,
@@ -1391,112 +1847,70 @@ impl<BT: Device> Hart<BT> {
                 funct7,
             } => {
                 let funct5 = funct7 >> 2;
-                let _aq = (funct7 >> 1) & 0b1;
-                let _rl = funct7 & 0b1;
 
-                let addr = self.get_register(rs1) as usize;
-                let mut val = self.bus.read_word(addr)?;
+                let addr = self.address(self.get_register(rs1), 0);
+                // Unlike regular accesses, AMOs always require natural alignment.  LR reports a
+                // misaligned load, SC and AMOs a misaligned store.
+                if !addr.is_multiple_of(4) {
+                    return Err(match funct5 {
+                        0x02 => Fault::MisalignedLoad(addr),
+                        _ => Fault::MisalignedStore(addr),
+                    });
+                }
                 let rs2val = (self.get_register(rs2) & 0xFFFFFFFF) as u32;
-                let new = match funct5 {
+                let val = match funct5 {
                     // lr.w
                     0x02 => {
-                        self.dbgins(
-                            ins,
-                            format!("lr.w\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        // XXX: should register a reservation on `addr`
+                        self.check_pmp(addr, 4, AccessKind::Read)?;
+                        let val = self.bus.read_word(addr)?;
+                        self.reservation = Some((addr, val as u64));
                         val
                     }
                     // sc.w
                     0x03 => {
-                        self.dbgins(
-                            ins,
-                            format!("sc.w\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        // XXX: should test for reservation on `addr`
-                        val = 0; // Success, non-zero on failure
-                        rs2val
-                    }
-                    // amoswap.w
-                    0x01 => {
-                        self.dbgins(
-                            ins,
-                            format!("amoswap.w\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        let rdval = self.get_register(rd);
-                        self.set_register(rs2, rdval);
-                        rs2val
-                    }
-                    // amoadd.w
-                    0x00 => {
-                        self.dbgins(
-                            ins,
-                            format!("amoadd.w\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-
-                        val.wrapping_add(rs2val)
-                    }
-                    // amoand.w
-                    0x0C => {
-                        self.dbgins(
-                            ins,
-                            format!("amoand.w\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        val & rs2val
-                    }
-                    // amoor.w
-                    0x08 => {
-                        self.dbgins(
-                            ins,
-                            format!("amoor.w\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        val | rs2val
-                    }
-                    // amoxor.w
-                    0x04 => {
-                        self.dbgins(
-                            ins,
-                            format!("amoxor.w\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        val ^ rs2val
-                    }
-                    // amomax.w
-                    0x14 => {
-                        self.dbgins(
-                            ins,
-                            format!("amomax.w\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        cmp::max(val as i32, rs2val as i32) as u32
-                    }
-                    // amomin.w
-                    0x10 => {
-                        self.dbgins(
-                            ins,
-                            format!("amomin.w\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        cmp::min(val as i32, rs2val as i32) as u32
-                    }
-                    // amomaxu.w
-                    0x1C => {
-                        self.dbgins(
-                            ins,
-                            format!("amomaxu.w\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        cmp::max(val, rs2val)
+                        self.check_pmp(addr, 4, AccessKind::Write)?;
+                        match self.reservation.take() {
+                            // Stores only if the reserved word was not changed by another hart
+                            Some((reserved, expected)) if reserved == addr => {
+                                let expected = expected as u32;
+                                let val =
+                                    self.bus.amo_word(addr, &|val| match val == expected {
+                                        true => rs2val,
+                                        false => val,
+                                    })?;
+                                (val != expected) as u32
+                            }
+                            _ => 1,
+                        }
                     }
-                    // amominu.w
-                    0x18 => {
-                        self.dbgins(
-                            ins,
-                            format!("amominu.w\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        cmp::min(val, rs2val)
+                    _ => {
+                        let op: fn(u32, u32) -> u32 = match funct5 {
+                            // amoswap.w
+                            0x01 => |_, rs2val| rs2val,
+                            // amoadd.w
+                            0x00 => |val, rs2val| val.wrapping_add(rs2val),
+                            // amoand.w
+                            0x0C => |val, rs2val| val & rs2val,
+                            // amoor.w
+                            0x08 => |val, rs2val| val | rs2val,
+                            // amoxor.w
+                            0x04 => |val, rs2val| val ^ rs2val,
+                            // amomax.w
+                            0x14 => |val, rs2val| cmp::max(val as i32, rs2val as i32) as u32,
+                            // amomin.w
+                            0x10 => |val, rs2val| cmp::min(val as i32, rs2val as i32) as u32,
+                            // amomaxu.w
+                            0x1C => cmp::max,
+                            // amominu.w
+                            0x18 => cmp::min,
+                            _ => return Err(IllegalOpcode(ins)),
+                        };
+                        self.check_pmp(addr, 4, AccessKind::ReadWrite)?;
+                        self.bus.amo_word(addr, &|val| op(val, rs2val))?
                     }
-                    _ => return Err(IllegalOpcode(ins)),
                 };
 
                 self.set_register(rd, val.sext());
-                self.bus.write_word(addr, new)?;
             }
             R {
                 opcode: 0b0101111,
@@ -1507,112 +1921,197 @@ impl<BT: Device> Hart<BT> {
                 funct7,
             } => {
                 let funct5 = funct7 >> 2;
-                let _aq = (funct7 >> 1) & 0b1;
-                let _rl = funct7 & 0b1;
 
-                let addr = self.get_register(rs1) as usize;
-                let mut val = self.bus.read_double(addr)?;
+                let addr = self.address(self.get_register(rs1), 0);
+                if !addr.is_multiple_of(8) {
+                    return Err(match funct5 {
+                        0x02 => Fault::MisalignedLoad(addr),
+                        _ => Fault::MisalignedStore(addr),
+                    });
+                }
                 let rs2val = self.get_register(rs2);
-                let new = match funct5 {
+                let val = match funct5 {
                     // lr.d
                     0x02 => {
-                        self.dbgins(
-                            ins,
-                            format!("lr.d\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        // XXX: should register a reservation on `addr`
+                        self.check_pmp(addr, 8, AccessKind::Read)?;
+                        let val = self.bus.read_double(addr)?;
+                        self.reservation = Some((addr, val));
                         val
                     }
                     // sc.d
                     0x03 => {
-                        self.dbgins(
-                            ins,
-                            format!("sc.d\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        // XXX: should test for reservation on `addr`
-                        val = 0; // Success, non-zero on failure
-                        rs2val
-                    }
-                    // amoswap.d
-                    0x01 => {
-                        self.dbgins(
-                            ins,
-                            format!("amoswap.d\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        let rdval = self.get_register(rd);
-                        self.set_register(rs2, rdval);
-                        rs2val
-                    }
-                    // amoadd.d
-                    0x00 => {
-                        self.dbgins(
-                            ins,
-                            format!("amoadd.d\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-
-                        val.wrapping_add(rs2val)
-                    }
-                    // amoand.d
-                    0x0C => {
-                        self.dbgins(
-                            ins,
-                            format!("amoand.d\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        val & rs2val
-                    }
-                    // amoor.d
-                    0x08 => {
-                        self.dbgins(
-                            ins,
-                            format!("amoor.d\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        val | rs2val
-                    }
-                    // amoxor.d
-                    0x04 => {
-                        self.dbgins(
-                            ins,
-                            format!("amoxor.d\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        val ^ rs2val
-                    }
-                    // amomax.d
-                    0x14 => {
-                        self.dbgins(
-                            ins,
-                            format!("amomax.d\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        cmp::max(val as i64, rs2val as i64) as u64
+                        self.check_pmp(addr, 8, AccessKind::Write)?;
+                        match self.reservation.take() {
+                            Some((reserved, expected)) if reserved == addr => {
+                                let val =
+                                    self.bus.amo_double(addr, &|val| match val == expected {
+                                        true => rs2val,
+                                        false => val,
+                                    })?;
+                                (val != expected) as u64
+                            }
+                            _ => 1,
+                        }
                     }
-                    // amomin.d
-                    0x10 => {
-                        self.dbgins(
-                            ins,
-                            format!("amomin.d\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        cmp::min(val as i64, rs2val as i64) as u64
+                    _ => {
+                        let op: fn(u64, u64) -> u64 = match funct5 {
+                            // amoswap.d
+                            0x01 => |_, rs2val| rs2val,
+                            // amoadd.d
+                            0x00 => |val, rs2val| val.wrapping_add(rs2val),
+                            // amoand.d
+                            0x0C => |val, rs2val| val & rs2val,
+                            // amoor.d
+                            0x08 => |val, rs2val| val | rs2val,
+                            // amoxor.d
+                            0x04 => |val, rs2val| val ^ rs2val,
+                            // amomax.d
+                            0x14 => |val, rs2val| cmp::max(val as i64, rs2val as i64) as u64,
+                            // amomin.d
+                            0x10 => |val, rs2val| cmp::min(val as i64, rs2val as i64) as u64,
+                            // amomaxu.d
+                            0x1C => cmp::max,
+                            // amominu.d
+                            0x18 => cmp::min,
+                            _ => return Err(IllegalOpcode(ins)),
+                        };
+                        self.check_pmp(addr, 8, AccessKind::ReadWrite)?;
+                        self.bus.amo_double(addr, &|val| op(val, rs2val))?
                     }
-                    // amomaxu.d
-                    0x1C => {
-                        self.dbgins(
-                            ins,
-                            format!("amomaxu.d\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        cmp::max(val, rs2val)
-                    }
-                    // amominu.d
-                    0x18 => {
-                        self.dbgins(
-                            ins,
-                            format!("amominu.d\t{},{},({})", reg(rd), reg(rs2), reg(rs1)),
-                        );
-                        cmp::min(val, rs2val)
-                    }
-                    _ => return Err(IllegalOpcode(ins)),
                 };
 
                 self.set_register(rd, val);
-                self.bus.write_double(addr, new)?;
+            }
+
+            // RV64D
+
+            // fld Load Double (FP)
+            I {
+                opcode: 0b0000111,
+                rd,
+                funct3: 0x3,
+                rs1,
+                imm,
+            } => {
+                let addr = self.address(self.get_register(rs1), imm as i64);
+                self.check_alignment(addr, 8, Fault::MisalignedLoad)?;
+
+                self.check_pmp(addr, 8, AccessKind::Read)?;
+                let val = self.bus.read_double(addr)?;
+                self.set_fregister(rd, val);
+            }
+            // fsd Store Double (FP)
+            S {
+                opcode: 0b0100111,
+                funct3: 0x3,
+                rs1,
+                rs2,
+                imm,
+            } => {
+                let addr = self.address(self.get_register(rs1), imm as i64);
+                self.check_alignment(addr, 8, Fault::MisalignedStore)?;
+                let val = self.get_fregister(rs2);
+
+                self.check_pmp(addr, 8, AccessKind::Write)?;
+                self.bus.write_double(addr, val)?;
+            }
+            // fadd.d / fsub.d / fmul.d / fdiv.d
+            R {
+                opcode: 0b1010011,
+                rd,
+                funct3: rm,
+                rs1,
+                rs2,
+                funct7: funct7 @ (0x01 | 0x05 | 0x09 | 0x0D),
+            } => {
+                // The host only rounds to nearest, but the mode must still be a legal one
+                self.rounding_mode(rm).ok_or(IllegalOpcode(ins))?;
+
+                let a = f64::from_bits(self.get_fregister(rs1));
+                let b = f64::from_bits(self.get_fregister(rs2));
+                let val = match funct7 {
+                    0x01 => a + b,
+                    0x05 => a - b,
+                    0x09 => a * b,
+                    _ => a / b,
+                };
+                self.set_fregister(rd, val.to_bits());
+            }
+            // fcvt.w.d / fcvt.wu.d / fcvt.l.d / fcvt.lu.d
+            R {
+                opcode: 0b1010011,
+                rd,
+                funct3: rm,
+                rs1,
+                rs2,
+                funct7: 0x61,
+            } => {
+                let rm = self.rounding_mode(rm).ok_or(IllegalOpcode(ins))?;
+                let val = f64::from_bits(self.get_fregister(rs1));
+                let val = match rm {
+                    0b000 => val.round_ties_even(),
+                    0b001 => val.trunc(),
+                    0b010 => val.floor(),
+                    0b011 => val.ceil(),
+                    _ => val.round(),
+                };
+
+                // Out of range values saturate, NaN converts to the largest value
+                let val = match rs2 {
+                    0x0 if val.is_nan() => i32::MAX.sext(),
+                    0x0 => (val as i32).sext(),
+                    0x1 if val.is_nan() => u32::MAX.sext(),
+                    0x1 => (val as u32).sext(),
+                    0x2 if val.is_nan() => i64::MAX as u64,
+                    0x2 => val as i64 as u64,
+                    0x3 if val.is_nan() => u64::MAX,
+                    0x3 => val as u64,
+                    _ => return Err(IllegalOpcode(ins)),
+                };
+                self.set_register(rd, val);
+            }
+            // fcvt.d.w / fcvt.d.wu / fcvt.d.l / fcvt.d.lu
+            R {
+                opcode: 0b1010011,
+                rd,
+                funct3: rm,
+                rs1,
+                rs2,
+                funct7: 0x69,
+            } => {
+                self.rounding_mode(rm).ok_or(IllegalOpcode(ins))?;
+
+                let val = self.get_register(rs1);
+                let val = match rs2 {
+                    0x0 => val as i32 as f64,
+                    0x1 => val as u32 as f64,
+                    0x2 => val as i64 as f64,
+                    0x3 => val as f64,
+                    _ => return Err(IllegalOpcode(ins)),
+                };
+                self.set_fregister(rd, val.to_bits());
+            }
+            // fmv.x.d Move (FP to integer)
+            R {
+                opcode: 0b1010011,
+                rd,
+                funct3: 0x0,
+                rs1,
+                rs2: 0x0,
+                funct7: 0x71,
+            } => {
+                self.set_register(rd, self.get_fregister(rs1));
+            }
+            // fmv.d.x Move (integer to FP)
+            R {
+                opcode: 0b1010011,
+                rd,
+                funct3: 0x0,
+                rs1,
+                rs2: 0x0,
+                funct7: 0x79,
+            } => {
+                self.set_fregister(rd, self.get_register(rs1));
             }
 
             _ => {
@@ -1631,32 +2130,178 @@ impl<BT: Device> Hart<BT> {
         // increase, it should do an early return in the match expression.
         self.csr
             .write(csr::MINSTRET, self.csr.read(csr::MINSTRET) + 1);
+        if let Some(clock) = &self.clock {
+            clock.advance();
+        }
 
         Ok(())
     }
 
-    fn dbgins(&self, ins: Instruction, asm: String) {
+    // CSRs with the top two address bits set are read-only, writing them is illegal
+    fn check_csr_write(csr: usize, ins: Instruction) -> Result<(), Fault> {
+        match csr >> 10 {
+            0b11 => Err(IllegalOpcode(ins)),
+            _ => Ok(()),
+        }
+    }
+
+    fn bits(&self) -> u32 {
+        match self.xlen {
+            Xlen::Rv32 => 32,
+            Xlen::Rv64 => 64,
+        }
+    }
+
+    // Maps an instruction onto its RV64 equivalent under RV32.  Operations which depend on the
+    // register width behave like their word variants, which RV32 does not have, like any
+    // access or conversion of doublewords to integer registers.
+    fn narrow(instruction: InstructionFormat) -> Option<InstructionFormat> {
+        let narrowed = match instruction {
+            R {
+                opcode: 0b0111011, ..
+            }
+            | I {
+                opcode: 0b0011011, ..
+            } => return None,
+            // add, sub, sll, srl, sra, mul, div, divu, rem and remu
+            R {
+                opcode: 0b0110011,
+                rd,
+                funct3,
+                rs1,
+                rs2,
+                funct7,
+            } if matches!(
+                (funct7, funct3),
+                (0x00 | 0x20, 0x0) | (0x00, 0x1) | (0x00 | 0x20, 0x5) | (0x01, 0x0 | 0x4..=0x7)
+            ) =>
+            {
+                R {
+                    opcode: 0b0111011,
+                    rd,
+                    funct3,
+                    rs1,
+                    rs2,
+                    funct7,
+                }
+            }
+            // addi, slli, srli and srai, shift amounts are limited to 5 bits
+            I {
+                opcode: 0b0010011,
+                rd,
+                funct3: funct3 @ (0x0 | 0x1 | 0x5),
+                rs1,
+                imm,
+            } => {
+                if funct3 != 0x0 && imm & 0b10_0000 != 0 {
+                    return None;
+                }
+                I {
+                    opcode: 0b0011011,
+                    rd,
+                    funct3,
+                    rs1,
+                    imm,
+                }
+            }
+            // ld, lwu and sd
+            I {
+                opcode: 0b0000011,
+                funct3: 0x3 | 0x6,
+                ..
+            }
+            | S {
+                opcode: 0b0100011,
+                funct3: 0x3,
+                ..
+            } => return None,
+            // doubleword AMOs
+            R {
+                opcode: 0b0101111,
+                funct3: 0x3,
+                ..
+            } => return None,
+            // fcvt.l.d, fcvt.lu.d, fcvt.d.l, fcvt.d.lu, fmv.x.d and fmv.d.x
+            R {
+                opcode: 0b1010011,
+                funct7: 0x61 | 0x69,
+                rs2: 0x2 | 0x3,
+                ..
+            }
+            | R {
+                opcode: 0b1010011,
+                funct7: 0x71 | 0x79,
+                ..
+            } => return None,
+            instruction => instruction,
+        };
+        Some(narrowed)
+    }
+
+    // Resolves the rounding mode of an instruction, falling back to `frm` for the dynamic
+    // mode.  Reserved modes resolve to `None`.
+    fn rounding_mode(&self, rm: u8) -> Option<u8> {
+        let rm = match rm {
+            0b111 => self.csr.read(csr::FRM) as u8,
+            rm => rm,
+        };
+        match rm {
+            0b000..=0b100 => Some(rm),
+            _ => None,
+        }
+    }
+
+    // `asm` is only formatted when tracing is enabled, this is called for every instruction
+    fn dbgins(&self, ins: Instruction, asm: impl FnOnce() -> String) {
         match ins {
             Instruction::IRV32(ins) => {
-                trace!("{:08x}:\t{:08x}          \t{}", self.pc - 4, ins, asm)
+                trace!(
+                    "{}:\t{:08x}          \t{}",
+                    self.describe(self.pc - 4),
+                    ins,
+                    asm()
+                )
             }
             Instruction::CRV32(ins) => {
-                trace!("{:08x}:\t{:04x}                \t{}", self.pc - 2, ins, asm)
+                trace!(
+                    "{}:\t{:04x}                \t{}",
+                    self.describe(self.pc - 2),
+                    ins,
+                    asm()
+                )
             }
         }
     }
+
+    fn describe(&self, addr: usize) -> String {
+        match &self.symbols {
+            Some(symbols) => symbols.describe(addr),
+            None => format!("{addr:08x}"),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex, OnceLock};
+    use std::thread;
+
+    use log::{Level, LevelFilter, Log, Metadata, Record};
 
     use crate::bus::Bus;
-    use crate::hart::Hart;
+    use crate::clint;
+    use crate::csr;
+    use crate::device::Device;
+    use crate::dynbus::DynBus;
+    use crate::hart::{CycleCosts, Exit, Hart, Xlen};
     use crate::ins::{Instruction, InstructionFormat};
+    use crate::plic::Fault;
     use crate::ram::Ram;
-    use crate::reg::treg;
+    use crate::reg::{fptreg, treg};
     use crate::rom::Rom;
+    use crate::rtc::{self, Clock, Rtc};
 
     #[test]
     fn addi() {
@@ -1815,6 +2460,28 @@ mod tests {
         assert_eq!(m.get_register(treg("s6")), 0x40 << 38);
     }
 
+    #[test]
+    fn rv64_shift_right_by_40() {
+        let mut m = hart();
+        m.set_register(treg("a0"), 0x8000_1234_5678_0000);
+
+        // srli a1,a0,40
+        let ins = Instruction::IRV32(0x02855593);
+        let decoded = ins.decode().expect("decode").1;
+        m.execute_instruction(decoded, ins).expect("execute");
+        assert_eq!(m.get_register(treg("a1")), 0x80_0012, "srli wrong");
+
+        // srai a2,a0,40
+        let ins = Instruction::IRV32(0x42855613);
+        let decoded = ins.decode().expect("decode").1;
+        m.execute_instruction(decoded, ins).expect("execute");
+        assert_eq!(
+            m.get_register(treg("a2")),
+            0xffff_ffff_ff80_0012,
+            "srai wrong"
+        );
+    }
+
     #[test]
     fn test_addw() {
         // beq	s3,s3,80000138
@@ -1879,4 +2546,1171 @@ mod tests {
 
         assert_eq!(m.get_register(treg("gp")), 0x0);
     }
+
+    #[test]
+    fn test_amoswap_w() {
+        // amoswap.w a0,a1,(a2)
+        let ins = Instruction::IRV32(0x08b6252f);
+        let mut m = hart();
+        m.pc = 0x80000004;
+        m.set_register(treg("a0"), 0x1);
+        m.set_register(treg("a1"), 0x1234);
+        m.set_register(treg("a2"), 0x80000100);
+        m.bus.write_word(0x80000100, 0xdeadbeef).expect("write");
+
+        let decoded = ins.decode().expect("decode").1;
+        m.execute_instruction(decoded, ins).expect("execute");
+
+        assert_eq!(
+            m.get_register(treg("a0")),
+            0xffffffff_deadbeef,
+            "rd mismatch"
+        );
+        assert_eq!(m.get_register(treg("a1")), 0x1234, "rs2 must not change");
+        assert_eq!(m.bus.read_word(0x80000100).expect("read"), 0x1234);
+    }
+
+    #[test]
+    fn test_amoswap_d() {
+        // amoswap.d a0,a1,(a2)
+        let ins = Instruction::IRV32(0x08b6352f);
+        let mut m = hart();
+        m.pc = 0x80000004;
+        m.set_register(treg("a1"), 0x11223344_55667788);
+        m.set_register(treg("a2"), 0x80000100);
+        m.bus
+            .write_double(0x80000100, 0xdeadbeef_cafebabe)
+            .expect("write");
+
+        let decoded = ins.decode().expect("decode").1;
+        m.execute_instruction(decoded, ins).expect("execute");
+
+        assert_eq!(
+            m.get_register(treg("a0")),
+            0xdeadbeef_cafebabe,
+            "rd mismatch"
+        );
+        assert_eq!(m.get_register(treg("a1")), 0x11223344_55667788);
+        assert_eq!(
+            m.bus.read_double(0x80000100).expect("read"),
+            0x11223344_55667788
+        );
+    }
+
+    #[test]
+    fn test_amoadd_w_multithreaded() {
+        let rom = Rom::new(vec![
+            0x2f, 0x20, 0xb6, 0x00, // amoadd.w zero,a1,(a2)
+            0x6f, 0xf0, 0xdf, 0xff, // j 0
+        ]);
+        let ram = Ram::new();
+        let bus = Arc::new(Bus::new(rom, ram));
+
+        let handles: Vec<_> = (0..2)
+            .map(|id| {
+                let bus = bus.clone();
+                thread::spawn(move || {
+                    let mut m = Hart::new(id, 0, bus);
+                    m.set_register(treg("a1"), 1);
+                    m.set_register(treg("a2"), 0x80000000);
+                    for _ in 0..10_000 {
+                        m.tick().expect("tick");
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().expect("hart failed");
+        }
+
+        assert_eq!(bus.read_word(0x80000000).expect("read"), 2 * 5_000);
+    }
+
+    #[test]
+    fn fp_load_add_store() {
+        let rom = Rom::new(vec![
+            0x07, 0x35, 0x05, 0x00, // fld	fa0,0(a0)
+            0x87, 0x35, 0x85, 0x00, // fld	fa1,8(a0)
+            0x53, 0x76, 0xb5, 0x02, // fadd.d	fa2,fa0,fa1
+            0x27, 0x38, 0xc5, 0x00, // fsd	fa2,16(a0)
+        ]);
+        let ram = Ram::new();
+        let bus = Arc::new(Bus::new(rom, ram));
+        bus.write_double(0x80000000, 1.5f64.to_bits())
+            .expect("write");
+        bus.write_double(0x80000008, 2.25f64.to_bits())
+            .expect("write");
+
+        let mut m = Hart::new(0, 0, bus.clone());
+        m.set_register(treg("a0"), 0x80000000);
+        for _ in 0..4 {
+            m.tick().expect("tick");
+        }
+
+        assert_eq!(f64::from_bits(m.get_fregister(fptreg("fa2"))), 3.75);
+        assert_eq!(
+            f64::from_bits(bus.read_double(0x80000010).expect("read")),
+            3.75
+        );
+    }
+
+    #[test]
+    fn fp_convert() {
+        let rom = Rom::new(vec![
+            0xd3, 0x15, 0x26, 0xc2, // fcvt.l.d	a1,fa2,rtz
+            0xd3, 0xf6, 0x25, 0xd2, // fcvt.d.l	fa3,a1
+            0x53, 0xf7, 0xb6, 0x12, // fmul.d	fa4,fa3,fa1
+        ]);
+        let ram = Ram::new();
+        let bus = Bus::new(rom, ram);
+        let mut m = Hart::new(0, 0, Arc::new(bus));
+        m.set_fregister(fptreg("fa1"), 0.5f64.to_bits());
+        m.set_fregister(fptreg("fa2"), (-3.75f64).to_bits());
+        for _ in 0..3 {
+            m.tick().expect("tick");
+        }
+
+        assert_eq!(m.get_register(treg("a1")) as i64, -3);
+        assert_eq!(f64::from_bits(m.get_fregister(fptreg("fa3"))), -3.0);
+        assert_eq!(f64::from_bits(m.get_fregister(fptreg("fa4"))), -1.5);
+    }
+
+    #[test]
+    fn fp_dynamic_rounding_mode() {
+        // fcvt.l.d	a1,fa2 (dynamic rounding)
+        let ins = Instruction::IRV32(0xc22675d3);
+        let mut m = hart();
+        m.set_fregister(fptreg("fa2"), 2.5f64.to_bits());
+
+        // round down
+        m.set_csr(csr::FRM, 0b010);
+        let decoded = ins.decode().expect("decode").1;
+        m.execute_instruction(decoded, ins).expect("execute");
+        assert_eq!(m.get_register(treg("a1")), 2);
+
+        // round up
+        m.set_csr(csr::FRM, 0b011);
+        let decoded = ins.decode().expect("decode").1;
+        m.execute_instruction(decoded, ins).expect("execute");
+        assert_eq!(m.get_register(treg("a1")), 3);
+        assert_eq!(m.csr.read(csr::FCSR), 0b011 << 5);
+
+        // reserved
+        m.set_csr(csr::FRM, 0b101);
+        let decoded = ins.decode().expect("decode").1;
+        assert!(m.execute_instruction(decoded, ins).is_err());
+    }
+
+    #[test]
+    fn rdinstret() {
+        let rom = Rom::new(vec![
+            0x93, 0x85, 0x15, 0x00, // addi	a1,a1,1
+            0x93, 0x85, 0x15, 0x00, // addi	a1,a1,1
+            0x93, 0x85, 0x15, 0x00, // addi	a1,a1,1
+            0x73, 0x25, 0x20, 0xc0, // rdinstret	a0
+            0x73, 0x26, 0x00, 0xc0, // rdcycle	a2
+        ]);
+        let ram = Ram::new();
+        let bus = Bus::new(rom, ram);
+        let mut m = Hart::new(0, 0, Arc::new(bus));
+        for _ in 0..5 {
+            m.tick().expect("tick");
+        }
+
+        assert_eq!(m.get_register(treg("a1")), 3);
+        assert_eq!(m.get_register(treg("a0")), 3);
+        assert_eq!(m.get_register(treg("a2")), 4 * 3);
+    }
+
+    #[test]
+    fn rdtime() {
+        // rdtime	a3
+        let ins = Instruction::IRV32(0xc01026f3);
+        let mut bus = DynBus::new();
+        bus.map(Rtc::new(), 0x4000..0x4020);
+        let mut m = Hart::new(0, 0, Arc::new(bus));
+
+        let decoded = ins.decode().expect("decode").1;
+        m.execute_instruction(decoded, ins).expect("execute");
+        let first = m.get_register(treg("a3"));
+        let decoded = ins.decode().expect("decode").1;
+        m.execute_instruction(decoded, ins).expect("execute");
+
+        assert!(m.get_register(treg("a3")) > first);
+    }
+
+    // Counts how often the time is read
+    struct ReadCounter(AtomicU64);
+
+    impl Clock for ReadCounter {
+        fn now(&self) -> u64 {
+            self.0.fetch_add(1, Ordering::SeqCst)
+        }
+    }
+
+    #[test]
+    fn csr_read_side_effects() {
+        let clock = Arc::new(ReadCounter(AtomicU64::new(0)));
+        let mut bus = DynBus::new();
+        bus.map(Rtc::with_clock(clock.clone()), 0x4000..0x4020);
+        let mut m = Hart::new(0, 0, Arc::new(bus));
+        let mut execute = |ins| {
+            let ins = Instruction::IRV32(ins);
+            let decoded = ins.decode().expect("decode").1;
+            m.execute_instruction(decoded, ins)
+        };
+
+        // csrrs	a0,time,zero
+        execute(0xc0102573).expect("read");
+        assert_eq!(clock.0.load(Ordering::SeqCst), 1);
+
+        // csrrw	zero,time,a0
+        assert!(matches!(execute(0xc0151073), Err(Fault::IllegalOpcode(_))));
+        // csrrwi	zero,cycle,1
+        assert!(matches!(execute(0xc000d073), Err(Fault::IllegalOpcode(_))));
+        // csrrw	zero,mscratch,a1
+        execute(0x34059073).expect("write");
+        assert_eq!(clock.0.load(Ordering::SeqCst), 1, "no further reads");
+    }
+
+    #[test]
+    fn csr_swap_same_register() {
+        let mut m = hart();
+        let execute = |m: &mut Hart<Bus>, ins| {
+            let ins = Instruction::IRV32(ins);
+            let decoded = ins.decode().expect("decode").1;
+            m.execute_instruction(decoded, ins).expect("execute");
+        };
+        m.set_csr(csr::MSCRATCH, 0x1111);
+        m.set_register(treg("a0"), 0x2222);
+
+        // csrrw	a0,mscratch,a0
+        execute(&mut m, 0x34051573);
+        assert_eq!(m.get_register(treg("a0")), 0x1111);
+        assert_eq!(m.get_csr(csr::MSCRATCH), 0x2222);
+
+        // csrrs	a0,mscratch,a0
+        m.set_register(treg("a0"), 0x0101);
+        execute(&mut m, 0x34052573);
+        assert_eq!(m.get_register(treg("a0")), 0x2222);
+        assert_eq!(m.get_csr(csr::MSCRATCH), 0x2323);
+
+        // csrrc	a0,mscratch,a0
+        m.set_register(treg("a0"), 0x0300);
+        execute(&mut m, 0x34053573);
+        assert_eq!(m.get_register(treg("a0")), 0x2323);
+        assert_eq!(m.get_csr(csr::MSCRATCH), 0x2023);
+    }
+
+    #[test]
+    fn idle_interrupt_sources() {
+        let clock = Arc::new(ReadCounter(AtomicU64::new(0)));
+        let mut bus = DynBus::new();
+        bus.map(Rtc::with_clock(clock.clone()), 0x4000..0x4020);
+        bus.write_double(rtc::MTIMECMP_ADDR, 0).expect("mtimecmp");
+        let mut m = Hart::new(0, 0, Arc::new(bus));
+
+        // nothing enabled, the timer is not polled
+        assert_eq!(m.pending_interrupt(), None);
+        assert_eq!(clock.0.load(Ordering::SeqCst), 0);
+
+        // csrrs	a0,mip,zero
+        let ins = Instruction::IRV32(0x34402573);
+        let decoded = ins.decode().expect("decode").1;
+        m.execute_instruction(decoded, ins).expect("execute");
+        assert_eq!(clock.0.load(Ordering::SeqCst), 1, "mtime read once");
+        assert_ne!(m.get_register(treg("a0")) & (1 << clint::MTIP), 0);
+    }
+
+    #[test]
+    fn interrupt_entry() {
+        let mut m = hart();
+        m.pc = 0x80000004;
+        m.set_csr(csr::MTVEC, 0x80000100);
+        m.set_csr(csr::MSTATUS, csr::MSTATUS_MIE);
+        m.set_csr(csr::MIE, 1 << clint::MSIP);
+        m.set_csr(csr::MIP, 1 << clint::MSIP);
+
+        m.tick().expect("tick");
+
+        assert_eq!(m.get_pc(), 0x80000100);
+        assert_eq!(m.get_csr(csr::MEPC), 0x80000004);
+        assert_eq!(m.get_csr(csr::MCAUSE), csr::MCAUSE_INTERRUPT | clint::MSIP);
+        let mstatus = m.get_csr(csr::MSTATUS);
+        assert_eq!(mstatus & csr::MSTATUS_MIE, 0);
+        assert_eq!(mstatus & csr::MSTATUS_MPIE, csr::MSTATUS_MPIE);
+
+        // mret
+        let ins = Instruction::IRV32(0x30200073);
+        let decoded = ins.decode().expect("decode").1;
+        m.execute_instruction(decoded, ins).expect("execute");
+
+        assert_eq!(m.get_pc(), 0x80000004);
+        assert_eq!(m.get_csr(csr::MSTATUS) & csr::MSTATUS_MIE, csr::MSTATUS_MIE);
+    }
+
+    #[test]
+    fn interrupt_masked() {
+        let mut m = hart();
+        m.pc = 0x80000004;
+        m.set_csr(csr::MTVEC, 0x80000100);
+        m.set_csr(csr::MIE, 1 << clint::MSIP);
+        m.set_csr(csr::MIP, 1 << clint::MSIP);
+        m.bus.write_word(0x80000004, 0x00000013).expect("nop");
+
+        m.tick().expect("tick");
+
+        assert_eq!(m.get_pc(), 0x80000008);
+    }
+
+    #[test]
+    fn interrupt_vectored() {
+        let mut m = hart();
+        m.pc = 0x80000004;
+        m.set_csr(csr::MTVEC, 0x80000100 | 0b01);
+        m.set_csr(csr::MSTATUS, csr::MSTATUS_MIE);
+        m.set_csr(csr::MIE, 1 << clint::MSIP);
+        m.set_csr(csr::MIP, 1 << clint::MSIP);
+
+        m.tick().expect("tick");
+
+        assert_eq!(m.get_pc(), 0x80000100 + 4 * clint::MSIP as usize);
+    }
+
+    #[test]
+    fn misaligned() {
+        // (instruction, address, is a load)
+        let cases = [
+            (0x00059503, 0x80000001, true),  // lh	a0,0(a1)
+            (0x0005a503, 0x80000002, true),  // lw	a0,0(a1)
+            (0x0005b503, 0x80000004, true),  // ld	a0,0(a1)
+            (0x0005d503, 0x80000003, true),  // lhu	a0,0(a1)
+            (0x0005e503, 0x80000006, true),  // lwu	a0,0(a1)
+            (0x00a59023, 0x80000001, false), // sh	a0,0(a1)
+            (0x00a5a023, 0x80000002, false), // sw	a0,0(a1)
+            (0x00a5b023, 0x80000004, false), // sd	a0,0(a1)
+        ];
+
+        for (ins, addr, load) in cases {
+            let ins = Instruction::IRV32(ins);
+            let mut m = hart();
+            m.set_register(treg("a1"), addr);
+
+            // permitted by default
+            let decoded = ins.decode().expect("decode").1;
+            m.execute_instruction(decoded, ins).expect("execute");
+
+            m.set_strict_alignment(true);
+            let decoded = ins.decode().expect("decode").1;
+            match (load, m.execute_instruction(decoded, ins)) {
+                (true, Err(Fault::MisalignedLoad(a))) | (false, Err(Fault::MisalignedStore(a))) => {
+                    assert_eq!(a, addr as usize)
+                }
+                (_, res) => panic!("{:?}: {:?}", ins, res),
+            }
+        }
+    }
+
+    #[test]
+    fn faults_trap() {
+        let rom = Rom::new(vec![
+            0x03, 0xa5, 0x05, 0x00, // lw	a0,0(a1)
+            0x23, 0x20, 0xa0, 0x00, // sw	a0,0(zero)
+        ]);
+        let bus = Bus::new(rom, Ram::new());
+        let mut m = Hart::new(0, 0, Arc::new(bus));
+        m.set_csr(csr::MTVEC, 0x80000000);
+        m.set_strict_alignment(true);
+        m.set_register(treg("a1"), 0x80000002);
+
+        m.tick().expect("trap");
+        assert_eq!(m.get_pc(), 0x80000000);
+        assert_eq!(m.get_csr(csr::MCAUSE), 4, "load address misaligned");
+        assert_eq!(m.get_csr(csr::MEPC), 0);
+        assert_eq!(m.get_csr(csr::MTVAL), 0x80000002);
+
+        // stores to address 0 are not ignored
+        m.set_pc(4);
+        m.tick().expect("trap");
+        assert_eq!(m.get_csr(csr::MCAUSE), 7, "store access fault");
+        assert_eq!(m.get_csr(csr::MEPC), 4);
+        assert_eq!(m.get_csr(csr::MTVAL), 0);
+
+        m.set_pc(0x40000000);
+        m.tick().expect("trap");
+        assert_eq!(m.get_csr(csr::MCAUSE), 1, "instruction access fault");
+        assert_eq!(m.get_csr(csr::MEPC), 0x40000000);
+        assert_eq!(m.get_csr(csr::MTVAL), 0x40000000);
+    }
+
+    #[test]
+    fn misaligned_amo() {
+        // amoadd.w a0,a1,(a2)
+        let ins = Instruction::IRV32(0x00b6252f);
+        let mut m = hart();
+        m.set_register(treg("a2"), 0x80000002);
+
+        let decoded = ins.decode().expect("decode").1;
+        assert!(matches!(
+            m.execute_instruction(decoded, ins),
+            Err(Fault::MisalignedStore(0x80000002))
+        ));
+
+        // lr.w a0,(a2) / lr.d a0,(a2)
+        for ins in [0x1006252f, 0x1006352f] {
+            let ins = Instruction::IRV32(ins);
+            let decoded = ins.decode().expect("decode").1;
+            assert!(matches!(
+                m.execute_instruction(decoded, ins),
+                Err(Fault::MisalignedLoad(0x80000002))
+            ));
+        }
+    }
+
+    #[test]
+    fn lr_sc() {
+        let bus = Arc::new(Bus::new(Rom::new(vec![]), Ram::new()));
+        let mut m0 = Hart::new(0, 0, bus.clone());
+        let mut m1 = Hart::new(1, 0, bus.clone());
+        let execute = |m: &mut Hart<Bus>, ins| {
+            let ins = Instruction::IRV32(ins);
+            let decoded = ins.decode().expect("decode").1;
+            m.execute_instruction(decoded, ins).expect("execute");
+            m.get_register(treg("a0"))
+        };
+        for m in [&mut m0, &mut m1] {
+            m.set_register(treg("a2"), 0x80000000);
+        }
+        bus.write_word(0x80000000, 7).expect("write");
+
+        // sc.w a0,a1,(a2) fails without a reservation
+        m0.set_register(treg("a1"), 1);
+        assert_eq!(execute(&mut m0, 0x18b6252f), 1);
+        assert_eq!(bus.read_word(0x80000000).expect("read"), 7);
+
+        // lr.w a0,(a2), then sc.w a0,a1,(a2)
+        assert_eq!(execute(&mut m0, 0x1006252f), 7);
+        assert_eq!(execute(&mut m0, 0x18b6252f), 0);
+        assert_eq!(bus.read_word(0x80000000).expect("read"), 1);
+        assert_eq!(execute(&mut m0, 0x18b6252f), 1, "reservation consumed");
+
+        // another hart updating the word in between breaks the reservation
+        m1.set_register(treg("a1"), 2);
+        execute(&mut m0, 0x1006252f);
+        execute(&mut m1, 0x1006252f);
+        assert_eq!(execute(&mut m1, 0x18b6252f), 0);
+        m0.set_register(treg("a1"), 3);
+        assert_eq!(execute(&mut m0, 0x18b6252f), 1);
+        assert_eq!(bus.read_word(0x80000000).expect("read"), 2);
+
+        // lr.d a0,(a2), then sc.d a0,a1,(a2)
+        assert_eq!(execute(&mut m0, 0x1006352f), 2);
+        assert_eq!(execute(&mut m0, 0x18b6352f), 0);
+        assert_eq!(bus.read_double(0x80000000).expect("read"), 3);
+    }
+
+    #[test]
+    fn dbgins_lazy() {
+        // Tests log at most at debug level, see `capture_log`, so the disassembly is never
+        // formatted
+        assert!(!log::log_enabled!(Level::Trace));
+        let m = hart();
+        let formatted = Cell::new(false);
+        m.dbgins(Instruction::IRV32(0x00000013), || {
+            formatted.set(true);
+            "nop".to_string()
+        });
+
+        assert!(!formatted.get());
+    }
+
+    #[test]
+    fn reset_csrs() {
+        let bus = DynBus::new();
+        let mut m = Hart::new(3, 0, Arc::new(bus));
+        let misa = m.get_csr(csr::MISA);
+
+        m.set_csr(csr::MISA, 0);
+        m.set_csr(csr::MSTATUS, csr::MSTATUS_MIE);
+        m.set_csr(csr::MEPC, 0x1000);
+        m.reset();
+
+        assert_eq!(m.get_csr(csr::MISA), misa);
+        assert_eq!(m.get_csr(csr::MHARTID), 3);
+        assert_eq!(m.get_csr(csr::MSTATUS), 0);
+        assert_eq!(m.get_csr(csr::MEPC), 0);
+    }
+
+    #[test]
+    fn fence_i() {
+        let mut bus = DynBus::new();
+        bus.map(Ram::sized(0x2000), 0x80000000..0x80002000);
+        let bus = Arc::new(bus);
+        bus.write_bytes(
+            0x80000000,
+            &[
+                0x23, 0x20, 0xb5, 0x00, // sw	a1,0(a0)
+                0x0f, 0x10, 0x00, 0x00, // fence.i
+            ],
+        )
+        .expect("ram");
+        bus.write_bytes(0x80001000, &[0x13, 0x06, 0x10, 0x00]) // li	a2,1
+            .expect("ram");
+        let mut m = Hart::new(0, 0x80000000, bus.clone());
+
+        m.set_pc(0x80001000);
+        m.tick().expect("tick");
+        assert_eq!(m.get_register(treg("a2")), 1);
+
+        // overwrite with li a2,42 from another page
+        m.set_register(treg("a0"), 0x80001000);
+        m.set_register(treg("a1"), 0x02a00613);
+        m.set_pc(0x80000000);
+        m.tick().expect("sw");
+
+        m.set_pc(0x80000004);
+        m.tick().expect("fence.i");
+        m.set_pc(0x80001000);
+        m.tick().expect("tick");
+        assert_eq!(m.get_register(treg("a2")), 42);
+    }
+
+    fn count_down(m: &mut Hart<DynBus>) {
+        m.bus
+            .write_bytes(
+                0x80000000,
+                &[
+                    0x13, 0x05, 0x80, 0x3e, // li	a0,1000
+                    0x93, 0x85, 0x15, 0x00, // addi	a1,a1,1
+                    0x13, 0x05, 0xf5, 0xff, // addi	a0,a0,-1
+                    0xe3, 0x1c, 0x05, 0xfe, // bnez	a0,-8
+                ],
+            )
+            .expect("ram");
+    }
+
+    #[test]
+    fn icache_hits() {
+        let mut bus = DynBus::new();
+        bus.map(Ram::sized(0x1000), 0x80000000..0x80001000);
+        let mut m = Hart::new(0, 0x80000000, Arc::new(bus));
+        count_down(&mut m);
+
+        for _ in 0..3001 {
+            m.tick().expect("tick");
+        }
+
+        assert_eq!(m.get_register(treg("a1")), 1000);
+        assert_eq!(m.icache_misses(), 4);
+    }
+
+    #[test]
+    fn icache_matches_decode() {
+        let mut bus = DynBus::new();
+        bus.map(Ram::sized(0x1000), 0x80000000..0x80001000);
+        let bus = Arc::new(bus);
+        let mut cached = Hart::new(0, 0x80000000, bus.clone());
+        let mut uncached = Hart::new(0, 0x80000000, bus.clone());
+        count_down(&mut cached);
+
+        for _ in 0..3001 {
+            cached.tick().expect("tick");
+            uncached.flush_icache();
+            uncached.tick().expect("tick");
+            assert_eq!(cached.get_pc(), uncached.get_pc());
+            assert_eq!(cached.registers, uncached.registers);
+        }
+        assert_eq!(uncached.icache_misses(), 3001);
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let ram = Arc::new(Ram::sized(0x1000));
+        ram.write(
+            0,
+            vec![
+                0x17, 0x06, 0x00, 0x00, // auipc	a2,0
+                0x93, 0x85, 0x15, 0x00, // addi	a1,a1,1
+                0x23, 0x30, 0xb6, 0x10, // sd	a1,256(a2)
+                0x6f, 0xf0, 0x9f, 0xff, // j	-8
+            ],
+        );
+        let mut bus = DynBus::new();
+        bus.map(ram.clone(), 0x80000000..0x80001000);
+        let mut m = Hart::new(0, 0x80000000, Arc::new(bus));
+
+        for _ in 0..10 {
+            m.tick().expect("tick");
+        }
+        let state = m.snapshot();
+        let memory = ram.snapshot();
+
+        for _ in 0..100 {
+            m.tick().expect("tick");
+        }
+        let first = (m.snapshot(), ram.read_double(0x100).expect("ram"));
+
+        m.restore(state);
+        ram.restore(memory).expect("restore");
+        assert_eq!(ram.read_double(0x100).expect("ram"), 3);
+
+        for _ in 0..100 {
+            m.tick().expect("tick");
+        }
+        assert_eq!(m.registers, first.0.registers);
+        assert_eq!(m.get_pc(), first.0.pc);
+        assert_eq!(m.get_csr(csr::MCYCLE), first.0.csr.read(csr::MCYCLE));
+        assert_eq!(ram.read_double(0x100).expect("ram"), first.1);
+
+        // a snapshot between lr.d a0,(a2) and sc.d a0,a1,(a2) replays the successful sc.d
+        let execute = |m: &mut Hart<DynBus>, ins| {
+            let ins = Instruction::IRV32(ins);
+            let decoded = ins.decode().expect("decode").1;
+            m.execute_instruction(decoded, ins).expect("execute");
+            m.get_register(treg("a0"))
+        };
+        execute(&mut m, 0x1006352f);
+        let (state, memory) = (m.snapshot(), ram.snapshot());
+        assert_eq!(execute(&mut m, 0x18b6352f), 0);
+        m.restore(state);
+        ram.restore(memory).expect("restore");
+        assert_eq!(execute(&mut m, 0x18b6352f), 0);
+    }
+
+    #[test]
+    fn pmp_read_only() {
+        let ram = Arc::new(Ram::sized(0x1000));
+        ram.write(
+            0,
+            vec![
+                0x17, 0x06, 0x00, 0x00, // auipc	a2,0
+                0x83, 0x35, 0x06, 0x10, // ld	a1,256(a2)
+                0x23, 0x30, 0xb6, 0x10, // sd	a1,256(a2)
+            ],
+        );
+        let mut bus = DynBus::new();
+        bus.map(ram, 0x80000000..0x80001000);
+        let mut m = Hart::new(0, 0x80000000, Arc::new(bus));
+
+        // a locked, read-only NAPOT region of 256 bytes at 0x80000100
+        m.set_csr(csr::PMPADDR0, (0x80000100 >> 2) | 0b11111);
+        m.set_csr(csr::PMPCFG0, 0b1001_1001);
+
+        m.set_csr(csr::MTVEC, 0x80000800);
+
+        m.tick().expect("auipc");
+        m.tick().expect("load is allowed");
+        m.tick().expect("store traps");
+        assert_eq!(m.get_pc(), 0x80000800);
+        assert_eq!(m.get_csr(csr::MCAUSE), 7, "store access fault");
+        assert_eq!(m.get_csr(csr::MEPC), 0x80000008);
+        assert_eq!(m.get_csr(csr::MTVAL), 0x80000100);
+    }
+
+    #[test]
+    fn unsigned_loads_negative_offset() {
+        let rom = Rom::new(vec![
+            0x03, 0xd5, 0x85, 0xff, // lhu	a0,-8(a1)
+            0x03, 0xe6, 0xc5, 0xff, // lwu	a2,-4(a1)
+        ]);
+        let bus = Bus::new(rom, Ram::new());
+        let mut m = Hart::new(0, 0, Arc::new(bus));
+        m.bus
+            .write_double(0x80000100, 0x8765_4321_0000_fedc)
+            .expect("ram");
+        m.set_register(treg("a1"), 0x80000108);
+        for _ in 0..2 {
+            m.tick().expect("tick");
+        }
+
+        assert_eq!(m.get_register(treg("a0")), 0xfedc);
+        assert_eq!(m.get_register(treg("a2")), 0x8765_4321);
+    }
+
+    #[test]
+    fn slti_negative() {
+        let rom = Rom::new(vec![
+            0x13, 0xa5, 0xf5, 0xff, // slti	a0,a1,-1
+            0x13, 0xb6, 0xf5, 0xff, // sltiu	a2,a1,-1
+            0x93, 0x26, 0xf0, 0xff, // slti	a3,zero,-1
+            0x13, 0xb7, 0xf7, 0xff, // sltiu	a4,a5,-1
+        ]);
+        let bus = Bus::new(rom, Ram::new());
+        let mut m = Hart::new(0, 0, Arc::new(bus));
+        m.set_register(treg("a1"), -2i64 as u64);
+        m.set_register(treg("a5"), u64::MAX);
+        for _ in 0..4 {
+            m.tick().expect("tick");
+        }
+
+        assert_eq!(m.get_register(treg("a0")), 1, "-2 < -1");
+        assert_eq!(m.get_register(treg("a2")), 1, "0xff..fe < 0xff..ff");
+        assert_eq!(m.get_register(treg("a3")), 0, "0 > -1");
+        assert_eq!(
+            m.get_register(treg("a4")),
+            0,
+            "0xff..ff is not below itself"
+        );
+    }
+
+    #[test]
+    fn clui() {
+        let rom = Rom::new(vec![
+            0x7d, 0x75, // c.lui	a0,0xfffff
+            0xfd, 0x65, // c.lui	a1,0x1f
+            0x01, 0x00, // c.nop
+        ]);
+        let bus = Bus::new(rom, Ram::new());
+        let mut m = Hart::new(0, 0, Arc::new(bus));
+        for _ in 0..2 {
+            m.tick().expect("tick");
+        }
+
+        assert_eq!(m.get_register(treg("a0")), 0xffff_ffff_ffff_f000);
+        assert_eq!(m.get_register(treg("a1")), 0x1f000);
+    }
+
+    #[test]
+    fn rv32_compressed_call() {
+        let rom = Rom::new(vec![
+            0x01, 0x00, // nop
+            0x11, 0x20, // jal	6
+            0x01, 0x00, // nop
+            0x01, 0x00, // nop
+        ]);
+        let bus = Bus::new(rom, Ram::new());
+        let mut m = Hart::with_xlen(0, 0, Arc::new(bus), Xlen::Rv32);
+
+        m.tick().expect("tick");
+        m.tick().expect("tick");
+        assert_eq!(m.get_pc(), 6);
+        assert_eq!(m.get_register(treg("ra")), 4);
+    }
+
+    #[test]
+    fn rv32_multiply_divide() {
+        let bus = Bus::new(Rom::new(vec![]), Ram::new());
+        let mut m = Hart::with_xlen(0, 0, Arc::new(bus), Xlen::Rv32);
+        let cases: [(u32, i32, i32, i32); 11] = [
+            (0x02b54633, -7, 2, -3),                   // div	a2,a0,a1
+            (0x02b56633, -7, 2, -1),                   // rem	a2,a0,a1
+            (0x02b57633, -7, 2, 1),                    // remu	a2,a0,a1
+            (0x02b55633, -7, 2, 0x7ffffffc),           // divu	a2,a0,a1
+            (0x02b54633, 5, 0, -1),                    // div	a2,a0,a1
+            (0x02b56633, -5, 0, -5),                   // rem	a2,a0,a1
+            (0x02b54633, i32::MIN, -1, i32::MIN),      // div	a2,a0,a1
+            (0x02b56633, i32::MIN, -1, 0),             // rem	a2,a0,a1
+            (0x02b51633, -7, 2, -1),                   // mulh	a2,a0,a1
+            (0x02b51633, i32::MIN, i32::MIN, 1 << 30), // mulh	a2,a0,a1
+            (0x02b53633, -1, -1, -2),                  // mulhu	a2,a0,a1
+        ];
+
+        for (ins, a, b, expected) in cases {
+            m.set_register(treg("a0"), a as u64);
+            m.set_register(treg("a1"), b as u64);
+            let ins = Instruction::IRV32(ins);
+            let decoded = ins.decode().expect("decode").1;
+            m.execute_instruction(decoded, ins).expect("execute");
+            assert_eq!(
+                m.get_register(treg("a2")),
+                expected as i64 as u64,
+                "{:?} {} {}",
+                ins,
+                a,
+                b
+            );
+        }
+    }
+
+    #[test]
+    fn word_divide() {
+        let mut m = hart();
+        let cases: [(u32, i32, i32, i32); 5] = [
+            (0x02b5463b, -7, 2, -3),              // divw	a2,a0,a1
+            (0x02b5663b, -7, 2, -1),              // remw	a2,a0,a1
+            (0x02b5763b, -7, 2, 1),               // remuw	a2,a0,a1
+            (0x02b5463b, i32::MIN, -1, i32::MIN), // divw	a2,a0,a1
+            (0x02b5663b, i32::MIN, -1, 0),        // remw	a2,a0,a1
+        ];
+
+        for (ins, a, b, expected) in cases {
+            m.set_register(treg("a0"), a as u64);
+            m.set_register(treg("a1"), b as u64);
+            let ins = Instruction::IRV32(ins);
+            let decoded = ins.decode().expect("decode").1;
+            m.execute_instruction(decoded, ins).expect("execute");
+            assert_eq!(
+                m.get_register(treg("a2")),
+                expected as i64 as u64,
+                "{:?}",
+                ins
+            );
+        }
+    }
+
+    #[test]
+    fn rv32() {
+        let rom = Rom::new(vec![
+            0x13, 0x05, 0x10, 0x00, // addi	a0,zero,1
+            0x93, 0x05, 0x10, 0x02, // addi	a1,zero,33
+            0x33, 0x16, 0xb5, 0x00, // sll	a2,a0,a1
+            0xb7, 0x06, 0x00, 0x80, // lui	a3,0x80000
+            0x13, 0xd7, 0x46, 0x00, // srli	a4,a3,0x4
+            0xbb, 0x07, 0xb5, 0x00, // addw	a5,a0,a1
+        ]);
+        let bus = Bus::new(rom, Ram::new());
+        let mut m = Hart::with_xlen(0, 0, Arc::new(bus), Xlen::Rv32);
+        assert_eq!(m.get_csr(csr::MISA) >> 30, 0b01);
+
+        for _ in 0..5 {
+            m.tick().expect("tick");
+        }
+        assert_eq!(
+            m.get_register(treg("a2")),
+            0b10,
+            "shift amount wraps at 5 bits"
+        );
+        assert_eq!(m.get_register(treg("a3")) as u32, 0x80000000);
+        assert_eq!(
+            m.get_register(treg("a4")),
+            0x08000000,
+            "logical shift of 32 bits"
+        );
+
+        m.tick().expect("addw traps");
+        assert_eq!(m.get_csr(csr::MCAUSE), 2, "illegal instruction");
+        assert_eq!(m.get_csr(csr::MEPC), 20);
+    }
+
+    #[test]
+    fn x0_writes_ignored() {
+        let rom = Rom::new(vec![
+            0x13, 0x00, 0x50, 0x00, // addi	zero,zero,5
+            0x37, 0x50, 0x34, 0x12, // lui	zero,0x12345
+            0x17, 0x10, 0x00, 0x00, // auipc	zero,0x1
+            0x33, 0x80, 0xb5, 0x00, // add	zero,a1,a1
+            0x33, 0x80, 0xb5, 0x02, // mul	zero,a1,a1
+            0x1b, 0x80, 0x15, 0x00, // addiw	zero,a1,1
+            0x03, 0x20, 0x06, 0x00, // lw	zero,0(a2)
+            0x2f, 0x20, 0xb6, 0x08, // amoswap.w	zero,a1,(a2)
+            0x2f, 0x30, 0xb6, 0x00, // amoadd.d	zero,a1,(a2)
+            0x73, 0x20, 0x00, 0x34, // csrrs	zero,mscratch,zero
+            0x53, 0x00, 0x05, 0xe2, // fmv.x.d	zero,fa0
+            0x6f, 0x00, 0x40, 0x00, // jal	zero,4
+            0x67, 0x80, 0x46, 0x00, // jalr	zero,4(a3)
+            0x2f, 0x25, 0xb6, 0x08, // amoswap.w	a0,a1,(a2)
+        ]);
+        let bus = Bus::new(rom, Ram::new());
+        let mut m = Hart::new(0, 0, Arc::new(bus));
+        m.set_register(treg("a1"), 0x7);
+        m.set_register(treg("a2"), 0x80000800);
+        m.set_register(treg("a3"), 0x30);
+        m.set_fregister(fptreg("fa0"), 0x3ff0000000000000);
+        m.set_csr(csr::MSCRATCH, 0x1234);
+        m.bus.write_word(0x80000800, 0x55).expect("ram");
+
+        for _ in 0..13 {
+            m.tick().expect("tick");
+            assert_eq!(m.get_register(0), 0, "{:#x}", m.get_pc());
+        }
+        assert_eq!(m.get_pc(), 0x34);
+
+        // amoswap writes the old value to rd, keeping rs2
+        m.tick().expect("tick");
+        assert_eq!(m.get_register(treg("a0")), 0xe);
+        assert_eq!(m.get_register(treg("a1")), 0x7);
+        assert_eq!(m.bus.read_word(0x80000800).expect("ram"), 0x7);
+    }
+
+    #[test]
+    fn run_budget() {
+        let rom = Rom::new(vec![
+            0x13, 0x05, 0x15, 0x00, // addi	a0,a0,1
+            0x6f, 0xf0, 0xdf, 0xff, // j	0
+        ]);
+        let bus = Bus::new(rom, Ram::new());
+        let mut m = Hart::new(0, 0, Arc::new(bus));
+
+        let summary = m.run(Some(1000));
+        assert!(matches!(summary.exit, Exit::Budget));
+        assert_eq!(summary.instructions_retired, 1000);
+        assert_eq!(m.get_register(treg("a0")), 500);
+
+        m.stop();
+        let summary = m.run(None);
+        assert!(matches!(summary.exit, Exit::Fault(Fault::Halt)));
+        assert_eq!(summary.instructions_retired, 0);
+    }
+
+    #[test]
+    fn long_instructions() {
+        let rom = Rom::new(vec![
+            0x1f, 0x00, 0x00, 0x00, 0x00, 0x00, // 48-bit
+            0x3f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 64-bit
+        ]);
+        let bus = Bus::new(rom, Ram::new());
+        let mut m = Hart::new(0, 0, Arc::new(bus));
+
+        m.tick().expect("trap");
+        assert_eq!(m.get_csr(csr::MCAUSE), 2, "illegal instruction");
+        assert_eq!(
+            m.get_csr(csr::MEPC),
+            0,
+            "not skipped as a shorter instruction"
+        );
+
+        m.set_pc(6);
+        m.tick().expect("trap");
+        assert_eq!(m.get_csr(csr::MCAUSE), 2, "illegal instruction");
+        assert_eq!(m.get_csr(csr::MEPC), 6);
+    }
+
+    #[test]
+    fn bulk_registers() {
+        let mut m = hart();
+        let registers: [u64; 32] = std::array::from_fn(|i| 0x100 + i as u64);
+        m.set_registers(registers);
+
+        let read = m.get_registers();
+        assert_eq!(read[0], 0, "x0 is hardwired");
+        assert_eq!(read[1..], registers[1..]);
+        assert_eq!(m.get_register(31), 0x11f);
+    }
+
+    #[test]
+    fn ecall_dispatch() {
+        let ram = Arc::new(Ram::sized(0x1000));
+        ram.write(
+            0,
+            vec![
+                0x93, 0x08, 0x00, 0x01, // li	a7,16
+                0x13, 0x08, 0x30, 0x00, // li	a6,3
+                0x13, 0x05, 0x00, 0x01, // li	a0,16
+                0x73, 0x00, 0x00, 0x00, // ecall
+            ],
+        );
+        let mut bus = DynBus::new();
+        bus.map(ram, 0x80000000..0x80001000);
+        let bus = Arc::new(bus);
+
+        // probing the base extension through the emulated SBI
+        let mut m = Hart::new(0, 0x80000000, bus.clone());
+        for _ in 0..4 {
+            m.tick().expect("tick");
+        }
+        assert_eq!(m.get_register(treg("a0")), 0);
+        assert_eq!(m.get_register(treg("a1")), 1);
+        assert_eq!(m.get_pc(), 0x80000010);
+
+        // trapping into the guest's own handler
+        let mut m = Hart::new(0, 0x80000000, bus);
+        m.set_emulated_sbi(false);
+        m.set_csr(csr::MTVEC, 0x80000800);
+        for _ in 0..4 {
+            m.tick().expect("tick");
+        }
+        assert_eq!(m.get_register(treg("a0")), 16);
+        assert_eq!(m.get_csr(csr::MCAUSE), 11);
+        assert_eq!(m.get_csr(csr::MEPC), 0x8000000c);
+        assert_eq!(m.get_pc(), 0x80000800);
+    }
+
+    // Collects the debug messages of the hart, shared by all tests as there is only one logger
+    struct Capture(Mutex<Vec<String>>);
+
+    impl Log for Capture {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Debug && metadata.target() == "rriscv::hart"
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn capture_log() -> &'static Capture {
+        static CAPTURE: OnceLock<&'static Capture> = OnceLock::new();
+        CAPTURE.get_or_init(|| {
+            let capture = Box::leak(Box::new(Capture(Mutex::new(vec![]))));
+            log::set_logger(capture).expect("logger");
+            log::set_max_level(LevelFilter::Debug);
+            capture
+        })
+    }
+
+    #[test]
+    fn trap_logging() {
+        let log = capture_log();
+
+        let mut bus = DynBus::new();
+        let ram = Ram::sized(0x20);
+        ram.write(
+            0,
+            vec![
+                0x02, 0x80, // c.jr x0, reserved
+                0x01, 0x00, // c.nop
+                0x13, 0x00, 0x00, 0x00, // nop
+                0x73, 0x00, 0x20, 0x30, // mret
+            ],
+        );
+        bus.map(ram, 0x40000000..0x40000020);
+        let mut m = Hart::new(0, 0x40000000, Arc::new(bus));
+        m.set_emulated_sbi(false);
+        m.set_csr(csr::MTVEC, 0x40000008);
+        m.tick().expect("illegal instruction");
+        assert_eq!(m.get_csr(csr::MCAUSE), 2);
+        assert_eq!(m.get_csr(csr::MTVAL), 0x8002);
+        m.tick().expect("mret");
+
+        let events: Vec<String> = log
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|msg| msg.contains("epc 0x40000000"))
+            .cloned()
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "trap M -> M: cause 0x2, epc 0x40000000, tval 0x8002",
+                "mret M -> M: epc 0x40000000",
+            ]
+        );
+    }
+
+    #[test]
+    fn csr_by_name() {
+        let bus = Bus::new(Rom::new(vec![]), Ram::new());
+        let m = Hart::new(3, 0, Arc::new(bus));
+
+        assert_eq!(m.get_csr(csr::MHARTID), 3);
+        assert_eq!(m.get_csr_by_name("mhartid"), Some(3));
+        assert_eq!(m.get_csr_by_name("nonexistent"), None);
+    }
+
+    #[test]
+    fn backward_branch() {
+        let mut bus = DynBus::new();
+        let ram = Ram::sized(0x1000);
+        ram.write(
+            0,
+            vec![
+                0x13, 0x05, 0x30, 0x00, // li	a0,3
+                0x13, 0x05, 0xf5, 0xff, // addi	a0,a0,-1
+                0xe3, 0x1e, 0x05, 0xfe, // bnez	a0,-4
+            ],
+        );
+        bus.map(ram, 0x80000000..0x80001000);
+        let mut m = Hart::new(0, 0x80000000, Arc::new(bus));
+
+        for _ in 0..3 {
+            m.tick().expect("tick");
+        }
+        assert_eq!(m.get_pc(), 0x80000004, "taken backwards");
+        for _ in 0..4 {
+            m.tick().expect("tick");
+        }
+        assert_eq!(m.get_register(treg("a0")), 0);
+        assert_eq!(m.get_pc(), 0x8000000c, "falls through");
+    }
+
+    #[test]
+    fn jalr_high_address() {
+        let mut bus = DynBus::new();
+        let ram = Ram::sized(0x1000);
+        ram.write(
+            0,
+            vec![
+                0xe7, 0x80, 0x35, 0x00, // jalr	3(a1)
+            ],
+        );
+        bus.map(ram, 0x80000000..0x80001000);
+        let mut m = Hart::new(0, 0x80000000, Arc::new(bus));
+        m.set_register(treg("a1"), 0x1_0000_0010);
+
+        m.tick().expect("tick");
+        assert_eq!(m.get_pc(), 0x1_0000_0012);
+        assert_eq!(m.get_register(treg("ra")), 0x80000004);
+
+        // into a kernel mapped at the top of the address space
+        m.set_pc(0x80000000);
+        m.set_register(treg("a1"), 0xffff_ffff_7fff_fffe);
+        m.tick().expect("tick");
+        assert_eq!(m.get_pc(), 0xffff_ffff_8000_0000);
+    }
+
+    #[test]
+    fn inject_interrupt() {
+        let mut bus = DynBus::new();
+        bus.map(Ram::sized(0x1000), 0x80000000..0x80001000);
+        let mut m = Hart::new(0, 0x80000000, Arc::new(bus));
+        m.set_csr(csr::MTVEC, 0x80000100);
+
+        m.inject_interrupt(clint::MTIP);
+        assert_eq!(clint::interrupt(&m), None, "not enabled");
+
+        m.set_csr(csr::MIE, 1 << clint::MTIP);
+        m.set_csr(csr::MSTATUS, csr::MSTATUS_MIE);
+        assert!(m.interrupt_enabled(clint::MTIP));
+        assert_eq!(clint::interrupt(&m), Some(clint::MTIP));
+
+        m.tick().expect("tick");
+        assert_eq!(m.get_csr(csr::MCAUSE), csr::MCAUSE_INTERRUPT | clint::MTIP);
+        assert_eq!(m.get_pc(), 0x80000100);
+
+        m.clear_interrupt(clint::MTIP);
+        assert_eq!(clint::interrupt(&m), None);
+    }
+
+    #[test]
+    fn cycle_costs() {
+        let ram = Ram::sized(0x1000);
+        ram.write(
+            0,
+            vec![
+                0x13, 0x05, 0x50, 0x00, // li	a0,5
+                0x17, 0x06, 0x00, 0x00, // auipc	a2,0
+                0x83, 0x35, 0x06, 0x00, // ld	a1,0(a2)
+                0x13, 0x05, 0xf5, 0xff, // addi	a0,a0,-1
+                0xe3, 0x1c, 0x05, 0xfe, // bnez	a0,-8
+            ],
+        );
+        let mut bus = DynBus::new();
+        bus.map(ram, 0x80000000..0x80001000);
+        let mut m = Hart::new(0, 0x80000000, Arc::new(bus));
+        m.set_cycle_costs(CycleCosts {
+            alu: 0,
+            branch: 0,
+            load: 10,
+            ..CycleCosts::default()
+        });
+
+        for _ in 0..17 {
+            m.tick().expect("tick");
+        }
+        assert_eq!(m.get_register(treg("a0")), 0);
+        assert_eq!(m.get_csr(csr::MCYCLE), 5 * 10);
+    }
+
+    #[test]
+    fn run_until() {
+        let mut bus = DynBus::new();
+        bus.map(Ram::sized(0x1000), 0x80000000..0x80001000);
+        let mut m = Hart::new(0, 0x80000000, Arc::new(bus));
+        count_down(&mut m);
+
+        let steps = m
+            .run_until(10_000, |m| m.get_register(treg("a1")) == 10)
+            .expect("run");
+        assert_eq!(m.get_register(treg("a1")), 10);
+        assert_eq!(steps, 1 + 1 + 9 * 3);
+
+        let steps = m.run_until(5, |_| false).expect("run");
+        assert_eq!(steps, 5);
+    }
 }
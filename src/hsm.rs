@@ -0,0 +1,98 @@
+use std::sync::Mutex;
+
+// Hart states of the SBI Hart State Management extension, as returned by `hart_get_status`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HartStatus {
+    Started = 0,
+    Stopped = 1,
+    StartPending = 2,
+    StopPending = 3,
+}
+
+// What a hart should do on its next tick
+#[derive(Debug, PartialEq)]
+pub enum Poll {
+    Run,
+    Idle,
+    // Start executing at the address, with the opaque value for a1
+    Start(usize, u64),
+}
+
+struct HartState {
+    status: HartStatus,
+    start: (usize, u64),
+}
+
+// The state of all harts of a machine, shared between them to start and stop each other
+pub struct Hsm {
+    harts: Mutex<Vec<HartState>>,
+}
+
+impl Hsm {
+    // Only the boot hart 0 is started, all others wait for `hart_start`
+    pub fn new(harts: usize) -> Hsm {
+        let harts = (0..harts)
+            .map(|hartid| HartState {
+                status: if hartid == 0 {
+                    HartStatus::Started
+                } else {
+                    HartStatus::Stopped
+                },
+                start: (0, 0),
+            })
+            .collect();
+
+        Self {
+            harts: Mutex::new(harts),
+        }
+    }
+
+    // Number of harts of the machine
+    pub fn harts(&self) -> usize {
+        self.harts.lock().unwrap().len()
+    }
+
+    pub fn status(&self, hartid: usize) -> Option<HartStatus> {
+        let harts = self.harts.lock().unwrap();
+        harts.get(hartid).map(|hart| hart.status)
+    }
+
+    // Requests a stopped hart to start, returning its status if it was not stopped
+    pub fn start(&self, hartid: usize, addr: usize, opaque: u64) -> Result<(), Option<HartStatus>> {
+        let mut harts = self.harts.lock().unwrap();
+        let hart = harts.get_mut(hartid).ok_or(None)?;
+        if hart.status != HartStatus::Stopped {
+            return Err(Some(hart.status));
+        }
+        hart.status = HartStatus::StartPending;
+        hart.start = (addr, opaque);
+        Ok(())
+    }
+
+    pub fn stop(&self, hartid: usize) {
+        let mut harts = self.harts.lock().unwrap();
+        if let Some(hart) = harts.get_mut(hartid) {
+            hart.status = HartStatus::Stopped;
+        }
+    }
+
+    // Called by each hart before executing, completing pending transitions
+    pub fn poll(&self, hartid: usize) -> Poll {
+        let mut harts = self.harts.lock().unwrap();
+        let Some(hart) = harts.get_mut(hartid) else {
+            return Poll::Run;
+        };
+        match hart.status {
+            HartStatus::Started => Poll::Run,
+            HartStatus::Stopped => Poll::Idle,
+            HartStatus::StartPending => {
+                hart.status = HartStatus::Started;
+                Poll::Start(hart.start.0, hart.start.1)
+            }
+            HartStatus::StopPending => {
+                hart.status = HartStatus::Stopped;
+                Poll::Idle
+            }
+        }
+    }
+}
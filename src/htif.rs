@@ -1,12 +1,49 @@
+use std::io::{self, Write};
+use std::sync::RwLock;
+
 use crate::device::Device;
 use crate::plic::Fault;
-use crate::plic::Fault::{Halt, MemoryFault, Unaligned};
+use crate::plic::Fault::{Halt, MemoryFault, StoreFault, Unaligned};
 
-pub struct Htif {}
+// Host-Target Interface, as used by riscv-tests through the `tohost` symbol
+pub struct Htif {
+    exit_code: RwLock<Option<u64>>,
+}
 
 impl Htif {
     pub fn new() -> Htif {
-        Htif {}
+        Htif {
+            exit_code: RwLock::new(None),
+        }
+    }
+
+    // The code the target exited with, once it did
+    pub fn exit_code(&self) -> Option<u64> {
+        *self.exit_code.read().unwrap()
+    }
+
+    // The payload encodes device (8 bits), command (8 bits) and 48 bits of data
+    fn tohost(&self, val: u64) -> Result<(), Fault> {
+        let device = val >> 56;
+        let command = (val >> 48) & 0xFF;
+        let payload = val & 0xFFFF_FFFF_FFFF;
+
+        match (device, command) {
+            // syscall proxy, only used to signal exit with `(code << 1) | 1`
+            (0, _) if payload & 0b1 == 1 => {
+                let mut exit_code = self.exit_code.write().unwrap();
+                *exit_code = Some(payload >> 1);
+                Err(Halt)
+            }
+            // console putchar
+            (1, 1) => {
+                let mut handle = io::stdout().lock();
+                handle.write_all(&[payload as u8])?;
+                handle.flush()?;
+                Ok(())
+            }
+            _ => Err(Halt),
+        }
     }
 }
 
@@ -17,17 +54,19 @@ impl Default for Htif {
 }
 
 impl Device for Htif {
-    fn write_double(&self, addr: usize, _val: u64) -> Result<(), Fault> {
+    fn write_double(&self, addr: usize, val: u64) -> Result<(), Fault> {
         match addr {
-            0x0 => Err(Halt),
-            _ => Err(MemoryFault(addr)),
+            0x0 => self.tohost(val),
+            _ => Err(StoreFault(addr)),
         }
     }
 
-    fn write_word(&self, addr: usize, _val: u32) -> Result<(), Fault> {
+    fn write_word(&self, addr: usize, val: u32) -> Result<(), Fault> {
         match addr {
-            0x0 => Err(Halt),
-            _ => Err(MemoryFault(addr)),
+            // RV32 targets write the halves separately, exits only need the lower one
+            0x0 => self.tohost(val as u64),
+            0x4 => Ok(()),
+            _ => Err(StoreFault(addr)),
         }
     }
 
@@ -55,3 +94,32 @@ impl Device for Htif {
         Err(Unaligned(addr))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::device::Device;
+    use crate::htif::Htif;
+    use crate::plic::Fault;
+
+    #[test]
+    fn exit_code() {
+        let htif = Htif::new();
+        assert_eq!(htif.exit_code(), None);
+
+        assert!(matches!(
+            htif.write_double(0, (42 << 1) | 1),
+            Err(Fault::Halt)
+        ));
+        assert_eq!(htif.exit_code(), Some(42));
+    }
+
+    #[test]
+    fn putchar() {
+        let htif = Htif::new();
+
+        assert!(htif
+            .write_double(0, 1 << 56 | 1 << 48 | b'x' as u64)
+            .is_ok());
+        assert_eq!(htif.exit_code(), None);
+    }
+}
@@ -2,11 +2,11 @@ use log::trace;
 use std::fmt;
 use std::fmt::{Formatter, LowerHex};
 
-use crate::plic::Fault::{self, IllegalOpcode, InstructionDecodingError};
+use crate::plic::Fault::{self, IllegalOpcode};
 
 use self::InstructionFormat::{B, I, J, R, S, U};
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum InstructionFormat {
     R {
         opcode: u8,
@@ -115,7 +115,18 @@ impl fmt::Display for InstructionFormat {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+// Why an instruction could not be decoded, see `Instruction::decode_verbose`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodeError {
+    // Not part of any extension
+    UnknownOpcode,
+    // Reserved by the specification
+    ReservedEncoding,
+    // A valid instruction of an extension which is not implemented
+    Unimplemented,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Instruction {
     IRV32(u32),
     CRV32(u16),
@@ -139,17 +150,61 @@ impl Instruction {
     }
 
     pub fn decode(self) -> Result<(Instruction, InstructionFormat), Fault> {
+        self.decode_verbose().map_err(|_| IllegalOpcode(self))
+    }
+
+    // Like `decode`, but tells apart why decoding failed, e.g. for comparing against other
+    // decoders
+    pub fn decode_verbose(self) -> Result<(Instruction, InstructionFormat), DecodeError> {
         let res = match self {
             Instruction::IRV32(instruction) => Instruction::decode_32(instruction),
             Instruction::CRV32(instruction) => Instruction::decode_16(instruction),
         };
-        res.map(|d| (self, d)).map_err(|_| IllegalOpcode(self))
+        res.map(|d| (self, d))
     }
 
-    fn decode_32(instruction: u32) -> Result<InstructionFormat, Fault> {
+    // Like `decode`, but for RV32, where some compressed encodings are reused: c.jal instead of
+    // c.addiw, and the single precision c.flw/c.fsw/c.flwsp/c.fswsp instead of the doubleword
+    // loads and stores
+    pub fn decode_rv32(self) -> Result<(Instruction, InstructionFormat), Fault> {
+        let Instruction::CRV32(instruction) = self else {
+            return self.decode();
+        };
+        match (instruction & 0b11, instruction >> 13) {
+            // c.jal -> jal x1, offset
+            (0b01, 0b001) => Ok((
+                self,
+                J {
+                    opcode: 0b1101111,
+                    rd: 0x1,
+                    imm: Instruction::cj_offset(instruction),
+                },
+            )),
+            // c.flw, c.fsw, c.flwsp and c.fswsp, without single precision floating point
+            (0b00 | 0b10, 0b011 | 0b111) => Err(IllegalOpcode(self)),
+            _ => self.decode(),
+        }
+    }
+
+    // The jump target offset of c.j and c.jal
+    fn cj_offset(instruction: u16) -> i32 {
+        // imm[11|4|9:8|10|6|7|3:1|5]
+        let imm = (((instruction >> 12) & 0b1) << 15)
+            | (((instruction >> 11) & 0b1) << 8)
+            | (((instruction >> 9) & 0b11) << 12)
+            | (((instruction >> 8) & 0b1) << 14)
+            | (((instruction >> 7) & 0b1) << 10)
+            | (((instruction >> 6) & 0b1) << 11)
+            | (((instruction >> 3) & 0b111) << 5)
+            | (((instruction >> 2) & 0b1) << 9);
+        let imm = imm as i16 >> 5;
+        (2 * imm) as i32
+    }
+
+    fn decode_32(instruction: u32) -> Result<InstructionFormat, DecodeError> {
         let opcode = (instruction & 0b1111111) as u8;
         let decoded = match opcode {
-            0b0110011 | 0b0101111 | 0b0111011 => {
+            0b0110011 | 0b0101111 | 0b0111011 | 0b1010011 => {
                 let rd = ((instruction >> 7) & 0b11111) as u8;
                 let funct3 = ((instruction >> 12) & 0b111) as u8;
                 let rs1 = ((instruction >> 15) & 0b11111) as u8;
@@ -164,7 +219,7 @@ impl Instruction {
                     funct7,
                 }
             }
-            0b0010011 | 0b0000011 | 0b1100111 | 0b0001111 | 0b0011011 => {
+            0b0010011 | 0b0000011 | 0b1100111 | 0b0001111 | 0b0011011 | 0b0000111 => {
                 let rd = ((instruction & 0x0F80) >> 7) as u8;
                 let funct3 = ((instruction & 0x7000) >> 12) as u8;
                 let rs1 = ((instruction & 0xF8000) >> 15) as u8;
@@ -187,8 +242,8 @@ impl Instruction {
                     trace!("incompat?");
                 }
 
-                // ecall / ebreak / csr*
-                if funct3 == 0x0 && (imm == 0 || imm == 1)
+                // ecall / ebreak / mret / csr*
+                if funct3 == 0x0 && (imm == 0 || imm == 1 || imm == 0x302)
                     || funct3 == 0x1
                     || funct3 == 0x2
                     || funct3 == 0x3
@@ -216,7 +271,7 @@ impl Instruction {
                     }
                 }
             }
-            0b0100011 => {
+            0b0100011 | 0b0100111 => {
                 let funct3 = ((instruction >> 12) & 0b111) as u8;
                 let rs1 = ((instruction >> 15) & 0b11111) as u8;
                 let rs2 = ((instruction >> 20) & 0b11111) as u8;
@@ -264,15 +319,19 @@ impl Instruction {
                 let imm = ((instruction & 0xfffff800) as i32 as u64 >> 12) as i32;
                 U { opcode, rd, imm }
             }
+            // F/D fused multiply-add and V
+            0b1000011 | 0b1000111 | 0b1001011 | 0b1001111 | 0b1010111 => {
+                return Err(DecodeError::Unimplemented);
+            }
             _ => {
-                return Err(InstructionDecodingError);
+                return Err(DecodeError::UnknownOpcode);
             }
         };
 
         Ok(decoded)
     }
 
-    fn decode_16(instruction: u16) -> Result<InstructionFormat, Fault> {
+    fn decode_16(instruction: u16) -> Result<InstructionFormat, DecodeError> {
         const RVC_REG_OFFSET: u8 = 0x8;
 
         let op = instruction & 0b11;
@@ -359,8 +418,12 @@ impl Instruction {
                             imm: imm.overflowing_mul(4).0 as i16,
                         }
                     }
+                    // c.fld / c.fsd
+                    0b001 | 0b101 => {
+                        return Err(DecodeError::Unimplemented);
+                    }
                     _ => {
-                        return Err(InstructionDecodingError);
+                        return Err(DecodeError::ReservedEncoding);
                     }
                 }
             }
@@ -420,13 +483,17 @@ impl Instruction {
 
                         if rd == 0x2 {
                             // c.addi16sp
-                            // nzimm[9] nzimm[4|6|8:7|5]
+                            // nzimm[9] nzimm[4|6|8:7|5], collected as nzimm[9:2] to sign
+                            // extend from bit 9, the immediate being a multiple of 16
                             let imm = (((instruction >> 12) as u8 & 0b1) << 7)
                                 | (((instruction >> 6) as u8 & 0b1) << 2)
                                 | (((instruction >> 5) as u8 & 0b1) << 4)
                                 | (((instruction >> 3) as u8 & 0b11) << 5)
                                 | (((instruction >> 2) as u8 & 0b1) << 3);
                             let imm = ((imm as i8) as i16) << 2;
+                            if imm == 0 {
+                                return Err(DecodeError::ReservedEncoding);
+                            }
 
                             I {
                                 opcode: 0b0010011,
@@ -437,10 +504,13 @@ impl Instruction {
                             }
                         } else {
                             // c.lui
-                            //  nzuimm[5|4:0]
+                            //  nzimm[17|16:12], sign extended as the upper immediate of lui
                             let imm = (((instruction >> 12) as u8 & 0b1) << 7)
                                 | (((instruction >> 2) as u8 & 0b11111) << 2);
                             let imm = (imm as i8) >> 2;
+                            if imm == 0 {
+                                return Err(DecodeError::ReservedEncoding);
+                            }
 
                             U {
                                 opcode: 0b0110111,
@@ -557,30 +627,18 @@ impl Instruction {
                                         funct7: 0x20,
                                     },
                                     _ => {
-                                        return Err(InstructionDecodingError);
+                                        return Err(DecodeError::ReservedEncoding);
                                     }
                                 }
                             }
                         }
                     }
                     // c.j
-                    0b101 => {
-                        // imm[11|4|9:8|10|6|7|3:1|5]
-                        let imm = (((instruction >> 12) & 0b1) << 15)
-                            | (((instruction >> 11) & 0b1) << 8)
-                            | (((instruction >> 9) & 0b11) << 12)
-                            | (((instruction >> 8) & 0b1) << 14)
-                            | (((instruction >> 7) & 0b1) << 10)
-                            | (((instruction >> 6) & 0b1) << 11)
-                            | (((instruction >> 3) & 0b111) << 5)
-                            | (((instruction >> 2) & 0b1) << 9);
-                        let imm = imm as i16 >> 5;
-                        J {
-                            opcode: 0b1101111,
-                            rd: 0x0,
-                            imm: (2 * imm) as i32,
-                        }
-                    }
+                    0b101 => J {
+                        opcode: 0b1101111,
+                        rd: 0x0,
+                        imm: Instruction::cj_offset(instruction),
+                    },
                     // c.beqz
                     0b110 => {
                         let rs1 = (instruction >> 7) as u8 & 0b111;
@@ -618,7 +676,7 @@ impl Instruction {
                         }
                     }
                     _ => {
-                        return Err(InstructionDecodingError);
+                        return Err(DecodeError::UnknownOpcode);
                     }
                 }
             }
@@ -643,8 +701,12 @@ impl Instruction {
                     }
                     // CR-Type: c.mv x12, x1 / c.jr
                     0b1000 => {
+                        // c.jr x0 is reserved, c.mv into x0 is a hint
+                        if rs1 == 0 && rs2 == 0 {
+                            return Err(DecodeError::ReservedEncoding);
+                        }
                         // c.jr
-                        if rs1 != 0 && rs2 == 0 {
+                        if rs2 == 0 {
                             I {
                                 opcode: 0b1100111,
                                 rd: 0x0, // x0
@@ -665,6 +727,7 @@ impl Instruction {
                     }
                     // CR-Type: c.add / c.ebreak / c.jalr
                     0b1001 => {
+                        // c.add into x0 is a hint
                         if rs1 != 0 && rs2 == 0 {
                             // c.jalr
                             I {
@@ -750,8 +813,9 @@ impl Instruction {
                             imm: imm as i16,
                         }
                     }
+                    // c.fldsp / c.fsdsp
                     _ => {
-                        return Err(InstructionDecodingError);
+                        return Err(DecodeError::Unimplemented);
                     }
                 }
             }
@@ -766,7 +830,8 @@ impl Instruction {
 
 #[cfg(test)]
 mod tests {
-    use crate::ins::{Instruction, InstructionFormat};
+    use crate::ins::{DecodeError, Instruction, InstructionFormat};
+    use crate::plic::Fault;
     use crate::reg::treg;
 
     #[test]
@@ -1049,6 +1114,109 @@ mod tests {
             _ => assert!(false, "not sw"),
         }
     }
+    #[test]
+    fn test_cldsp_csdsp() {
+        // ld a0,8(sp) / ld a0,504(sp) / ld a0,8(a1) / ld a0,248(a1)
+        let loads = [
+            (0x6522, treg("sp"), 8),
+            (0x757e, treg("sp"), 504),
+            (0x6588, treg("a1"), 8),
+            (0x7de8, treg("a1"), 248),
+        ];
+        for (ins, base, offset) in loads {
+            match Instruction::CRV32(ins).decode().expect("decode").1 {
+                InstructionFormat::I {
+                    opcode,
+                    funct3,
+                    rs1,
+                    imm,
+                    rd,
+                } => {
+                    assert_eq!(opcode, 0b0000011, "opcode wrong");
+                    assert_eq!(funct3, 0x3, "funct3 wrong");
+                    assert_eq!(rd, treg("a0"), "rd wrong");
+                    assert_eq!(rs1, base, "rs1 wrong");
+                    assert_eq!(imm, offset, "imm wrong");
+                }
+                _ => panic!("not ld"),
+            }
+        }
+
+        // sd a1,16(sp) / sd a1,504(sp) / sd a0,16(a1) / sd a2,248(a1)
+        let stores = [
+            (0xe82e, treg("sp"), treg("a1"), 16),
+            (0xffae, treg("sp"), treg("a1"), 504),
+            (0xe988, treg("a1"), treg("a0"), 16),
+            (0xfdf0, treg("a1"), treg("a2"), 248),
+        ];
+        for (ins, base, src, offset) in stores {
+            match Instruction::CRV32(ins).decode().expect("decode").1 {
+                InstructionFormat::S {
+                    opcode,
+                    funct3,
+                    rs1,
+                    rs2,
+                    imm,
+                } => {
+                    assert_eq!(opcode, 0b0100011, "opcode wrong");
+                    assert_eq!(funct3, 0x3, "funct3 wrong");
+                    assert_eq!(rs1, base, "rs1 wrong");
+                    assert_eq!(rs2, src, "rs2 wrong");
+                    assert_eq!(imm, offset, "imm wrong");
+                }
+                _ => panic!("not sd"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_caddiw() {
+        // addiw a0,a0,-1 / addiw a0,a0,31
+        for (ins, expected) in [(0x357d, -1), (0x257d, 31)] {
+            match Instruction::CRV32(ins).decode().expect("decode").1 {
+                InstructionFormat::I {
+                    opcode,
+                    funct3,
+                    rs1,
+                    imm,
+                    rd,
+                } => {
+                    assert_eq!(opcode, 0b0011011, "opcode wrong");
+                    assert_eq!(funct3, 0x0, "funct3 wrong");
+                    assert_eq!(rd, treg("a0"), "rd wrong");
+                    assert_eq!(rs1, treg("a0"), "rs1 wrong");
+                    assert_eq!(imm, expected, "imm wrong");
+                }
+                _ => panic!("not addiw"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_caddw_csubw() {
+        // addw a0,a0,a1 / subw a0,a0,a1
+        for (ins, expected) in [(0x9d2d, 0x00), (0x9d0d, 0x20)] {
+            match Instruction::CRV32(ins).decode().expect("decode").1 {
+                InstructionFormat::R {
+                    opcode,
+                    rd,
+                    funct3,
+                    rs1,
+                    rs2,
+                    funct7,
+                } => {
+                    assert_eq!(opcode, 0b0111011, "opcode wrong");
+                    assert_eq!(funct3, 0x0, "funct3 wrong");
+                    assert_eq!(funct7, expected, "funct7 wrong");
+                    assert_eq!(rd, treg("a0"), "rd wrong");
+                    assert_eq!(rs1, treg("a0"), "rs1 wrong");
+                    assert_eq!(rs2, treg("a1"), "rs2 wrong");
+                }
+                _ => panic!("not addw/subw"),
+            }
+        }
+    }
+
     #[test]
     fn test_csrai_8000041a() {
         // lw	s0,64(a1)
@@ -1074,4 +1242,120 @@ mod tests {
             _ => assert!(false, "not sw"),
         }
     }
+
+    #[test]
+    fn test_cjr_reserved() {
+        // c.jr x0
+        let ins = Instruction::CRV32(0x8002);
+
+        assert!(matches!(ins.decode(), Err(Fault::IllegalOpcode(_))));
+    }
+
+    #[test]
+    fn test_cmv_x0() {
+        // c.mv x0, a1
+        let ins = Instruction::CRV32(0x802e);
+
+        let decoded = ins.decode().expect("decode").1;
+        match decoded {
+            InstructionFormat::I {
+                opcode,
+                funct3,
+                rs1,
+                imm,
+                rd,
+            } => {
+                assert_eq!(opcode, 0b0010011, "opcode wrong");
+                assert_eq!(funct3, 0x0, "funct3 wrong");
+                assert_eq!(rd, 0, "rd wrong");
+                assert_eq!(rs1, treg("a1"), "rs1 wrong");
+                assert_eq!(imm, 0, "imm wrong");
+            }
+            _ => panic!("not mv"),
+        }
+    }
+
+    #[test]
+    fn test_caddi16sp() {
+        // addi sp,sp,-16 / addi sp,sp,496 / addi sp,sp,-512
+        for (ins, expected) in [(0x717d, -16), (0x617d, 496), (0x7101, -512)] {
+            let decoded = Instruction::CRV32(ins).decode().expect("decode").1;
+            match decoded {
+                InstructionFormat::I {
+                    opcode,
+                    funct3,
+                    rs1,
+                    imm,
+                    rd,
+                } => {
+                    assert_eq!(opcode, 0b0010011, "opcode wrong");
+                    assert_eq!(funct3, 0x0, "funct3 wrong");
+                    assert_eq!(rd, treg("sp"), "rd wrong");
+                    assert_eq!(rs1, treg("sp"), "rs1 wrong");
+                    assert_eq!(imm, expected, "imm wrong");
+                }
+                _ => panic!("not addi"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_cjal_rv32() {
+        // jal 4 / jal -2048
+        for (ins, expected) in [(0x2011, 4), (0x3001, -2048)] {
+            match Instruction::CRV32(ins).decode_rv32().expect("decode").1 {
+                InstructionFormat::J { opcode, rd, imm } => {
+                    assert_eq!(opcode, 0b1101111, "opcode wrong");
+                    assert_eq!(rd, treg("ra"), "rd wrong");
+                    assert_eq!(imm, expected, "imm wrong");
+                }
+                _ => panic!("not jal"),
+            }
+        }
+
+        // flw fa0,4(a1) is c.ld on RV64
+        assert!(Instruction::CRV32(0x61c8).decode_rv32().is_err());
+        assert!(Instruction::CRV32(0x61c8).decode().is_ok());
+    }
+
+    #[test]
+    fn test_clui() {
+        // lui a0,0xfffff / lui a0,0x1f / lui a0,0x1
+        for (ins, expected) in [(0x757d, -1), (0x657d, 31), (0x6505, 1)] {
+            let decoded = Instruction::CRV32(ins).decode().expect("decode").1;
+            match decoded {
+                InstructionFormat::U { opcode, rd, imm } => {
+                    assert_eq!(opcode, 0b0110111, "opcode wrong");
+                    assert_eq!(rd, treg("a0"), "rd wrong");
+                    assert_eq!(imm, expected, "imm wrong");
+                }
+                _ => panic!("not lui"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        // c.addi16sp sp,0 / c.lui a0,0 / c.jr x0
+        for ins in [0x6101, 0x6501, 0x8002] {
+            let ins = Instruction::CRV32(ins);
+            assert_eq!(
+                ins.decode_verbose().err(),
+                Some(DecodeError::ReservedEncoding)
+            );
+            assert!(matches!(ins.decode(), Err(Fault::IllegalOpcode(_))));
+        }
+
+        // c.fldsp fa0,0(sp) / fmadd.d fa0,fa1,fa2,fa3
+        let unimplemented = [Instruction::CRV32(0x2502), Instruction::IRV32(0x6ac5f543)];
+        for ins in unimplemented {
+            assert_eq!(ins.decode_verbose().err(), Some(DecodeError::Unimplemented));
+        }
+
+        let unknown = Instruction::IRV32(0x0000007f);
+        assert_eq!(
+            unknown.decode_verbose().err(),
+            Some(DecodeError::UnknownOpcode)
+        );
+    }
 }
@@ -1,16 +1,24 @@
 pub mod bus;
+pub mod clint;
+pub mod console;
 pub mod csr;
 pub mod device;
+pub mod disasm;
 pub mod dt;
 pub mod dynbus;
 pub mod gdb;
 pub mod hart;
+pub mod hsm;
 pub mod htif;
 pub mod ins;
+pub mod machine_builder;
 pub mod plic;
+pub mod pmp;
 pub mod ram;
 pub mod reg;
 pub mod rom;
 pub mod rtc;
 pub mod see;
+pub mod sifive_test;
+pub mod symbols;
 pub mod uart8250;
@@ -0,0 +1,470 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use object::{Architecture, Endianness, Object, ObjectSegment};
+
+use crate::clint::{Clint, CLINT_ADDR, CLINT_SIZE};
+use crate::console::{Console, Stdio};
+use crate::dt;
+use crate::dynbus::DynBus;
+use crate::hart::Hart;
+use crate::plic::{Plic, PLIC_ADDR};
+use crate::ram::Ram;
+use crate::reg::treg;
+use crate::rom::Rom;
+use crate::rtc::{Rtc, RTC_ADDR};
+use crate::symbols::Symbols;
+use crate::uart8250::Uart8250;
+use crate::{bus, csr};
+
+pub const DTB_ADDR: usize = 0x8000;
+
+// Interrupt source of the UART on the PLIC
+pub const UART_IRQ: u32 = 10;
+
+// Why an image cannot be run on a hart
+#[derive(Debug)]
+pub enum ImageError {
+    Parse(object::Error),
+    Architecture(Architecture),
+    BigEndian,
+    // A segment starting in RAM, but running past its end
+    OutOfRam {
+        addr: usize,
+        len: usize,
+    },
+    // A segment outside of RAM, overlapping RAM or the named device
+    Overlap {
+        addr: usize,
+        len: usize,
+        device: String,
+    },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Parse(err) => write!(f, "not an ELF image: {}", err),
+            ImageError::Architecture(arch) => write!(f, "not a RISC-V image, but {:?}", arch),
+            ImageError::BigEndian => write!(f, "big-endian images are not supported"),
+            ImageError::OutOfRam { addr, len } => {
+                write!(f, "segment at {:#x} of {:#x} bytes exceeds RAM", addr, len)
+            }
+            ImageError::Overlap { addr, len, device } => {
+                write!(
+                    f,
+                    "segment at {:#x} of {:#x} bytes overlaps {}",
+                    addr, len, device
+                )
+            }
+        }
+    }
+}
+
+impl Error for ImageError {}
+
+// Only little-endian RISC-V images can be run, anything else parses fine but executes garbage
+pub fn check_elf(elf: &object::File) -> Result<(), ImageError> {
+    match elf.architecture() {
+        Architecture::Riscv32 | Architecture::Riscv64 => {}
+        arch => return Err(ImageError::Architecture(arch)),
+    }
+    match elf.endianness() {
+        Endianness::Little => Ok(()),
+        Endianness::Big => Err(ImageError::BigEndian),
+    }
+}
+
+// Wires up the devices of a machine on a `DynBus`, with RAM at `bus::RAM_ADDR`
+pub struct MachineBuilder {
+    ram: Option<Ram>,
+    image: Vec<(usize, Vec<u8>)>,
+    entry: usize,
+    uart: Option<usize>,
+    console: Arc<dyn Console>,
+    clint: bool,
+    plic: bool,
+    dtb: Option<Vec<u8>>,
+    fdt: bool,
+    symbols: Option<Symbols>,
+}
+
+impl MachineBuilder {
+    pub fn new() -> MachineBuilder {
+        Self {
+            ram: None,
+            image: vec![],
+            entry: bus::RAM_ADDR,
+            uart: None,
+            console: Arc::new(Stdio),
+            clint: false,
+            plic: false,
+            dtb: None,
+            fdt: false,
+            symbols: None,
+        }
+    }
+
+    pub fn ram(mut self, size: usize) -> Self {
+        self.ram = Some(Ram::sized(size));
+        self
+    }
+
+    pub fn uart(mut self, base: usize) -> Self {
+        self.uart = Some(base);
+        self
+    }
+
+    // Connects the UART and the SBI console to `console` instead of stdin and stdout
+    pub fn console(mut self, console: Arc<dyn Console>) -> Self {
+        self.console = console;
+        self
+    }
+
+    // Maps the CLINT along with the RTC providing its timer
+    pub fn clint(mut self) -> Self {
+        self.clint = true;
+        self
+    }
+
+    pub fn plic(mut self) -> Self {
+        self.plic = true;
+        self
+    }
+
+    // Loads the device tree `name` from the data directory, see `dt::load`
+    pub fn dtb(mut self, name: &str) -> Self {
+        self.dtb = Some(dt::load(name));
+        self
+    }
+
+    // Generates the device tree from the devices mapped by `build`, see `dt::generate`
+    pub fn fdt(mut self) -> Self {
+        self.fdt = true;
+        self
+    }
+
+    // Loads the segments of an ELF image into RAM and starts the hart at its entry point
+    pub fn load_elf(mut self, bytes: &[u8]) -> Result<Self, ImageError> {
+        let elf = object::File::parse(bytes).map_err(ImageError::Parse)?;
+        check_elf(&elf)?;
+        for segment in elf.segments() {
+            let data = segment.data().map_err(ImageError::Parse)?;
+            self.image.push((segment.address() as usize, data.to_vec()));
+        }
+        self.entry = elf.entry() as usize;
+        self.symbols = Some(Symbols::from_elf(&elf));
+        Ok(self)
+    }
+
+    // Loads a flat binary to the start of RAM
+    pub fn load_bin(self, bytes: &[u8]) -> Self {
+        self.load_flat(bytes, bus::RAM_ADDR, bus::RAM_ADDR)
+    }
+
+    // Loads a flat binary to `load_addr` and starts the hart at `entry`, images outside of RAM
+    // are mapped read-only
+    pub fn load_flat(mut self, bytes: &[u8], load_addr: usize, entry: usize) -> Self {
+        self.image.push((load_addr, bytes.to_vec()));
+        self.entry = entry;
+        self
+    }
+
+    pub fn build(self) -> Result<(Arc<DynBus>, Hart<DynBus>), ImageError> {
+        let mut bus = DynBus::new();
+
+        let ram = self.ram.unwrap_or_default();
+        let ram_range = bus::RAM_ADDR..bus::RAM_ADDR + ram.size();
+        let mut roms = vec![];
+        for (addr, data) in self.image {
+            if ram_range.contains(&addr) {
+                let len = data.len();
+                ram.write(addr - bus::RAM_ADDR, data)
+                    .ok_or(ImageError::OutOfRam { addr, len })?;
+            } else {
+                roms.push((addr, data));
+            }
+        }
+        let mut layout = dt::Layout {
+            harts: 1,
+            ram: ram_range,
+            clint: None,
+            plic: None,
+            uart: None,
+        };
+        bus.map_named("memory", ram, layout.ram.clone());
+
+        if self.clint {
+            let rtc = Arc::new(Rtc::new());
+            let clint = CLINT_ADDR..CLINT_ADDR + CLINT_SIZE;
+            bus.map_named("clint", Clint::with_timer(rtc.clone()), clint.clone());
+            bus.map_named("rtc", rtc, RTC_ADDR..RTC_ADDR + 0x20);
+            layout.clint = Some(clint);
+        }
+
+        let plic = if self.plic {
+            let plic = Arc::new(Plic::new());
+            let range = PLIC_ADDR..PLIC_ADDR + 0x400000;
+            bus.map_named("plic", plic.clone(), range.clone());
+            layout.plic = Some(range);
+            Some(plic)
+        } else {
+            None
+        };
+
+        if let Some(base) = self.uart {
+            let uart = Uart8250::with_console(self.console.clone());
+            if let Some(plic) = &plic {
+                uart.connect(plic, UART_IRQ);
+            }
+            bus.map_named("uart", uart, base..base + 0x10);
+            layout.uart = Some(base..base + 0x10);
+        }
+
+        let dtb = if self.fdt {
+            Some(dt::generate(&layout))
+        } else {
+            self.dtb
+        };
+        if let Some(dtb) = &dtb {
+            let dtb_end = DTB_ADDR + dtb.len();
+            bus.map_named("dtb", Rom::new(dtb.clone()), DTB_ADDR..dtb_end);
+        }
+
+        // Mapped last, so that segments overlapping anything else are refused rather than
+        // panicking in `map_named`
+        for (addr, data) in roms {
+            let len = data.len();
+            let range = addr..addr.saturating_add(len);
+            if let Some((device, _)) = bus
+                .mappings()
+                .into_iter()
+                .find(|(_, mapped)| range.start < mapped.end && mapped.start < range.end)
+            {
+                return Err(ImageError::Overlap { addr, len, device });
+            }
+            bus.map_named("rom", Rom::new(data), range);
+        }
+
+        let bus = Arc::new(bus);
+        let mut hart = Hart::new(0, self.entry, bus.clone());
+        hart.set_console(self.console);
+        if let Some(plic) = plic {
+            hart.set_plic(plic);
+        }
+        if let Some(symbols) = self.symbols {
+            hart.set_symbols(Arc::new(symbols));
+        }
+
+        // Boot register state, as expected by linux and OpenSBI payloads
+        hart.set_register(treg("a0"), 0);
+        if dtb.is_some() {
+            hart.set_register(treg("a1"), DTB_ADDR as u64);
+        }
+        hart.set_csr(csr::SATP, 0);
+
+        Ok((bus, hart))
+    }
+}
+
+impl Default for MachineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::console::Buffer;
+    use crate::device::Device;
+    use crate::machine_builder::{ImageError, MachineBuilder, UART_IRQ};
+    use crate::plic::{Fault, PLIC_ADDR};
+    use crate::{clint, csr};
+
+    #[test]
+    fn minimal_machine() {
+        let (bus, mut hart) = MachineBuilder::new()
+            .ram(0x1000)
+            .clint()
+            .plic()
+            .load_bin(&[
+                0x93, 0x05, 0xa0, 0x02, // li	a1,42
+                0x93, 0x08, 0x80, 0x00, // li	a7,8
+                0x73, 0x00, 0x00, 0x00, // ecall (legacy shutdown)
+            ])
+            .build()
+            .expect("build");
+
+        let mut ticks = 0;
+        let res = loop {
+            match hart.tick() {
+                Ok(()) if ticks < 100 => ticks += 1,
+                res => break res,
+            }
+        };
+
+        assert!(matches!(res, Err(Fault::Halt)));
+        assert_eq!(ticks, 3);
+        assert_eq!(hart.get_register(11), 42);
+        assert_eq!(bus.read_word(0x80000000).expect("ram"), 0x02a00593);
+    }
+
+    #[test]
+    fn flat_image() {
+        let (bus, mut hart) = MachineBuilder::new()
+            .ram(0x1000)
+            .load_flat(
+                &[
+                    0x13, 0x05, 0x70, 0x00, // li	a0,7
+                    0x93, 0x05, 0xa0, 0x02, // li	a1,42
+                    0x93, 0x08, 0x80, 0x00, // li	a7,8
+                    0x73, 0x00, 0x00, 0x00, // ecall (legacy shutdown)
+                ],
+                0x1000,
+                0x1004,
+            )
+            .build()
+            .expect("build");
+
+        let res = (0..100).map(|_| hart.tick()).find(Result::is_err);
+
+        assert!(matches!(res, Some(Err(Fault::Halt))));
+        assert_eq!(hart.get_register(10), 0, "skipped before the entry point");
+        assert_eq!(hart.get_register(11), 42);
+        assert!(
+            bus.write_word(0x1000, 0).is_err(),
+            "outside of RAM is read-only"
+        );
+    }
+
+    #[test]
+    fn uart_interrupt() {
+        let console = Arc::new(Buffer::new());
+        let (bus, mut hart) = MachineBuilder::new()
+            .ram(0x1000)
+            .plic()
+            .uart(0x10000000)
+            .console(console.clone())
+            .load_bin(&[
+                0x01, 0xa0, // j	.
+            ])
+            .build()
+            .expect("build");
+
+        // UART receive interrupt routed to the M-mode context of hart 0
+        bus.write_byte(0x10000001, 0b01).expect("ier");
+        bus.write_word(PLIC_ADDR + 4 * UART_IRQ as usize, 1)
+            .expect("priority");
+        bus.write_word(PLIC_ADDR + 0x2000, 1 << UART_IRQ)
+            .expect("enable");
+        hart.set_csr(csr::MTVEC, 0x80000100);
+        hart.set_csr(csr::MIE, 1 << clint::MEIP);
+        hart.set_csr(csr::MSTATUS, csr::MSTATUS_MIE);
+
+        hart.tick().expect("tick");
+        assert_eq!(hart.get_pc(), 0x80000000, "nothing received yet");
+
+        console.push_input(b"x");
+        hart.tick().expect("tick");
+        assert_eq!(hart.get_pc(), 0x80000100);
+        assert_eq!(
+            hart.get_csr(csr::MCAUSE),
+            csr::MCAUSE_INTERRUPT | clint::MEIP
+        );
+    }
+
+    #[test]
+    fn image_exceeding_ram() {
+        let res = MachineBuilder::new()
+            .ram(0x1000)
+            .load_flat(&[0; 0x1000], 0x80000800, 0x80000800)
+            .build();
+
+        assert!(matches!(
+            res,
+            Err(ImageError::OutOfRam {
+                addr: 0x80000800,
+                len: 0x1000
+            })
+        ));
+    }
+
+    #[test]
+    fn image_overlapping_devices() {
+        let into_ram = MachineBuilder::new()
+            .ram(0x1000)
+            .load_flat(&[0; 0x1000], 0x7ffff800, 0x7ffff800)
+            .build();
+        assert!(matches!(
+            into_ram,
+            Err(ImageError::Overlap { addr: 0x7ffff800, len: 0x1000, device }) if device == "memory"
+        ));
+
+        let over_rtc = MachineBuilder::new()
+            .clint()
+            .load_flat(&[0; 0x10], 0x4000, 0x4000)
+            .build();
+        assert!(matches!(
+            over_rtc,
+            Err(ImageError::Overlap { addr: 0x4000, device, .. }) if device == "rtc"
+        ));
+
+        let over_dtb = MachineBuilder::new()
+            .fdt()
+            .load_flat(&[0; 0x10], 0x8000, 0x8000)
+            .build();
+        assert!(matches!(
+            over_dtb,
+            Err(ImageError::Overlap { device, .. }) if device == "dtb"
+        ));
+    }
+
+    // An ELF64 header without segments or sections
+    fn elf_header(big_endian: bool, machine: u16) -> Vec<u8> {
+        let half = |val: u16| match big_endian {
+            true => val.to_be_bytes().to_vec(),
+            false => val.to_le_bytes().to_vec(),
+        };
+        let word = |val: u32| match big_endian {
+            true => val.to_be_bytes().to_vec(),
+            false => val.to_le_bytes().to_vec(),
+        };
+
+        let mut header = vec![0x7f, b'E', b'L', b'F', 2, 1 + big_endian as u8, 1];
+        header.resize(16, 0);
+        header.extend(half(2)); // e_type: executable
+        header.extend(half(machine));
+        header.extend(word(1)); // e_version
+        header.extend([0; 3 * 8]); // e_entry, e_phoff, e_shoff
+        header.extend(word(0)); // e_flags
+        header.extend(half(64)); // e_ehsize
+        header.extend(half(56)); // e_phentsize
+        header.extend(half(0)); // e_phnum
+        header.extend(half(64)); // e_shentsize
+        header.extend(half(0)); // e_shnum
+        header.extend(half(0)); // e_shstrndx
+        header
+    }
+
+    #[test]
+    fn foreign_images() {
+        const EM_X86_64: u16 = 62;
+        const EM_RISCV: u16 = 243;
+
+        assert!(MachineBuilder::new()
+            .load_elf(&elf_header(false, EM_RISCV))
+            .is_ok());
+
+        let x86 = MachineBuilder::new().load_elf(&elf_header(false, EM_X86_64));
+        assert!(matches!(x86, Err(ImageError::Architecture(_))));
+
+        let big_endian = MachineBuilder::new().load_elf(&elf_header(true, EM_RISCV));
+        assert!(matches!(big_endian, Err(ImageError::BigEndian)));
+
+        let garbage = MachineBuilder::new().load_elf(b"garbage");
+        assert!(matches!(garbage, Err(ImageError::Parse(_))));
+    }
+}
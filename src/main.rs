@@ -4,7 +4,7 @@ use std::thread;
 use std::{env, fs};
 
 use rriscv::bus::Bus;
-use rriscv::hart::Hart;
+use rriscv::hart::{self, Exit, Hart};
 use rriscv::ram::Ram;
 use rriscv::rom::Rom;
 
@@ -29,14 +29,9 @@ fn main() {
         let handle = thread::spawn(move || {
             debug!("[{}] hart spawned", id);
             let mut m = Hart::new(id, 0, bus);
-            for i in 0..100 {
-                match m.tick() {
-                    Ok(_) => {}
-                    Err(e) => {
-                        info!("exited at: {} ({:?})", i, e);
-                        break;
-                    }
-                }
+            let summary = m.run(Some(hart::max_instructions().unwrap_or(100)));
+            if let Exit::Fault(e) = summary.exit {
+                info!("exited at: {} ({:?})", summary.instructions_retired, e);
             }
         });
 
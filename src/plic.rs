@@ -1,12 +1,356 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::RwLock;
+
+use crate::device::Device;
 use crate::ins::Instruction;
 
 #[derive(Debug)]
 pub enum Fault {
+    // An instruction fetch from an address which is unmapped
+    FetchFault(usize),
     MemoryFault(usize),
     Unmapped(usize),
+    // A store or AMO to an address which is unmapped or does not accept writes
+    StoreFault(usize),
     Unaligned(usize),
+    MisalignedLoad(usize),
+    MisalignedStore(usize),
+    // An ebreak, to be handled by an attached debugger.  Standalone harts treat it as a halt.
+    Breakpoint,
     Halt,
     Unimplemented,
     InstructionDecodingError,
     IllegalOpcode(Instruction),
 }
+
+impl Fault {
+    // The exception code to report in `mcause`, or `None` for faults of the emulator itself
+    pub fn cause_code(&self) -> Option<u64> {
+        match self {
+            // Illegal instruction
+            Fault::Unimplemented | Fault::InstructionDecodingError | Fault::IllegalOpcode(_) => {
+                Some(2)
+            }
+            // Instruction access fault
+            Fault::FetchFault(_) => Some(1),
+            // Load address misaligned
+            Fault::Unaligned(_) | Fault::MisalignedLoad(_) => Some(4),
+            // Load access fault
+            Fault::MemoryFault(_) | Fault::Unmapped(_) => Some(5),
+            // Store/AMO address misaligned
+            Fault::MisalignedStore(_) => Some(6),
+            // Store/AMO access fault
+            Fault::StoreFault(_) => Some(7),
+            Fault::Breakpoint => Some(3),
+            Fault::Halt => None,
+        }
+    }
+
+    // The value to report in `mtval`: the faulting address, or the instruction bits of an
+    // illegal instruction
+    pub fn trap_value(&self) -> u64 {
+        match self {
+            Fault::FetchFault(addr)
+            | Fault::MemoryFault(addr)
+            | Fault::Unmapped(addr)
+            | Fault::StoreFault(addr)
+            | Fault::Unaligned(addr)
+            | Fault::MisalignedLoad(addr)
+            | Fault::MisalignedStore(addr) => *addr as u64,
+            Fault::IllegalOpcode(Instruction::IRV32(ins)) => *ins as u64,
+            Fault::IllegalOpcode(Instruction::CRV32(ins)) => *ins as u64,
+            _ => 0,
+        }
+    }
+}
+
+// Where the PLIC is usually mapped on the bus
+pub const PLIC_ADDR: usize = 0xc000000;
+
+// Register offsets, relative to the mapping of the device
+const PRIORITY: usize = 0x0;
+const PRIORITY_END: usize = PRIORITY + 4 * NUM_SOURCES;
+// Read-only, only the first word holds sources
+const PENDING: usize = 0x1000;
+const PENDING_END: usize = 0x1080;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const ENABLE_END: usize = ENABLE + ENABLE_STRIDE * NUM_CONTEXTS;
+const CONTEXT: usize = 0x200000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_END: usize = CONTEXT + CONTEXT_STRIDE * NUM_CONTEXTS;
+const THRESHOLD: usize = 0x0;
+const CLAIM: usize = 0x4;
+
+// Source 0 is reserved, leaving 31 usable interrupt sources in one word of pending bits
+const NUM_SOURCES: usize = 32;
+// One context per privilege level (M, S) per hart
+const NUM_CONTEXTS: usize = 8;
+
+type Level = Box<dyn Fn() -> bool + Send + Sync>;
+
+pub struct Plic {
+    priority: [AtomicU32; NUM_SOURCES],
+    pending: AtomicU32,
+    claimed: AtomicU32,
+    enable: [AtomicU32; NUM_CONTEXTS],
+    threshold: [AtomicU32; NUM_CONTEXTS],
+    levels: RwLock<Vec<(u32, Level)>>,
+}
+
+impl Plic {
+    pub fn new() -> Plic {
+        Self {
+            priority: Default::default(),
+            pending: AtomicU32::new(0),
+            claimed: AtomicU32::new(0),
+            enable: Default::default(),
+            threshold: Default::default(),
+            levels: RwLock::new(vec![]),
+        }
+    }
+
+    // Connects a level-triggered source, which is pending for as long as `level` holds
+    pub fn connect(&self, irq: u32, level: impl Fn() -> bool + Send + Sync + 'static) {
+        let mut levels = self.levels.write().unwrap();
+
+        levels.push((irq, Box::new(level)));
+    }
+
+    // Raises an edge-triggered source, it stays pending until claimed
+    pub fn fire_interrupt(&self, irq: u32) {
+        self.pending.fetch_or(1 << irq, Ordering::SeqCst);
+    }
+
+    pub fn is_pending(&self, irq: u32) -> bool {
+        self.pending_bits() & (1 << irq) != 0
+    }
+
+    fn pending_bits(&self) -> u32 {
+        let levels = self.levels.read().unwrap();
+
+        levels
+            .iter()
+            .filter(|(_, level)| level())
+            .fold(self.pending.load(Ordering::SeqCst), |bits, (irq, _)| {
+                bits | 1 << irq
+            })
+    }
+
+    // The pending and enabled source of highest priority above the threshold of `context`, the
+    // lowest numbered one on ties.  Priority 0 never interrupts.
+    fn best_interrupt(&self, context: usize) -> Option<u32> {
+        let enabled = self.enable[context].load(Ordering::SeqCst);
+        let claimable = self.pending_bits() & enabled & !self.claimed.load(Ordering::SeqCst) & !1;
+        let threshold = self.threshold[context].load(Ordering::SeqCst);
+
+        (1..NUM_SOURCES as u32)
+            .filter(|irq| claimable & (1 << irq) != 0)
+            .map(|irq| (self.priority[irq as usize].load(Ordering::SeqCst), irq))
+            .filter(|&(priority, _)| priority > threshold)
+            .min_by_key(|&(priority, irq)| (u32::MAX - priority, irq))
+            .map(|(_, irq)| irq)
+    }
+
+    // Whether a source is ready to be claimed by `context`, as signalled to its hart's external
+    // interrupt pin.  Context 2n is M-mode and 2n+1 S-mode of hart n.
+    pub fn interrupt_pending(&self, context: usize) -> bool {
+        context < NUM_CONTEXTS && self.best_interrupt(context).is_some()
+    }
+
+    // The claimed source stays in service until completed
+    fn claim_interrupt(&self, context: usize) -> u32 {
+        match self.best_interrupt(context) {
+            None => 0,
+            Some(irq) => {
+                self.pending.fetch_and(!(1 << irq), Ordering::SeqCst);
+                self.claimed.fetch_or(1 << irq, Ordering::SeqCst);
+                irq
+            }
+        }
+    }
+
+    fn complete_interrupt(&self, irq: u32) {
+        self.claimed.fetch_and(!(1 << irq), Ordering::SeqCst);
+    }
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for Plic {
+    fn write_double(&self, addr: usize, _val: u64) -> Result<(), Fault> {
+        Err(Fault::Unaligned(addr))
+    }
+
+    fn write_word(&self, addr: usize, val: u32) -> Result<(), Fault> {
+        match addr {
+            PRIORITY..PRIORITY_END => {
+                self.priority[(addr - PRIORITY) / 4].store(val, Ordering::SeqCst);
+            }
+            ENABLE..ENABLE_END if (addr - ENABLE).is_multiple_of(ENABLE_STRIDE) => {
+                self.enable[(addr - ENABLE) / ENABLE_STRIDE].store(val, Ordering::SeqCst);
+            }
+            CONTEXT..CONTEXT_END => {
+                let context = (addr - CONTEXT) / CONTEXT_STRIDE;
+                match (addr - CONTEXT) % CONTEXT_STRIDE {
+                    THRESHOLD => self.threshold[context].store(val, Ordering::SeqCst),
+                    CLAIM if (val as usize) < NUM_SOURCES => self.complete_interrupt(val),
+                    _ => return Err(Fault::StoreFault(addr)),
+                }
+            }
+            _ => return Err(Fault::StoreFault(addr)),
+        }
+        Ok(())
+    }
+
+    fn write_half(&self, addr: usize, _val: u16) -> Result<(), Fault> {
+        Err(Fault::Unaligned(addr))
+    }
+
+    fn write_byte(&self, addr: usize, _val: u8) -> Result<(), Fault> {
+        Err(Fault::Unaligned(addr))
+    }
+
+    fn read_double(&self, addr: usize) -> Result<u64, Fault> {
+        Err(Fault::Unaligned(addr))
+    }
+
+    fn read_word(&self, addr: usize) -> Result<u32, Fault> {
+        match addr {
+            PRIORITY..PRIORITY_END => {
+                Ok(self.priority[(addr - PRIORITY) / 4].load(Ordering::SeqCst))
+            }
+            PENDING..PENDING_END => match addr {
+                PENDING => Ok(self.pending_bits()),
+                _ => Ok(0),
+            },
+            ENABLE..ENABLE_END if (addr - ENABLE).is_multiple_of(ENABLE_STRIDE) => {
+                Ok(self.enable[(addr - ENABLE) / ENABLE_STRIDE].load(Ordering::SeqCst))
+            }
+            CONTEXT..CONTEXT_END => {
+                let context = (addr - CONTEXT) / CONTEXT_STRIDE;
+                match (addr - CONTEXT) % CONTEXT_STRIDE {
+                    THRESHOLD => Ok(self.threshold[context].load(Ordering::SeqCst)),
+                    CLAIM => Ok(self.claim_interrupt(context)),
+                    _ => Err(Fault::MemoryFault(addr)),
+                }
+            }
+            _ => Err(Fault::MemoryFault(addr)),
+        }
+    }
+
+    fn read_half(&self, addr: usize) -> Result<u16, Fault> {
+        Err(Fault::Unaligned(addr))
+    }
+
+    fn read_byte(&self, addr: usize) -> Result<u8, Fault> {
+        Err(Fault::Unaligned(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use crate::device::Device;
+    use crate::ins::Instruction;
+    use crate::plic::{Fault, Plic};
+
+    #[test]
+    fn claim_complete() {
+        let plic = Plic::new();
+        let level = Arc::new(AtomicBool::new(true));
+        let source = level.clone();
+        plic.connect(3, move || source.load(Ordering::SeqCst));
+        plic.fire_interrupt(5);
+        plic.write_word(4 * 3, 1).expect("priority");
+        plic.write_word(4 * 5, 1).expect("priority");
+
+        // nothing enabled for context 0
+        assert_eq!(plic.read_word(0x200004).expect("claim"), 0);
+
+        plic.write_word(0x2000, 1 << 3 | 1 << 5).expect("enable");
+        assert_eq!(plic.read_word(0x200004).expect("claim"), 3);
+        assert_eq!(plic.read_word(0x200004).expect("claim"), 5);
+        assert_eq!(plic.read_word(0x200004).expect("claim"), 0);
+
+        // the edge triggered source was consumed by the claim, the level one is still high
+        plic.write_word(0x200004, 3).expect("complete");
+        plic.write_word(0x200004, 5).expect("complete");
+        assert_eq!(plic.read_word(0x200004).expect("claim"), 3);
+        plic.write_word(0x200004, 3).expect("complete");
+
+        level.store(false, Ordering::SeqCst);
+        assert_eq!(plic.read_word(0x200004).expect("claim"), 0);
+    }
+
+    #[test]
+    fn priority_and_threshold() {
+        let plic = Plic::new();
+        plic.fire_interrupt(2);
+        plic.fire_interrupt(7);
+        plic.write_word(0x2000, 1 << 2 | 1 << 7).expect("enable");
+        plic.write_word(4 * 2, 3).expect("priority");
+        plic.write_word(4 * 7, 5).expect("priority");
+
+        // source 2 is at the threshold, and does not interrupt
+        plic.write_word(0x200000, 3).expect("threshold");
+        assert_eq!(plic.read_word(0x200004).expect("claim"), 7);
+        assert_eq!(plic.read_word(0x200004).expect("claim"), 0);
+
+        plic.write_word(0x200000, 0).expect("threshold");
+        assert_eq!(plic.read_word(0x200004).expect("claim"), 2);
+    }
+
+    #[test]
+    fn pending_array() {
+        let plic = Plic::new();
+        assert_eq!(plic.read_word(0x1000).expect("pending"), 0);
+        assert_eq!(plic.read_word(0x1004).expect("pending"), 0);
+
+        plic.fire_interrupt(4);
+        plic.fire_interrupt(9);
+        assert_eq!(plic.read_word(0x1000).expect("pending"), 1 << 4 | 1 << 9);
+        assert!(plic.write_word(0x1000, 0).is_err());
+
+        // claiming consumes the pending bit
+        plic.write_word(0x2000, 1 << 4).expect("enable");
+        plic.write_word(4 * 4, 1).expect("priority");
+        assert_eq!(plic.read_word(0x200004).expect("claim"), 4);
+        assert_eq!(plic.read_word(0x1000).expect("pending"), 1 << 9);
+    }
+
+    #[test]
+    fn claim_idle() {
+        let plic = Plic::new();
+        plic.write_word(0x2000, !0).expect("enable");
+        assert_eq!(plic.read_word(0x200004).expect("claim"), 0);
+
+        // completing a source which was never claimed is harmless
+        plic.write_word(0x200004, 1).expect("complete");
+        assert_eq!(plic.read_word(0x200004).expect("claim"), 0);
+    }
+
+    #[test]
+    fn cause_codes() {
+        assert_eq!(
+            Fault::IllegalOpcode(Instruction::IRV32(0)).cause_code(),
+            Some(2)
+        );
+        assert_eq!(Fault::InstructionDecodingError.cause_code(), Some(2));
+        assert_eq!(Fault::Unimplemented.cause_code(), Some(2));
+        assert_eq!(Fault::MisalignedLoad(1).cause_code(), Some(4));
+        assert_eq!(Fault::Unaligned(1).cause_code(), Some(4));
+        assert_eq!(Fault::MemoryFault(0).cause_code(), Some(5));
+        assert_eq!(Fault::Unmapped(0).cause_code(), Some(5));
+        assert_eq!(Fault::MisalignedStore(1).cause_code(), Some(6));
+        assert_eq!(Fault::StoreFault(0).cause_code(), Some(7));
+        assert_eq!(Fault::FetchFault(0).cause_code(), Some(1));
+        assert_eq!(Fault::Halt.cause_code(), None);
+    }
+}
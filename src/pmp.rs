@@ -0,0 +1,176 @@
+// Physical Memory Protection, see section 3.7 of the privileged specification
+use crate::csr::Csr;
+use crate::hart::AccessKind;
+
+// Number of PMP entries implemented, configured through pmpcfg0/pmpcfg2 and pmpaddr0-15
+pub const PMP_ENTRIES: usize = 16;
+
+// Bits of an entry's configuration byte
+const PMP_R: u8 = 1 << 0;
+const PMP_W: u8 = 1 << 1;
+const PMP_L: u8 = 1 << 7;
+
+// Address matching modes, in the A field of the configuration
+const PMP_OFF: u8 = 0;
+const PMP_TOR: u8 = 1;
+const PMP_NA4: u8 = 2;
+const PMP_NAPOT: u8 = 3;
+
+// The byte range an entry matches, or `None` if it is off
+fn region(csr: &Csr, entry: usize) -> Option<(u64, u64)> {
+    let (cfg, pmpaddr) = csr.pmp(entry);
+
+    match (cfg >> 3) & 0b11 {
+        PMP_OFF => None,
+        PMP_TOR => {
+            let start = if entry == 0 { 0 } else { csr.pmp(entry - 1).1 };
+            Some((start << 2, pmpaddr << 2))
+        }
+        PMP_NA4 => Some((pmpaddr << 2, (pmpaddr << 2).saturating_add(4))),
+        PMP_NAPOT => {
+            // The number of trailing ones encodes a region of 2^(ones + 3) bytes.  pmpaddr holds
+            // 54 bits, so the largest region is the 2^57 bytes starting at 0
+            let ones = pmpaddr.trailing_ones();
+            let start = (pmpaddr & !((1 << ones) - 1)) << 2;
+            Some((start, start.saturating_add(1 << (ones + 3))))
+        }
+        _ => unreachable!(),
+    }
+}
+
+// Whether the access is allowed in M-mode, or a less privileged mode when `machine` is not set.
+// The lowest numbered entry matching any byte of the access decides, but only locked entries
+// apply to M-mode.  Accesses matching no entry are only allowed in M-mode.
+pub fn allows(csr: &Csr, addr: usize, width: usize, kind: AccessKind, machine: bool) -> bool {
+    let (start, end) = (addr as u64, (addr as u64).saturating_add(width as u64));
+
+    for entry in 0..PMP_ENTRIES {
+        let Some((lo, hi)) = region(csr, entry) else {
+            continue;
+        };
+        if end <= lo || hi <= start {
+            continue;
+        }
+
+        let cfg = csr.pmp(entry).0;
+        if lo > start || end > hi {
+            // partially matching accesses always fail
+            return false;
+        }
+        if machine && cfg & PMP_L == 0 {
+            return true;
+        }
+        return match kind {
+            AccessKind::Read => cfg & PMP_R != 0,
+            AccessKind::Write => cfg & PMP_W != 0,
+            AccessKind::ReadWrite => cfg & (PMP_R | PMP_W) == PMP_R | PMP_W,
+        };
+    }
+
+    machine
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csr::{self, Csr};
+    use crate::hart::{AccessKind, Xlen};
+    use crate::pmp::allows;
+
+    // A locked, read-only NAPOT region of 4KiB at 0x80001000
+    fn read_only() -> Csr {
+        let mut csr = Csr::new(0);
+        csr.write(csr::PMPADDR0, (0x80001000 >> 2) | 0x1ff);
+        csr.write(csr::PMPCFG0, (0b1 << 7 | 0b11 << 3 | 0b001) as u64);
+        csr
+    }
+
+    #[test]
+    fn napot() {
+        let csr = read_only();
+
+        assert!(allows(&csr, 0x80001000, 4, AccessKind::Read, true));
+        assert!(!allows(&csr, 0x80001000, 4, AccessKind::Write, true));
+        assert!(
+            !allows(&csr, 0x80001ffc, 8, AccessKind::Read, true),
+            "partial"
+        );
+        assert!(
+            allows(&csr, 0x80002000, 8, AccessKind::Write, true),
+            "no match"
+        );
+    }
+
+    #[test]
+    fn napot_largest_region() {
+        let mut csr = Csr::new(0);
+        csr.write(csr::PMPADDR0, !0);
+        csr.write(csr::PMPCFG0, (0b11 << 3 | 0b001) as u64);
+        assert_eq!(csr.read(csr::PMPADDR0), (1 << 54) - 1, "WARL");
+
+        assert!(allows(&csr, 0, 4, AccessKind::Read, false));
+        assert!(allows(&csr, 0x1_0000_0000_0000, 8, AccessKind::Read, false));
+        assert!(!allows(
+            &csr,
+            0x1_0000_0000_0000,
+            8,
+            AccessKind::Write,
+            false
+        ));
+        assert!(
+            !allows(&csr, 1 << 57, 4, AccessKind::Read, false),
+            "beyond 2^57"
+        );
+        assert!(allows(&csr, 1 << 57, 4, AccessKind::Write, true));
+    }
+
+    #[test]
+    fn tor() {
+        let mut csr = Csr::new(0);
+        csr.write(csr::PMPADDR0, 0x1000 >> 2);
+        csr.write(csr::PMPADDR0 + 1, 0x2000 >> 2);
+        // entry 0 is off, entry 1 covers 0x1000..0x2000 read-only and locked
+        csr.write(csr::PMPCFG0, ((0b1 << 7 | 0b01 << 3 | 0b001) as u64) << 8);
+
+        assert!(allows(&csr, 0x800, 4, AccessKind::Write, true));
+        assert!(!allows(&csr, 0x1000, 4, AccessKind::Write, true));
+        assert!(!allows(&csr, 0x1ff8, 8, AccessKind::ReadWrite, true));
+        assert!(allows(&csr, 0x1ff8, 8, AccessKind::Read, true));
+        assert!(allows(&csr, 0x2000, 4, AccessKind::Write, true));
+    }
+
+    #[test]
+    fn rv32_layout() {
+        let mut csr = Csr::new(0);
+        csr.set_xlen(Xlen::Rv32);
+        csr.write(csr::PMPADDR0 + 5, (0x80001000 >> 2) | 0x1ff);
+        // entry 5 is the second byte of pmpcfg1 on RV32
+        csr.write(
+            csr::PMPCFG0 + 1,
+            ((0b1 << 7 | 0b11 << 3 | 0b001) as u64) << 8,
+        );
+
+        assert!(allows(&csr, 0x80001000, 4, AccessKind::Read, true));
+        assert!(!allows(&csr, 0x80001000, 4, AccessKind::Write, true));
+    }
+
+    #[test]
+    fn unlocked() {
+        let mut csr = read_only();
+        csr.write(csr::PMPCFG0, (0b11 << 3 | 0b001) as u64);
+
+        assert!(allows(&csr, 0x80001000, 4, AccessKind::Write, true));
+    }
+
+    #[test]
+    fn less_privileged() {
+        let mut csr = read_only();
+        csr.write(csr::PMPCFG0, (0b11 << 3 | 0b001) as u64);
+
+        assert!(allows(&csr, 0x80001000, 4, AccessKind::Read, false));
+        assert!(!allows(&csr, 0x80001000, 4, AccessKind::Write, false));
+        assert!(
+            !allows(&csr, 0x80002000, 4, AccessKind::Read, false),
+            "no match"
+        );
+    }
+}
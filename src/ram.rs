@@ -1,32 +1,123 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::RwLock;
 
+use memmap2::MmapMut;
+
 use crate::device::Device;
 use crate::plic::Fault;
-use crate::plic::Fault::MemoryFault;
+use crate::plic::Fault::{MemoryFault, StoreFault};
 
 pub const DRAM_SIZE: usize = 1024 * 1024 * 128; // 128MiB
 
+// Zeroed allocations are only backed by pages once touched, mappings persist into their file
+enum Backing {
+    Heap(Vec<u8>),
+    Mapped(MmapMut),
+}
+
+impl Deref for Backing {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Backing::Heap(data) => data,
+            Backing::Mapped(map) => map,
+        }
+    }
+}
+
+impl DerefMut for Backing {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Backing::Heap(data) => data,
+            Backing::Mapped(map) => map,
+        }
+    }
+}
+
 pub struct Ram {
-    data: RwLock<Vec<u8>>,
+    data: RwLock<Backing>,
+    readonly: AtomicBool,
 }
 
 impl Ram {
     pub fn new() -> Ram {
-        let ram = vec![0; DRAM_SIZE];
+        Ram::sized(DRAM_SIZE)
+    }
+
+    pub fn sized(size: usize) -> Ram {
+        let ram = vec![0; size];
 
         Self {
-            data: RwLock::new(ram),
+            data: RwLock::new(Backing::Heap(ram)),
+            readonly: AtomicBool::new(false),
         }
     }
 
+    // Memory backed by the file at `path`, which is created or resized to `size` bytes
+    pub fn from_mmap(path: impl AsRef<Path>, size: usize) -> io::Result<Ram> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        file.set_len(size as u64)?;
+
+        // SAFETY: the mapping is only accessed through the lock, other processes modifying
+        // the file concurrently is the embedder's responsibility
+        let map = unsafe { MmapMut::map_mut(&file)? };
+
+        Ok(Self {
+            data: RwLock::new(Backing::Mapped(map)),
+            readonly: AtomicBool::new(false),
+        })
+    }
+
     pub fn size(&self) -> usize {
-        DRAM_SIZE
+        let data = self.data.read().unwrap();
+
+        data.len()
+    }
+
+    // A copy of the whole memory, see `restore`
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.data.read().unwrap().to_vec()
+    }
+
+    // Replaces the whole memory with a `snapshot` of the same size, otherwise fails with the
+    // size it needs to have
+    pub fn restore(&self, snapshot: Vec<u8>) -> Result<(), usize> {
+        let mut data = self.data.write().unwrap();
+        if data.len() != snapshot.len() {
+            return Err(data.len());
+        }
+        data.copy_from_slice(&snapshot);
+        Ok(())
+    }
+
+    // Protects the memory against stores of the guest, loaders may still `write` to it
+    pub fn set_readonly(&self, readonly: bool) {
+        self.readonly.store(readonly, Ordering::SeqCst);
+    }
+
+    fn check_writable(&self, addr: usize) -> Result<(), Fault> {
+        match self.readonly.load(Ordering::SeqCst) {
+            true => Err(StoreFault(addr)),
+            false => Ok(()),
+        }
     }
 
     pub fn write(&self, addr: usize, code: Vec<u8>) -> Option<()> {
         let mut shared = self.data.write().unwrap();
 
-        shared.splice(addr..(addr + code.len()), code.iter().cloned());
+        shared
+            .get_mut(addr..(addr + code.len()))?
+            .copy_from_slice(&code);
         Some(())
     }
 }
@@ -39,32 +130,40 @@ impl Default for Ram {
 
 impl Device for Ram {
     fn write_double(&self, addr: usize, val: u64) -> Result<(), Fault> {
+        self.check_writable(addr)?;
         let mut shared = self.data.write().unwrap();
 
-        shared.splice(addr..(addr + 8), val.to_le_bytes());
+        // The whole width is checked up front, so a faulting store leaves memory untouched
+        let bytes = shared.get_mut(addr..(addr + 8)).ok_or(StoreFault(addr))?;
+        bytes.copy_from_slice(&val.to_le_bytes());
 
         Ok(())
     }
     fn write_word(&self, addr: usize, val: u32) -> Result<(), Fault> {
+        self.check_writable(addr)?;
         let mut shared = self.data.write().unwrap();
 
-        shared.splice(addr..(addr + 4), val.to_le_bytes());
+        let bytes = shared.get_mut(addr..(addr + 4)).ok_or(StoreFault(addr))?;
+        bytes.copy_from_slice(&val.to_le_bytes());
 
         Ok(())
     }
 
     fn write_half(&self, addr: usize, val: u16) -> Result<(), Fault> {
+        self.check_writable(addr)?;
         let mut shared = self.data.write().unwrap();
 
-        shared.splice(addr..(addr + 2), val.to_le_bytes());
+        let bytes = shared.get_mut(addr..(addr + 2)).ok_or(StoreFault(addr))?;
+        bytes.copy_from_slice(&val.to_le_bytes());
 
         Ok(())
     }
 
     fn write_byte(&self, addr: usize, val: u8) -> Result<(), Fault> {
+        self.check_writable(addr)?;
         let mut shared = self.data.write().unwrap();
 
-        *(shared.get_mut(addr).ok_or(MemoryFault(addr))?) = val;
+        *(shared.get_mut(addr).ok_or(StoreFault(addr))?) = val;
         Ok(())
     }
 
@@ -100,11 +199,57 @@ impl Device for Ram {
 
         data.get(addr).copied().ok_or(MemoryFault(addr))
     }
+
+    fn amo_word(&self, addr: usize, f: &dyn Fn(u32) -> u32) -> Result<u32, Fault> {
+        self.check_writable(addr)?;
+        let mut data = self.data.write().unwrap();
+
+        let bytes = data.get_mut(addr..(addr + 4)).ok_or(StoreFault(addr))?;
+        let val = u32::from_le_bytes(<[u8; 4]>::try_from(&*bytes).map_err(|_| StoreFault(addr))?);
+        bytes.copy_from_slice(&f(val).to_le_bytes());
+        Ok(val)
+    }
+
+    fn amo_double(&self, addr: usize, f: &dyn Fn(u64) -> u64) -> Result<u64, Fault> {
+        self.check_writable(addr)?;
+        let mut data = self.data.write().unwrap();
+
+        let bytes = data.get_mut(addr..(addr + 8)).ok_or(StoreFault(addr))?;
+        let val = u64::from_le_bytes(<[u8; 8]>::try_from(&*bytes).map_err(|_| StoreFault(addr))?);
+        bytes.copy_from_slice(&f(val).to_le_bytes());
+        Ok(val)
+    }
+
+    fn read_bytes(&self, addr: usize, buf: &mut [u8]) -> Result<(), Fault> {
+        let data = self.data.read().unwrap();
+
+        let bytes = data
+            .get(addr..(addr + buf.len()))
+            .ok_or(MemoryFault(addr))?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_bytes(&self, addr: usize, data: &[u8]) -> Result<(), Fault> {
+        self.check_writable(addr)?;
+        let mut shared = self.data.write().unwrap();
+
+        let bytes = shared
+            .get_mut(addr..(addr + data.len()))
+            .ok_or(StoreFault(addr))?;
+        bytes.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_only(&self) -> bool {
+        self.readonly.load(Ordering::SeqCst)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::device::Device;
+    use crate::plic::Fault;
     use crate::ram::Ram;
 
     #[test]
@@ -116,6 +261,17 @@ mod tests {
         assert_eq!(i, 0x7d008113, "x1 mismatch");
     }
 
+    #[test]
+    fn restore_size_mismatch() {
+        let ram = Ram::sized(0x10);
+        ram.write_word(0, 0xdeadbeef).expect("written");
+
+        assert_eq!(ram.restore(vec![0; 0x8]), Err(0x10));
+        assert_eq!(ram.read_word(0).expect("read"), 0xdeadbeef);
+        assert_eq!(ram.restore(vec![0; 0x10]), Ok(()));
+        assert_eq!(ram.read_word(0).expect("read"), 0);
+    }
+
     #[test]
     fn write_read_cycle_u16() {
         let ram = Ram::new();
@@ -142,4 +298,119 @@ mod tests {
 
         assert_eq!(i, 0xdeadbeef_11223344, "dead beef");
     }
+
+    #[test]
+    fn sized() {
+        let ram = Ram::sized(0x1000);
+
+        assert_eq!(ram.size(), 0x1000, "size mismatch");
+    }
+
+    #[test]
+    fn sized_out_of_range() {
+        let ram = Ram::sized(0x1000);
+
+        assert!(ram.read_word(0xFFC).is_ok(), "last word should be readable");
+        assert!(matches!(
+            ram.read_word(0xFFE),
+            Err(Fault::MemoryFault(0xFFE))
+        ));
+        assert!(matches!(
+            ram.read_byte(0x1000),
+            Err(Fault::MemoryFault(0x1000))
+        ));
+        assert!(matches!(
+            ram.write_byte(0x1000, 0x1),
+            Err(Fault::StoreFault(0x1000))
+        ));
+    }
+
+    #[test]
+    fn amo_word() {
+        let ram = Ram::new();
+        ram.write_word(0, 40).expect("written");
+        let old = ram.amo_word(0, &|val| val + 2).expect("amo");
+
+        assert_eq!(old, 40, "amo returns the previous value");
+        assert_eq!(ram.read_word(0).expect("read"), 42);
+    }
+
+    #[test]
+    fn bulk_matches_bytes() {
+        let ram = Ram::sized(0x100);
+        ram.write_bytes(0x10, &(0..0x40).collect::<Vec<u8>>())
+            .expect("write");
+
+        let mut bulk = [0; 0x30];
+        ram.read_bytes(0x1a, &mut bulk).expect("read");
+        let bytes: Vec<u8> = (0x1a..0x4a)
+            .map(|addr| ram.read_byte(addr).expect("read"))
+            .collect();
+        assert_eq!(bulk.to_vec(), bytes);
+        assert_eq!(bulk[0], 0x0a);
+
+        let mut bulk = [0; 0x10];
+        assert!(matches!(
+            ram.read_bytes(0xf8, &mut bulk),
+            Err(Fault::MemoryFault(0xf8))
+        ));
+    }
+
+    #[test]
+    fn readonly() {
+        let ram = Ram::sized(0x10);
+        ram.write_word(0x4, 0xdeadbeef).expect("write");
+        ram.set_readonly(true);
+
+        assert!(matches!(
+            ram.write_word(0x4, 0),
+            Err(Fault::StoreFault(0x4))
+        ));
+        assert!(matches!(
+            ram.amo_word(0x4, &|val| val + 1),
+            Err(Fault::StoreFault(0x4))
+        ));
+        assert!(ram.write_bytes(0x0, &[1, 2]).is_err());
+        assert_eq!(ram.read_word(0x4).expect("read"), 0xdeadbeef);
+
+        ram.set_readonly(false);
+        ram.write_word(0x4, 0).expect("write");
+    }
+
+    #[test]
+    fn mmap() {
+        let path = std::env::temp_dir().join(format!("rriscv-ram-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let ram = Ram::from_mmap(&path, 0x1000).expect("mapping");
+        assert_eq!(ram.size(), 0x1000);
+        assert_eq!(ram.read_double(0x800).expect("read"), 0, "fresh mapping");
+        ram.write_word(0x800, 0xdeadbeef).expect("write");
+        assert_eq!(ram.read_word(0x800).expect("read"), 0xdeadbeef);
+        drop(ram);
+
+        let ram = Ram::from_mmap(&path, 0x1000).expect("mapping");
+        assert_eq!(ram.read_word(0x800).expect("read"), 0xdeadbeef, "persisted");
+        std::fs::remove_file(&path).expect("cleanup");
+    }
+
+    #[test]
+    fn straddling_store() {
+        let ram = Ram::sized(0x10);
+        ram.write_double(0x8, 0x1122334455667788).expect("write");
+
+        assert!(matches!(
+            ram.write_double(0xc, 0xdeadbeefcafebabe),
+            Err(Fault::StoreFault(0xc))
+        ));
+        assert!(matches!(
+            ram.write_word(0xe, 0xdeadbeef),
+            Err(Fault::StoreFault(0xe))
+        ));
+        assert!(matches!(
+            ram.write_half(0xf, 0xdead),
+            Err(Fault::StoreFault(0xf))
+        ));
+        assert_eq!(ram.read_double(0x8).expect("read"), 0x1122334455667788);
+    }
 }
@@ -2,7 +2,7 @@ use std::sync::RwLock;
 
 use crate::device::Device;
 use crate::plic::Fault;
-use crate::plic::Fault::MemoryFault;
+use crate::plic::Fault::{MemoryFault, StoreFault};
 
 pub struct Rom {
     data: RwLock<Vec<u8>>,
@@ -30,19 +30,19 @@ impl Rom {
 
 impl Device for Rom {
     fn write_double(&self, addr: usize, _val: u64) -> Result<(), Fault> {
-        Err(MemoryFault(addr))
+        Err(StoreFault(addr))
     }
 
     fn write_word(&self, addr: usize, _val: u32) -> Result<(), Fault> {
-        Err(MemoryFault(addr))
+        Err(StoreFault(addr))
     }
 
     fn write_half(&self, addr: usize, _val: u16) -> Result<(), Fault> {
-        Err(MemoryFault(addr))
+        Err(StoreFault(addr))
     }
 
     fn write_byte(&self, addr: usize, _val: u8) -> Result<(), Fault> {
-        Err(MemoryFault(addr))
+        Err(StoreFault(addr))
     }
 
     fn read_double(&self, addr: usize) -> Result<u64, Fault> {
@@ -82,6 +82,10 @@ impl Device for Rom {
 
         data.get(addr).copied().ok_or(MemoryFault(addr))
     }
+
+    fn read_only(&self) -> bool {
+        true
+    }
 }
 
 #[cfg(test)]
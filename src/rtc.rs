@@ -1,28 +1,117 @@
-use std::sync::RwLock;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
 
 use crate::device::Device;
 use crate::plic::Fault;
 
-pub const MTIMECMP_ADDR: usize = 0x4000;
-pub const MTIMECMP_ADDRH: usize = 0x4004;
-pub const MTIME_ADDR: usize = 0x4008;
-pub const MTIME_ADDRH: usize = 0x400c;
+// Where the RTC is usually mapped on the bus
+pub const RTC_ADDR: usize = 0x4000;
+pub const MTIMECMP_ADDR: usize = RTC_ADDR + MTIMECMP;
+pub const MTIMECMP_ADDRH: usize = RTC_ADDR + MTIMECMPH;
+pub const MTIME_ADDR: usize = RTC_ADDR + MTIME;
+pub const MTIME_ADDRH: usize = RTC_ADDR + MTIMEH;
 
-pub struct Rtc {
+// Register offsets, relative to the mapping of the device
+pub(crate) const MTIMECMP: usize = 0x0;
+const MTIMECMPH: usize = 0x4;
+pub(crate) const MTIME: usize = 0x8;
+const MTIMEH: usize = 0xc;
+
+// The time source of mtime, in nanoseconds
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+// Time passing in the real world, for interactive use
+pub struct WallClock {
     start: Instant,
-    mtimecmp: RwLock<Duration>,
-    mtimecmptmp: RwLock<u64>,
+}
+
+impl WallClock {
+    pub fn new() -> WallClock {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for WallClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for WallClock {
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+}
+
+// Deterministic time, advancing by a nanosecond per instruction retired by the harts it is set
+// on, see `Hart::set_clock`
+pub struct TickCounter {
+    ticks: AtomicU64,
+}
+
+impl TickCounter {
+    pub fn new() -> TickCounter {
+        Self {
+            ticks: AtomicU64::new(0),
+        }
+    }
+
+    pub fn advance(&self) {
+        self.ticks.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl Default for TickCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for TickCounter {
+    fn now(&self) -> u64 {
+        self.ticks.load(Ordering::SeqCst)
+    }
+}
+
+pub struct Rtc {
+    clock: Arc<dyn Clock>,
+    mtimecmp: RwLock<u64>,
 }
 
 impl Rtc {
     pub fn new() -> Rtc {
+        Rtc::with_clock(Arc::new(WallClock::new()))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Rtc {
         Self {
-            start: Instant::now(),
-            mtimecmp: RwLock::new(Duration::MAX),
-            mtimecmptmp: RwLock::new(u64::MAX),
+            clock,
+            mtimecmp: RwLock::new(u64::MAX),
         }
     }
+
+    fn mtime(&self) -> u64 {
+        self.clock.now()
+    }
+
+    // Sub-word accesses are composed from the word containing them, shifted to `addr`
+    fn read_part(&self, addr: usize) -> Result<u32, Fault> {
+        Ok(self.read_word(addr & !0b11)? >> (8 * (addr & 0b11)))
+    }
+
+    fn write_part(&self, addr: usize, val: u32, mask: u32) -> Result<(), Fault> {
+        let shift = 8 * (addr & 0b11);
+        let word = self
+            .read_word(addr & !0b11)
+            .map_err(|_| Fault::StoreFault(addr))?;
+        let word = (word & !(mask << shift)) | ((val & mask) << shift);
+        self.write_word(addr & !0b11, word)
+    }
 }
 
 impl Default for Rtc {
@@ -34,69 +123,141 @@ impl Default for Rtc {
 impl Device for Rtc {
     fn write_double(&self, addr: usize, val: u64) -> Result<(), Fault> {
         match addr {
-            MTIMECMP_ADDR => {
-                let mut v = self.mtimecmptmp.write().unwrap();
+            MTIMECMP => {
+                let mut v = self.mtimecmp.write().unwrap();
                 *v = val;
                 Ok(())
             }
-            _ => Err(Fault::MemoryFault(addr)),
+            _ => Err(Fault::StoreFault(addr)),
         }
     }
 
     fn write_word(&self, addr: usize, val: u32) -> Result<(), Fault> {
         match addr {
-            MTIMECMP_ADDR => {
-                let mut low = self.mtimecmptmp.write().unwrap();
+            MTIMECMP => {
+                let mut low = self.mtimecmp.write().unwrap();
                 *low = (*low & 0xFFFF_FFFF_0000_0000) | val as u64;
                 Ok(())
             }
-            MTIMECMP_ADDRH => {
-                let mut high = self.mtimecmptmp.write().unwrap();
+            MTIMECMPH => {
+                let mut high = self.mtimecmp.write().unwrap();
                 *high = (*high & 0x0000_0000_FFFF_FFFF) | ((val as u64) << 32);
-
-                let mut shared = self.mtimecmp.write().unwrap();
-                *shared = Duration::from_nanos(*high);
                 Ok(())
             }
-            _ => Err(Fault::MemoryFault(addr)),
+            _ => Err(Fault::StoreFault(addr)),
         }
     }
 
-    fn write_half(&self, addr: usize, _val: u16) -> Result<(), Fault> {
-        Err(Fault::Unaligned(addr))
+    fn write_half(&self, addr: usize, val: u16) -> Result<(), Fault> {
+        if addr % 4 == 3 {
+            return Err(Fault::Unaligned(addr));
+        }
+        self.write_part(addr, val as u32, 0xFFFF)
     }
 
-    fn write_byte(&self, addr: usize, _val: u8) -> Result<(), Fault> {
-        Err(Fault::Unaligned(addr))
+    fn write_byte(&self, addr: usize, val: u8) -> Result<(), Fault> {
+        self.write_part(addr, val as u32, 0xFF)
     }
 
     fn read_double(&self, addr: usize) -> Result<u64, Fault> {
-        let now = self.start.elapsed();
-
         match addr {
-            MTIMECMP_ADDR => Ok(0xFFFFFFFF),
-            MTIME_ADDR => Ok(now.as_nanos() as u64),
+            MTIMECMP => Ok(*self.mtimecmp.read().unwrap()),
+            MTIME => Ok(self.mtime()),
             _ => Err(Fault::MemoryFault(addr)),
         }
     }
 
     fn read_word(&self, addr: usize) -> Result<u32, Fault> {
-        let now = self.start.elapsed();
-
         match addr {
-            MTIMECMP_ADDR => Ok(0xFFFFFFFF),
-            MTIMECMP_ADDRH => Ok(0xFFFFFFFF),
-            MTIME_ADDR => Ok((now.as_nanos() & 0x0FFFFFFFFu128) as u32),
-            MTIME_ADDRH => Ok(((now.as_nanos() >> 32) & 0x0FFFFFFFFu128) as u32),
+            MTIMECMP => Ok((*self.mtimecmp.read().unwrap() & 0xFFFFFFFF) as u32),
+            MTIMECMPH => Ok((*self.mtimecmp.read().unwrap() >> 32) as u32),
+            MTIME => Ok((self.mtime() & 0xFFFFFFFF) as u32),
+            MTIMEH => Ok((self.mtime() >> 32) as u32),
             _ => Err(Fault::MemoryFault(addr)),
         }
     }
 
     fn read_half(&self, addr: usize) -> Result<u16, Fault> {
-        Err(Fault::Unaligned(addr))
+        if addr % 4 == 3 {
+            return Err(Fault::Unaligned(addr));
+        }
+        Ok(self.read_part(addr)? as u16)
     }
 
     fn read_byte(&self, addr: usize) -> Result<u8, Fault> {
-        Err(Fault::Unaligned(addr))
+        Ok(self.read_part(addr)? as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::device::Device;
+    use crate::dynbus::DynBus;
+    use crate::hart::Hart;
+    use crate::ram::Ram;
+    use crate::rtc::{self, Rtc, TickCounter};
+
+    fn mtimes() -> Vec<u64> {
+        let clock = Arc::new(TickCounter::new());
+        let ram = Ram::sized(0x1000);
+        ram.write(
+            0,
+            vec![
+                0x93, 0x85, 0x15, 0x00, // addi	a1,a1,1
+                0x6f, 0xf0, 0xdf, 0xff, // j	-4
+            ],
+        );
+        let mut bus = DynBus::new();
+        bus.map(ram, 0x80000000..0x80001000);
+        bus.map(
+            Rtc::with_clock(clock.clone()),
+            rtc::RTC_ADDR..rtc::RTC_ADDR + 0x20,
+        );
+        let bus = Arc::new(bus);
+        let mut hart = Hart::new(0, 0x80000000, bus.clone());
+        hart.set_clock(clock);
+
+        (0..100)
+            .map(|_| {
+                hart.tick().expect("tick");
+                bus.read_double(rtc::MTIME_ADDR).expect("mtime")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deterministic_clock() {
+        let first = mtimes();
+        assert_eq!(first, mtimes());
+        assert_eq!(first[..3], [1, 2, 3]);
+    }
+
+    #[test]
+    fn byte_access() {
+        let clock = Arc::new(TickCounter::new());
+        let rtc = Rtc::with_clock(clock.clone());
+        for _ in 0..0x1234 {
+            clock.advance();
+        }
+
+        let mut mtime = 0;
+        for i in 0..8 {
+            mtime |= (rtc
+                .read_byte(rtc::MTIME_ADDR - rtc::RTC_ADDR + i)
+                .expect("mtime") as u64)
+                << (8 * i);
+        }
+        assert_eq!(mtime, 0x1234);
+
+        rtc.write_half(0x2, 0xabcd).expect("mtimecmp");
+        rtc.write_byte(0x4, 0x12).expect("mtimecmph");
+        assert_eq!(
+            rtc.read_double(0x0).expect("mtimecmp"),
+            0xFFFF_FF12_ABCD_FFFF
+        );
+        assert_eq!(rtc.read_half(0x6).expect("mtimecmph"), 0xFFFF);
+        assert!(rtc.read_byte(0x10).is_err());
     }
 }
@@ -1,13 +1,16 @@
 use log::debug;
-use std::io::{self, Read, Write};
+use std::io;
 use std::ops::{Index, IndexMut};
 
+use crate::clint;
+use crate::console::Console;
+use crate::csr;
 use crate::device::Device;
 // Supervisor Execution Environment (SEE) implementing
 // RISC-V SBI (Supervisor Binary Interface)
 use crate::hart;
 use crate::plic::Fault;
-use crate::plic::Fault::Unimplemented;
+use crate::rtc;
 
 const SBI_VERSION: (u64, u64) = (1, 0);
 const SBI_IMPL_ID: u64 = 0xFFFFFFFF;
@@ -20,6 +23,8 @@ enum Register {
     ARG0 = 10,
     // a1: in/out (Value)
     ARG1 = 11,
+    // a2: in
+    ARG2 = 12,
     // a6: FID (Function ID)
     FID = 16,
     // a7: EID (Extension ID)
@@ -66,6 +71,12 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<Fault> for Error {
+    fn from(_value: Fault) -> Self {
+        Error::Failed
+    }
+}
+
 // Base Extension (EID #0x10)
 
 fn sbi_get_spec_version() -> Result<u64, Error> {
@@ -80,11 +91,14 @@ fn sbi_get_sbi_impl_version() -> Result<u64, Error> {
     Ok(SBI_IMPL_VERSION)
 }
 
-fn sbi_probe_extension(extension_id: u64) -> Result<u64, Error> {
+fn sbi_probe_extension<BT: Device>(hart: &hart::Hart<BT>, extension_id: u64) -> Result<u64, Error> {
     match extension_id {
         0x01 => Ok(1),
         0x02 => Ok(1),
         0x10 => Ok(1),
+        0x54494D45 => Ok(1),
+        0x735049 => Ok(1),
+        0x48534D => Ok(hart.hsm.is_some() as u64),
         _ => Ok(0),
     }
 }
@@ -103,20 +117,15 @@ fn sbi_get_mimpid() -> Result<u64, Error> {
 
 //  Legacy Extensions (EIDs #0x00 - #0x0F)
 
-fn sbi_console_putchar(value: u64) -> Result<u64, Error> {
-    let char = [u8::try_from(value)?];
-
-    let mut handle = io::stdout().lock();
-
-    handle.write_all(&char)?;
-    handle.flush()?;
+fn sbi_console_putchar(console: &dyn Console, value: u64) -> Result<u64, Error> {
+    console.putchar(u8::try_from(value)?);
     Ok(0)
 }
 
-fn sbi_console_getchar() -> Result<u64, Error> {
-    let mut buffer = [0];
-    io::stdin().read_exact(&mut buffer)?;
-    Ok(buffer[0] as u64)
+// Reads from the same console as the UART, returning -1 without blocking when it is empty
+fn sbi_console_getchar(console: &dyn Console) -> Result<u64, Error> {
+    let byte = console.getchar();
+    Ok(byte.map_or(-1i64 as u64, |b| b as u64))
 }
 
 fn sbi_shutdown<BT: Device>(hart: &mut hart::Hart<BT>) -> Result<u64, Error> {
@@ -124,6 +133,79 @@ fn sbi_shutdown<BT: Device>(hart: &mut hart::Hart<BT>) -> Result<u64, Error> {
     Ok(0)
 }
 
+// Timer Extension (EID #0x54494D45 "TIME")
+
+fn sbi_set_timer<BT: Device>(hart: &mut hart::Hart<BT>, stime_value: u64) -> Result<u64, Error> {
+    hart.bus.write_double(rtc::MTIMECMP_ADDR, stime_value)?;
+
+    let mip = hart.get_csr(csr::MIP);
+    hart.set_csr(csr::MIP, mip & !(1 << clint::STIP));
+    Ok(0)
+}
+
+// IPI Extension (EID #0x735049 "sPI: s-mode IPI")
+
+fn sbi_send_ipi<BT: Device>(
+    hart: &mut hart::Hart<BT>,
+    hart_mask: u64,
+    hart_mask_base: u64,
+) -> Result<u64, Error> {
+    let harts: Vec<usize> = if hart_mask_base == u64::MAX {
+        // broadcast to the harts which exist, if known
+        let count = hart
+            .hsm
+            .as_ref()
+            .map_or(clint::MAX_HARTS, |hsm| hsm.harts());
+        (0..count.min(clint::MAX_HARTS)).collect()
+    } else {
+        let base = usize::try_from(hart_mask_base)?;
+        (0..64)
+            .filter(|bit| hart_mask & (1 << bit) != 0)
+            .map(|bit| base.checked_add(bit).ok_or(Error::InvalidParam))
+            .collect::<Result<_, _>>()?
+    };
+    if harts.iter().any(|&hartid| hartid >= clint::MAX_HARTS) {
+        return Err(Error::InvalidParam);
+    }
+
+    for hartid in harts {
+        hart.bus
+            .write_word(clint::MSIP_HART0_ADDR + 4 * hartid, 1)?;
+    }
+    Ok(0)
+}
+
+// Hart State Management Extension (EID #0x48534D "HSM")
+
+fn sbi_hart_start<BT: Device>(
+    hart: &mut hart::Hart<BT>,
+    hartid: u64,
+    start_addr: u64,
+    opaque: u64,
+) -> Result<u64, Error> {
+    let hsm = hart.hsm.as_ref().ok_or(Error::NotSupported)?;
+
+    match hsm.start(usize::try_from(hartid)?, start_addr as usize, opaque) {
+        Ok(()) => Ok(0),
+        Err(None) => Err(Error::InvalidParam),
+        Err(Some(_)) => Err(Error::AlreadyAvailable),
+    }
+}
+
+fn sbi_hart_stop<BT: Device>(hart: &mut hart::Hart<BT>) -> Result<u64, Error> {
+    let hsm = hart.hsm.as_ref().ok_or(Error::NotSupported)?;
+
+    hsm.stop(hart.get_csr(csr::MHARTID) as usize);
+    Ok(0)
+}
+
+fn sbi_hart_get_status<BT: Device>(hart: &mut hart::Hart<BT>, hartid: u64) -> Result<u64, Error> {
+    let hsm = hart.hsm.as_ref().ok_or(Error::NotSupported)?;
+
+    let status = hsm.status(usize::try_from(hartid)?);
+    status.map(|s| s as u64).ok_or(Error::InvalidParam)
+}
+
 // System Reset Extension (EID #0x53525354 "SRST")
 
 fn sbi_system_reset<BT: Device>(
@@ -167,8 +249,8 @@ fn call_0_1<BT: Device>(hart: &mut hart::Hart<BT>) -> Result<u64, Error> {
     let func = hart.get_register(Register::EID as u8);
 
     let result = match func {
-        0x01 => sbi_console_putchar(hart.get_register(Register::ARG0 as u8)),
-        0x02 => sbi_console_getchar(),
+        0x01 => sbi_console_putchar(&*hart.console, hart.get_register(Register::ARG0 as u8)),
+        0x02 => sbi_console_getchar(&*hart.console),
         0x08 => sbi_shutdown(hart),
         _ => Err(Error::NotSupported),
     };
@@ -196,10 +278,24 @@ fn call_0_2<BT: Device>(hart: &mut hart::Hart<BT>) -> Result<u64, Error> {
         (0x10, 0x0) => sbi_get_spec_version(),
         (0x10, 0x1) => sbi_get_sbi_impl_id(),
         (0x10, 0x2) => sbi_get_sbi_impl_version(),
-        (0x10, 0x3) => sbi_probe_extension(hart.get_register(Register::ARG0 as u8)),
+        (0x10, 0x3) => sbi_probe_extension(hart, hart.get_register(Register::ARG0 as u8)),
         (0x10, 0x4) => sbi_get_mvendorid(),
         (0x10, 0x5) => sbi_get_marchid(),
         (0x10, 0x6) => sbi_get_mimpid(),
+        (0x54494D45, 0x0) => sbi_set_timer(hart, hart.get_register(Register::ARG0 as u8)),
+        (0x735049, 0x0) => sbi_send_ipi(
+            hart,
+            hart.get_register(Register::ARG0 as u8),
+            hart.get_register(Register::ARG1 as u8),
+        ),
+        (0x48534D, 0x0) => sbi_hart_start(
+            hart,
+            hart.get_register(Register::ARG0 as u8),
+            hart.get_register(Register::ARG1 as u8),
+            hart.get_register(Register::ARG2 as u8),
+        ),
+        (0x48534D, 0x1) => sbi_hart_stop(hart),
+        (0x48534D, 0x2) => sbi_hart_get_status(hart, hart.get_register(Register::ARG0 as u8)),
         (0x53525354, 0x0) => sbi_system_reset(
             hart,
             hart.get_register(Register::ARG0 as u8),
@@ -222,15 +318,185 @@ fn call_0_2<BT: Device>(hart: &mut hart::Hart<BT>) -> Result<u64, Error> {
     }
 }
 
+// SBI errors are reported to the guest in a0, the hart keeps running either way
 pub fn call<BT: Device>(hart: &mut hart::Hart<BT>) -> Result<(), Fault> {
-    if (0x00..=0x0F).contains(&hart.get_register(Register::EID as u8)) {
-        call_0_1(hart).map(|_x| ()).map_err(|_x| Unimplemented)
+    let _ = if (0x00..=0x0F).contains(&hart.get_register(Register::EID as u8)) {
+        call_0_1(hart)
     } else {
-        call_0_2(hart).map(|_x| ()).map_err(|_x| Unimplemented)
-    }
+        call_0_2(hart)
+    };
+    Ok(())
 }
 
-pub(crate) fn ebreak() {
-    // XXX: Ignore for now - we may decide to open a port used for GDB Remote Serial Protocol
-    //      communication.
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::clint::{Clint, CLINT_ADDR, MSIP_HART0_ADDR, STIP};
+    use crate::console;
+    use crate::csr;
+    use crate::device::Device;
+    use crate::dynbus::DynBus;
+    use crate::hart::Hart;
+    use crate::hsm::{HartStatus, Hsm};
+    use crate::machine_builder::MachineBuilder;
+    use crate::plic::Fault;
+    use crate::ram::Ram;
+    use crate::rtc::{self, Rtc};
+    use crate::see::{self, sbi_console_getchar, Register};
+
+    #[test]
+    fn set_timer() {
+        let mut bus = DynBus::new();
+        bus.map(Rtc::new(), rtc::RTC_ADDR..rtc::RTC_ADDR + 0x20);
+        let bus = Arc::new(bus);
+        let mut hart = Hart::new(0, 0, bus.clone());
+        hart.set_csr(csr::MIP, 1 << STIP);
+
+        hart.set_register(Register::EID as u8, 0x54494D45);
+        hart.set_register(Register::FID as u8, 0x0);
+        hart.set_register(Register::ARG0 as u8, 123456789);
+        see::call(&mut hart).expect("sbi call");
+
+        assert_eq!(hart.get_register(Register::ARG0 as u8), 0);
+        assert_eq!(
+            bus.read_double(rtc::MTIMECMP_ADDR).expect("mtimecmp"),
+            123456789
+        );
+        assert_eq!(hart.get_csr(csr::MIP) & (1 << STIP), 0);
+    }
+
+    #[test]
+    fn send_ipi() {
+        let mut bus = DynBus::new();
+        bus.map(Clint::new(), CLINT_ADDR..CLINT_ADDR + 0x4000);
+        let bus = Arc::new(bus);
+        let mut hart = Hart::new(0, 0, bus.clone());
+
+        // harts 3 and 5, relative to base 2
+        hart.set_register(Register::EID as u8, 0x735049);
+        hart.set_register(Register::FID as u8, 0x0);
+        hart.set_register(Register::ARG0 as u8, 0b1010);
+        hart.set_register(Register::ARG1 as u8, 2);
+        see::call(&mut hart).expect("sbi call");
+
+        assert_eq!(hart.get_register(Register::ARG0 as u8), 0);
+        let msip = |hartid: usize| bus.read_word(MSIP_HART0_ADDR + 4 * hartid).expect("msip");
+        assert_eq!([msip(2), msip(3), msip(4), msip(5)], [0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn send_ipi_bounds() {
+        let mut bus = DynBus::new();
+        bus.map(Clint::new(), CLINT_ADDR..CLINT_ADDR + 0x4000);
+        let bus = Arc::new(bus);
+        let mut hart = Hart::new(0, 0, bus.clone());
+        hart.set_hsm(Arc::new(Hsm::new(2)));
+
+        // base overflowing with the mask
+        hart.set_register(Register::EID as u8, 0x735049);
+        hart.set_register(Register::FID as u8, 0x0);
+        hart.set_register(Register::ARG0 as u8, 1 << 63);
+        hart.set_register(Register::ARG1 as u8, u64::MAX - 1);
+        see::call(&mut hart).expect("sbi call");
+        assert_eq!(hart.get_register(Register::ARG0 as u8) as i64, -3);
+
+        // broadcast only reaches the existing harts
+        hart.set_register(Register::EID as u8, 0x735049);
+        hart.set_register(Register::FID as u8, 0x0);
+        hart.set_register(Register::ARG0 as u8, 0);
+        hart.set_register(Register::ARG1 as u8, u64::MAX);
+        see::call(&mut hart).expect("sbi call");
+
+        assert_eq!(hart.get_register(Register::ARG0 as u8), 0);
+        let msip = |hartid: usize| bus.read_word(MSIP_HART0_ADDR + 4 * hartid).expect("msip");
+        assert_eq!([msip(0), msip(1), msip(2)], [1, 1, 0]);
+    }
+
+    #[test]
+    fn hart_start() {
+        let mut bus = DynBus::new();
+        bus.map(Ram::sized(0x1000), 0x80000000..0x80001000);
+        let bus = Arc::new(bus);
+        let hsm = Arc::new(Hsm::new(2));
+        let mut hart0 = Hart::new(0, 0x80000000, bus.clone());
+        let mut hart1 = Hart::new(1, 0x80000000, bus.clone());
+        hart0.set_hsm(hsm.clone());
+        hart1.set_hsm(hsm.clone());
+
+        // secondary harts are parked until started
+        hart1.tick().expect("idle");
+        assert_eq!(hart1.get_pc(), 0x80000000);
+        assert_eq!(hsm.status(1), Some(HartStatus::Stopped));
+
+        hart0.set_register(Register::EID as u8, 0x48534D);
+        hart0.set_register(Register::FID as u8, 0x0);
+        hart0.set_register(Register::ARG0 as u8, 1);
+        hart0.set_register(Register::ARG1 as u8, 0x80000800);
+        hart0.set_register(Register::ARG2 as u8, 0x1234);
+        see::call(&mut hart0).expect("sbi call");
+        assert_eq!(hart0.get_register(Register::ARG0 as u8), 0);
+        assert_eq!(hsm.status(1), Some(HartStatus::StartPending));
+
+        hart1.tick().expect("start");
+        assert_eq!(hsm.status(1), Some(HartStatus::Started));
+        assert_eq!(hart1.get_pc(), 0x80000800);
+        assert_eq!(hart1.get_register(Register::ARG0 as u8), 1);
+        assert_eq!(hart1.get_register(Register::ARG1 as u8), 0x1234);
+    }
+
+    #[test]
+    fn not_supported() {
+        let (_bus, mut hart) = MachineBuilder::new()
+            .ram(0x1000)
+            .load_bin(&[
+                0x93, 0x08, 0x00, 0x01, // li	a7,16
+                0x13, 0x08, 0x90, 0x09, // li	a6,153
+                0x73, 0x00, 0x00, 0x00, // ecall
+                0x93, 0x05, 0xa0, 0x02, // li	a1,42
+            ])
+            .build()
+            .expect("build");
+
+        for _ in 0..4 {
+            hart.tick().expect("running");
+        }
+
+        assert_eq!(hart.get_register(Register::ARG0 as u8) as i64, -2);
+        assert_eq!(hart.get_register(Register::ARG1 as u8), 42);
+    }
+
+    #[test]
+    fn console_getchar() {
+        let input = console::Buffer::new();
+        assert_eq!(sbi_console_getchar(&input).ok(), Some(-1i64 as u64));
+
+        input.push_input(b"x");
+        assert_eq!(sbi_console_getchar(&input).ok(), Some(b'x' as u64));
+        assert_eq!(sbi_console_getchar(&input).ok(), Some(-1i64 as u64));
+    }
+
+    #[test]
+    fn console_putchar() {
+        let output = Arc::new(console::Buffer::new());
+        let (_bus, mut hart) = MachineBuilder::new()
+            .ram(0x1000)
+            .console(output.clone())
+            .load_bin(&[
+                0x93, 0x08, 0x10, 0x00, // li	a7,1
+                0x13, 0x05, 0x80, 0x04, // li	a0,72
+                0x73, 0x00, 0x00, 0x00, // ecall
+                0x13, 0x05, 0x90, 0x06, // li	a0,105
+                0x73, 0x00, 0x00, 0x00, // ecall
+                0x93, 0x08, 0x80, 0x00, // li	a7,8
+                0x73, 0x00, 0x00, 0x00, // ecall (legacy shutdown)
+            ])
+            .build()
+            .expect("build");
+
+        let res = (0..100).map(|_| hart.tick()).find(Result::is_err);
+
+        assert!(matches!(res, Some(Err(Fault::Halt))));
+        assert_eq!(output.output(), b"Hi");
+    }
 }
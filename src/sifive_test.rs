@@ -0,0 +1,128 @@
+use std::sync::RwLock;
+
+use crate::device::Device;
+use crate::plic::Fault;
+use crate::plic::Fault::{Halt, MemoryFault, StoreFault, Unaligned};
+
+// Where QEMU's `virt` machine maps the test device
+pub const SIFIVE_TEST_ADDR: usize = 0x100000;
+
+const FAIL: u32 = 0x3333;
+const PASS: u32 = 0x5555;
+const RESET: u32 = 0x7777;
+
+// The `sifive_test` finisher, guests write a status word to exit or reset the machine
+pub struct SifiveTest {
+    exit_code: RwLock<Option<u64>>,
+    reset: RwLock<bool>,
+}
+
+impl SifiveTest {
+    pub fn new() -> SifiveTest {
+        SifiveTest {
+            exit_code: RwLock::new(None),
+            reset: RwLock::new(false),
+        }
+    }
+
+    // The code the guest exited with, 0 on pass
+    pub fn exit_code(&self) -> Option<u64> {
+        *self.exit_code.read().unwrap()
+    }
+
+    // Whether the guest asked for the machine to be reset
+    pub fn reset_requested(&self) -> bool {
+        *self.reset.read().unwrap()
+    }
+
+    // The lower half is the status, failures carry their code in the upper half
+    fn finish(&self, val: u32) -> Result<(), Fault> {
+        match val & 0xFFFF {
+            FAIL => *self.exit_code.write().unwrap() = Some((val >> 16) as u64),
+            PASS => *self.exit_code.write().unwrap() = Some(0),
+            RESET => *self.reset.write().unwrap() = true,
+            _ => return Ok(()),
+        }
+        Err(Halt)
+    }
+}
+
+impl Default for SifiveTest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for SifiveTest {
+    fn write_double(&self, addr: usize, _val: u64) -> Result<(), Fault> {
+        Err(Unaligned(addr))
+    }
+
+    fn write_word(&self, addr: usize, val: u32) -> Result<(), Fault> {
+        match addr {
+            0x0 => self.finish(val),
+            _ => Err(StoreFault(addr)),
+        }
+    }
+
+    fn write_half(&self, addr: usize, _val: u16) -> Result<(), Fault> {
+        Err(Unaligned(addr))
+    }
+
+    fn write_byte(&self, addr: usize, _val: u8) -> Result<(), Fault> {
+        Err(Unaligned(addr))
+    }
+
+    fn read_double(&self, addr: usize) -> Result<u64, Fault> {
+        Err(Unaligned(addr))
+    }
+
+    fn read_word(&self, addr: usize) -> Result<u32, Fault> {
+        match addr {
+            0x0 => Ok(0),
+            _ => Err(MemoryFault(addr)),
+        }
+    }
+
+    fn read_half(&self, addr: usize) -> Result<u16, Fault> {
+        Err(Unaligned(addr))
+    }
+
+    fn read_byte(&self, addr: usize) -> Result<u8, Fault> {
+        Err(Unaligned(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::Device;
+    use crate::plic::Fault;
+    use crate::sifive_test::SifiveTest;
+
+    #[test]
+    fn exit_code() {
+        let test = SifiveTest::new();
+        assert_eq!(test.exit_code(), None);
+
+        assert!(matches!(
+            test.write_word(0, 0x3333 | (7 << 16)),
+            Err(Fault::Halt)
+        ));
+        assert_eq!(test.exit_code(), Some(7));
+
+        let test = SifiveTest::new();
+        assert!(matches!(test.write_word(0, 0x5555), Err(Fault::Halt)));
+        assert_eq!(test.exit_code(), Some(0));
+    }
+
+    #[test]
+    fn reset() {
+        let test = SifiveTest::new();
+        assert!(test.write_word(0, 0x1234).is_ok());
+        assert!(!test.reset_requested());
+
+        assert!(matches!(test.write_word(0, 0x7777), Err(Fault::Halt)));
+        assert!(test.reset_requested());
+        assert_eq!(test.exit_code(), None);
+    }
+}
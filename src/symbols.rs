@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+
+use object::{Object, ObjectSymbol, SymbolKind};
+
+// Names of the functions and data of a loaded image, to annotate addresses in traces
+pub struct Symbols {
+    names: BTreeMap<usize, String>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Self {
+            names: BTreeMap::new(),
+        }
+    }
+
+    // Collects the named text and data symbols of an ELF symbol table
+    pub fn from_elf(elf: &object::File) -> Symbols {
+        let mut symbols = Symbols::new();
+        for symbol in elf.symbols() {
+            if !matches!(
+                symbol.kind(),
+                SymbolKind::Text | SymbolKind::Data | SymbolKind::Label
+            ) {
+                continue;
+            }
+            if let Ok(name) = symbol.name() {
+                if !name.is_empty() {
+                    symbols.insert(symbol.address() as usize, name);
+                }
+            }
+        }
+        symbols
+    }
+
+    pub fn insert(&mut self, addr: usize, name: &str) {
+        self.names.insert(addr, name.to_string());
+    }
+
+    // The nearest symbol at or before `addr`, and the offset of `addr` into it
+    pub fn lookup(&self, addr: usize) -> Option<(&str, usize)> {
+        self.names
+            .range(..=addr)
+            .next_back()
+            .map(|(start, name)| (name.as_str(), addr - start))
+    }
+
+    // Formats `addr` like objdump does, e.g. `8000129c <start+0x4c>`
+    pub fn describe(&self, addr: usize) -> String {
+        match self.lookup(addr) {
+            Some((name, 0)) => format!("{addr:08x} <{name}>"),
+            Some((name, offset)) => format!("{addr:08x} <{name}+{offset:#x}>"),
+            None => format!("{addr:08x}"),
+        }
+    }
+}
+
+impl Default for Symbols {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::symbols::Symbols;
+
+    #[test]
+    fn describe() {
+        let mut symbols = Symbols::new();
+        symbols.insert(0x80001250, "start");
+        symbols.insert(0x80001300, "main");
+
+        assert_eq!(symbols.describe(0x8000129c), "8000129c <start+0x4c>");
+        assert_eq!(symbols.describe(0x80001300), "80001300 <main>");
+        assert_eq!(symbols.describe(0x80001000), "80001000");
+    }
+}
@@ -1,9 +1,16 @@
+use crate::console::{Console, Stdio};
 use crate::device::Device;
-use crate::plic::Fault;
+use crate::plic::{Fault, Plic};
 use std::io;
-use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
 
-pub struct Uart8250 {}
+pub struct Uart8250 {
+    console: Arc<dyn Console>,
+    ier: Arc<AtomicU8>,
+    scr: AtomicU8,
+    fifo: AtomicBool,
+}
 
 #[allow(unused)]
 impl Uart8250 {
@@ -13,11 +20,56 @@ impl Uart8250 {
     const MCR: usize = 4; // In: Modem Control Register
     const LSR: usize = 5; // Out:  Line Status Register
     const FCR: usize = 2; // In: FIFO Control Register
+    const IIR: usize = 2; // Out: Interrupt Identification Register
+    const SCR: usize = 7; // In/Out: Scratch Register
     const DLL: usize = 0; // In: Divisor Latch Low
     const DLM: usize = 1; // In: Divisor Latch Low
 
+    const IER_RDI: u8 = 0b01; // Received Data Available Interrupt
+    const IER_THRI: u8 = 0b10; // Transmitter Holding Register Empty Interrupt
+
+    const IIR_NO_INT: u8 = 0b0001;
+    const IIR_THRI: u8 = 0b0010;
+    const IIR_RDI: u8 = 0b0100;
+    const IIR_FIFO: u8 = 0b1100_0000; // FIFOs enabled
+
+    const FCR_ENABLE: u8 = 0b001; // Enable FIFOs
+    const FCR_CLEAR_RX: u8 = 0b010; // Clear the receive FIFO
+
     pub fn new() -> Uart8250 {
-        Uart8250 {}
+        Uart8250::with_console(Arc::new(Stdio))
+    }
+
+    // Reads and writes `console` instead of stdin and stdout
+    pub fn with_console(console: Arc<dyn Console>) -> Uart8250 {
+        Uart8250 {
+            console,
+            ier: Arc::new(AtomicU8::new(0)),
+            scr: AtomicU8::new(0),
+            fifo: AtomicBool::new(false),
+        }
+    }
+
+    // The highest priority pending interrupt.  Output is written out immediately, so the
+    // transmitter is always idle.
+    fn iir(ier: u8, have_data: bool) -> u8 {
+        if ier & Uart8250::IER_RDI != 0 && have_data {
+            Uart8250::IIR_RDI
+        } else if ier & Uart8250::IER_THRI != 0 {
+            Uart8250::IIR_THRI
+        } else {
+            Uart8250::IIR_NO_INT
+        }
+    }
+
+    // Signal interrupts as `irq` on the `plic`
+    pub fn connect(&self, plic: &Plic, irq: u32) {
+        let console = self.console.clone();
+        let ier = self.ier.clone();
+
+        plic.connect(irq, move || {
+            Uart8250::iir(ier.load(Ordering::SeqCst), console.has_input()) != Uart8250::IIR_NO_INT
+        });
     }
 }
 
@@ -37,9 +89,15 @@ impl Device for Uart8250 {
     fn write_byte(&self, addr: usize, val: u8) -> Result<(), Fault> {
         // Emulating a 8250 / 16550 UART
         match addr {
-            Uart8250::RX => {
-                print!("{}", val as char);
-                io::stdout().flush().unwrap();
+            Uart8250::RX => self.console.putchar(val),
+            Uart8250::IER => self.ier.store(val, Ordering::SeqCst),
+            Uart8250::SCR => self.scr.store(val, Ordering::SeqCst),
+            Uart8250::FCR => {
+                self.fifo
+                    .store(val & Uart8250::FCR_ENABLE != 0, Ordering::SeqCst);
+                if val & Uart8250::FCR_CLEAR_RX != 0 {
+                    while self.console.getchar().is_some() {}
+                }
             }
             _ => {}
         }
@@ -60,14 +118,19 @@ impl Device for Uart8250 {
 
     fn read_byte(&self, addr: usize) -> Result<u8, Fault> {
         // Emulating a 8250 / 16550 UART
-        let have_data: bool = false; // XXX: need a way to detect presence of data in stdin
+        let have_data = self.console.has_input();
 
         match addr {
-            Uart8250::LSR if have_data => {
-                let mut buffer = [0];
-                io::stdin().read_exact(&mut buffer)?;
-                Ok(buffer[0])
+            Uart8250::RX => Ok(self.console.getchar().unwrap_or(0)),
+            Uart8250::IER => Ok(self.ier.load(Ordering::SeqCst)),
+            Uart8250::IIR => {
+                let fifo = match self.fifo.load(Ordering::SeqCst) {
+                    true => Uart8250::IIR_FIFO,
+                    false => 0,
+                };
+                Ok(fifo | Uart8250::iir(self.ier.load(Ordering::SeqCst), have_data))
             }
+            Uart8250::SCR => Ok(self.scr.load(Ordering::SeqCst)),
             Uart8250::LSR => Ok(0x60 | have_data as u8),
             Uart8250::LCR => Ok(0b0_0_000_0_11),
             _ => Ok(0),
@@ -80,3 +143,107 @@ impl From<io::Error> for Fault {
         Fault::MemoryFault(0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::console;
+    use crate::device::Device;
+    use crate::plic::Plic;
+    use crate::uart8250::Uart8250;
+
+    #[test]
+    fn read_input() {
+        let input = Arc::new(console::Buffer::new());
+        let uart = Uart8250::with_console(input.clone());
+        assert_eq!(uart.read_byte(Uart8250::LSR).expect("lsr") & 0b1, 0);
+
+        input.push_input(b"hi");
+        assert_eq!(uart.read_byte(Uart8250::LSR).expect("lsr") & 0b1, 1);
+        assert_eq!(uart.read_byte(Uart8250::RX).expect("rx"), b'h');
+        assert_eq!(uart.read_byte(Uart8250::LSR).expect("lsr") & 0b1, 1);
+        assert_eq!(uart.read_byte(Uart8250::RX).expect("rx"), b'i');
+        assert_eq!(uart.read_byte(Uart8250::LSR).expect("lsr") & 0b1, 0);
+    }
+
+    #[test]
+    fn receive_interrupt() {
+        let input = Arc::new(console::Buffer::new());
+        let uart = Uart8250::with_console(input.clone());
+        let plic = Plic::new();
+        uart.connect(&plic, 10);
+
+        input.push_input(b"x");
+        assert!(!plic.is_pending(10));
+
+        uart.write_byte(Uart8250::IER, 0b1).expect("ier");
+        assert!(plic.is_pending(10));
+
+        // draining the buffer deasserts
+        assert_eq!(uart.read_byte(Uart8250::RX).expect("rx"), b'x');
+        assert!(!plic.is_pending(10));
+    }
+
+    #[test]
+    fn transmit_interrupt() {
+        let input = Arc::new(console::Buffer::new());
+        let uart = Uart8250::with_console(input.clone());
+        let plic = Plic::new();
+        uart.connect(&plic, 10);
+        assert_eq!(uart.read_byte(Uart8250::IIR).expect("iir"), 0b0001);
+
+        uart.write_byte(Uart8250::IER, 0b10).expect("ier");
+        assert_eq!(uart.read_byte(Uart8250::IIR).expect("iir"), 0b0010);
+        assert!(plic.is_pending(10));
+
+        // received data takes priority
+        uart.write_byte(Uart8250::IER, 0b11).expect("ier");
+        input.push_input(b"x");
+        assert_eq!(uart.read_byte(Uart8250::IIR).expect("iir"), 0b0100);
+
+        uart.write_byte(Uart8250::IER, 0b00).expect("ier");
+        assert_eq!(uart.read_byte(Uart8250::IIR).expect("iir"), 0b0001);
+        assert!(!plic.is_pending(10));
+    }
+
+    #[test]
+    fn transmit() {
+        let output = Arc::new(console::Buffer::new());
+        let uart = Uart8250::with_console(output.clone());
+        for byte in b"ok\n" {
+            uart.write_byte(Uart8250::RX, *byte).expect("tx");
+        }
+        assert_eq!(output.output(), b"ok\n");
+    }
+
+    #[test]
+    fn scratch() {
+        let uart = Uart8250::with_console(Arc::new(console::Buffer::new()));
+        uart.write_byte(Uart8250::SCR, 0xa5).expect("scr");
+        assert_eq!(uart.read_byte(Uart8250::SCR).expect("scr"), 0xa5);
+    }
+
+    #[test]
+    fn fifo() {
+        let input = Arc::new(console::Buffer::new());
+        let uart = Uart8250::with_console(input.clone());
+        uart.write_byte(Uart8250::FCR, 0b1).expect("fcr");
+        uart.write_byte(Uart8250::IER, 0b1).expect("ier");
+
+        input.push_input(b"ab");
+        assert_eq!(uart.read_byte(Uart8250::IIR).expect("iir"), 0b1100_0100);
+
+        assert_eq!(uart.read_byte(Uart8250::RX).expect("rx"), b'a');
+        assert_eq!(uart.read_byte(Uart8250::RX).expect("rx"), b'b');
+        assert_eq!(uart.read_byte(Uart8250::IIR).expect("iir"), 0b1100_0001);
+
+        // clearing the receive FIFO drops pending input
+        input.push_input(b"cd");
+        uart.write_byte(Uart8250::FCR, 0b11).expect("fcr");
+        assert_eq!(uart.read_byte(Uart8250::LSR).expect("lsr") & 0b1, 0);
+
+        uart.write_byte(Uart8250::FCR, 0b0).expect("fcr");
+        assert_eq!(uart.read_byte(Uart8250::IIR).expect("iir"), 0b0001);
+    }
+}